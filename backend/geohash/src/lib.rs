@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate quadtree;
+
+use std::collections::HashSet;
 
 pub mod base32;
 
@@ -46,13 +49,29 @@ pub struct GeoBox {
 }
 
 impl GeoBox {
-    fn new(min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> Self {
+    /// x is lon, y is lat
+    pub fn new(min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> Self {
         GeoBox {
             x_range: Range::new(min_x, max_x),
             y_range: Range::new(min_y, max_y),
         }
     }
 
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.x_range.min <= lon && lon <= self.x_range.max &&
+            self.y_range.min <= lat && lat <= self.y_range.max
+    }
+
+    /// Distance in meters from lat/lon to the closest point of the box
+    pub fn distance_to(&self, lat: f64, lon: f64) -> f64 {
+        // The box can be closer the other way around the antimeridian
+        let y = lat.clamp(self.y_range.min, self.y_range.max);
+        [lon - 360.0, lon, lon + 360.0].iter()
+            .map(|x| quadtree::distance(
+                lon, lat, x.clamp(self.x_range.min, self.x_range.max), y))
+            .fold(f64::INFINITY, f64::min)
+    }
+
     fn geohash(&mut self, lat: f64, lon: f64, precision: usize) -> u64 {
         let mut val = 0;
 
@@ -96,6 +115,105 @@ pub fn geobox_for_hash(h: &str) -> GeoBox {
     geobox
 }
 
+/// Size in degrees (lon, lat) of a cell at precision
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = precision * 5;
+    (360.0 / (1u64 << bits.div_ceil(2)) as f64,
+     180.0 / (1u64 << (bits / 2)) as f64)
+}
+
+/// Wrap a longitude back into -180..180
+fn wrap_lon(lon: f64) -> f64 {
+    if lon >= 180.0 {
+        lon - 360.0
+    } else if lon < -180.0 {
+        lon + 360.0
+    } else {
+        lon
+    }
+}
+
+/// The 8 cells around h: n, ne, e, se, s, sw, w, nw.
+/// There is nothing north of the north pole, so these can be missing.
+pub fn neighbors(h: &str) -> Vec<String> {
+    let gbox = geobox_for_hash(h);
+    let (width, height) = cell_size(h.len());
+    let lat = (gbox.y_range.min + gbox.y_range.max) / 2.0;
+    let lon = (gbox.x_range.min + gbox.x_range.max) / 2.0;
+
+    vec![(0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (1.0, -1.0),
+         (0.0, -1.0), (-1.0, -1.0), (-1.0, 0.0), (-1.0, 1.0)]
+        .into_iter()
+        .map(|(dx, dy)| (lat + dy * height, wrap_lon(lon + dx * width)))
+        .filter(|(lat, _)| *lat > -90.0 && *lat < 90.0)
+        .map(|(lat, lon)| hash(lat, lon, h.len()))
+        .collect()
+}
+
+/// All the cells of precision touching bbox. Boxes across the
+/// antimeridian either go past ±180 or have min x east of max x.
+pub fn cover_bbox(bbox: &GeoBox, precision: usize) -> HashSet<String> {
+    let (min, max) = (bbox.x_range.min, bbox.x_range.max);
+    let (min_y, max_y) = (bbox.y_range.min, bbox.y_range.max);
+    if max - min >= 360.0 {
+        return cover_lons(-180.0, 180.0, min_y, max_y, precision);
+    }
+
+    let wrap = |lon: f64| match lon {
+        lon if (-180.0..=180.0).contains(&lon) => lon,
+        lon => wrap_lon(lon)
+    };
+    let (min, max) = (wrap(min), wrap(max));
+    if min <= max {
+        return cover_lons(min, max, min_y, max_y, precision);
+    }
+    let mut hashes = cover_lons(min, 180.0, min_y, max_y, precision);
+    hashes.extend(cover_lons(-180.0, max, min_y, max_y, precision));
+    hashes
+}
+
+fn cover_lons(min_x: f64, max_x: f64, min_y: f64, max_y: f64,
+              precision: usize) -> HashSet<String> {
+    let (width, height) = cell_size(precision);
+    let first = geobox_for_hash(&hash(min_y, min_x, precision));
+    let mut hashes = HashSet::new();
+
+    // Walk cell centers, starting from the cell holding the bottom left
+    let mut lat = (first.y_range.min + first.y_range.max) / 2.0;
+    while lat - height / 2.0 <= max_y && lat < 90.0 {
+        let mut lon = (first.x_range.min + first.x_range.max) / 2.0;
+        while lon - width / 2.0 <= max_x && lon < 180.0 {
+            hashes.insert(hash(lat, lon, precision));
+            lon += width;
+        }
+        lat += height;
+    }
+
+    hashes
+}
+
+/// Cells covering a circle of radius meters around lat/lon, at the
+/// finest precision where that takes at most 3x3 cells. Everything
+/// within radius has one of these as a prefix.
+pub fn cover_circle(lat: f64, lon: f64, radius: f64) -> HashSet<String> {
+    let fits = |precision: usize| {
+        let (width, height) = cell_size(precision);
+        height * 111320.0 >= radius &&
+            width * 111320.0 * lat.to_radians().cos() >= radius
+    };
+    let precision = (1..=12).rev().find(|p| fits(*p)).unwrap_or(1);
+
+    let dlat = (radius / 111320.0).min(90.0);
+    let dlon = (radius / (111320.0 * lat.to_radians().cos().max(0.000001)))
+        .min(180.0);
+    let bbox = GeoBox::new(lon - dlon, lon + dlon,
+                           (lat - dlat).max(-90.0), (lat + dlat).min(90.0));
+
+    cover_bbox(&bbox, precision).into_iter()
+        .filter(|h| geobox_for_hash(h).distance_to(lat, lon) <= radius)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     fn init() {
@@ -184,4 +302,73 @@ mod tests {
         assert_eq!(gbox.x_range.min, -122.4591064453125);
     }
 
+    #[test]
+    fn test_neighbors() {
+        assert_eq!(super::neighbors("ezs42"),
+                   vec!["ezs48", "ezs49", "ezs43", "ezs41",
+                        "ezs40", "ezefp", "ezefr", "ezefx"]);
+    }
+
+    #[test]
+    fn test_neighbors_touch() {
+        let gbox = super::geobox_for_hash("9q8yvmg");
+        let north = super::geobox_for_hash(&super::neighbors("9q8yvmg")[0]);
+        assert_eq!(north.y_range.min, gbox.y_range.max);
+        assert_eq!(north.x_range.min, gbox.x_range.min);
+    }
+
+    #[test]
+    fn test_cover_bbox() {
+        let gbox = super::geobox_for_hash("9q8y");
+        // A box a bit inside 9q8y: covered by its 32 children
+        let inner = super::GeoBox::new(gbox.x_range.min + 0.0001,
+                                       gbox.x_range.max - 0.0001,
+                                       gbox.y_range.min + 0.0001,
+                                       gbox.y_range.max - 0.0001);
+        let cover = super::cover_bbox(&inner, 5);
+        assert_eq!(cover.len(), 32);
+        assert!(cover.iter().all(|h| h.starts_with("9q8y")));
+        assert_eq!(super::cover_bbox(&inner, 4),
+                   vec!["9q8y".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn test_cover_circle() {
+        let cover = super::cover_circle(37.7809921, -122.4600502, 800.0);
+        assert!(cover.len() <= 9);
+        assert!(cover.contains(&super::hash(37.7809921, -122.4600502,
+                                            cover.iter().next().unwrap().len())));
+        // A point 700m east must be under one of the prefixes
+        let east = super::hash(37.7809921, -122.4520502, 12);
+        assert!(cover.iter().any(|h| east.starts_with(h.as_str())));
+        // Prefixes are coarser than the circle, but not that much
+        let far = super::hash(37.7809921, -122.3400502, 12);
+        assert!(!cover.iter().any(|h| far.starts_with(h.as_str())));
+    }
+
+    #[test]
+    fn test_cover_antimeridian() {
+        let under = |cover: &std::collections::HashSet<String>, lat, lon| {
+            let h = super::hash(lat, lon, 12);
+            cover.iter().any(|c| h.starts_with(c.as_str()))
+        };
+
+        // 2km across the antimeridian is still in the circle
+        let cover = super::cover_circle(0.0, 179.99, 5000.0);
+        assert!(under(&cover, 0.0, 179.99));
+        assert!(under(&cover, 0.0, -179.99));
+        assert!(!under(&cover, 0.0, 0.0));
+
+        // Boxes given either way
+        let east_west = super::GeoBox::new(179.9, -179.9, 0.0, 0.1);
+        let past = super::GeoBox::new(179.9, 180.1, 0.0, 0.1);
+        for bbox in &[east_west, past] {
+            let cover = super::cover_bbox(bbox, 4);
+            assert!(under(&cover, 0.05, 179.95));
+            assert!(under(&cover, 0.05, -179.95));
+            assert!(!under(&cover, 0.05, 0.0));
+            assert!(!under(&cover, 0.05, 179.0));
+        }
+    }
+
 }
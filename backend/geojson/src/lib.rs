@@ -5,8 +5,9 @@ use serde::Serialize as AutoSerialize;
 #[derive(Debug, AutoSerialize)]
 pub struct GeoPoint(pub f64, pub f64);
 
+// A line of two points or more
 #[derive(Debug, AutoSerialize)]
-pub struct MultiLineSegment(pub GeoPoint, pub GeoPoint);
+pub struct MultiLineSegment(pub Vec<GeoPoint>);
 
 #[derive(Debug)]
 pub struct MultiLine {
@@ -59,10 +60,26 @@ impl Serialize for Point {
     }
 }
 
-#[derive(Debug, AutoSerialize)]
+#[derive(Debug, Default, AutoSerialize)]
 pub struct Properties {
     pub name: Option<String>,
     pub node_id: Option<i64>,
+    // Whether a wheelchair can go there, and what's in the way otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessible: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<String>,
+    // How we go along: walk or transit, and which line for the latter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headsign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_color: Option<String>,
 }
 
 #[derive(Debug)]
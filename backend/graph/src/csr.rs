@@ -0,0 +1,80 @@
+use std::ops::Range;
+
+// Compressed sparse row adjacency: nodes are dense u32 indices and the
+// edges leaving node i are targets/edges[offsets[i]..offsets[i + 1]].
+// Built once, read only.
+#[derive(Debug)]
+pub struct Csr<E> {
+    offsets: Vec<u32>,
+    targets: Vec<u32>,
+    edges: Vec<E>
+}
+
+impl<E> Csr<E> {
+    pub fn empty() -> Self {
+        Csr {
+            offsets: vec![0],
+            targets: Vec::new(),
+            edges: Vec::new()
+        }
+    }
+
+    // From (from, to, edge) triples, in any order
+    pub fn new(nodes: usize, mut list: Vec<(u32, u32, E)>) -> Self {
+        list.sort_by_key(|(from, _, _)| *from);
+
+        let mut offsets = vec![0u32; nodes + 1];
+        for (from, _, _) in &list {
+            offsets[*from as usize + 1] += 1;
+        }
+        for i in 0..nodes {
+            offsets[i + 1] += offsets[i];
+        }
+
+        let (targets, edges) = list.into_iter()
+            .map(|(_, to, edge)| (to, edge))
+            .unzip();
+
+        Csr {
+            offsets,
+            targets,
+            edges
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    // Nodes out of the graph have no edges
+    fn range(&self, node: u32) -> Range<usize> {
+        match (self.offsets.get(node as usize),
+               self.offsets.get(node as usize + 1)) {
+            (Some(&a), Some(&b)) => a as usize..b as usize,
+            _ => 0..0
+        }
+    }
+
+    pub fn degree(&self, node: u32) -> usize {
+        self.range(node).len()
+    }
+
+    pub fn edge_at(&self, node: u32, i: usize) -> Option<(u32, &E)> {
+        let range = self.range(node);
+        if i < range.len() {
+            Some((self.targets[range.start + i], &self.edges[range.start + i]))
+        } else {
+            None
+        }
+    }
+
+    pub fn edges(&self, node: u32) -> impl Iterator<Item=(u32, &E)> {
+        let range = self.range(node);
+        self.targets[range.clone()].iter().cloned()
+            .zip(self.edges[range].iter())
+    }
+}
@@ -1,29 +1,43 @@
-use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::time::{Duration, Instant};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::time::Instant;
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::hash::Hasher;
 use std::vec::Vec;
-use std::rc::Rc;
 use std::fmt;
 
 #[macro_use]
 extern crate log;
 
+mod csr;
+mod scc;
+
+pub use csr::Csr;
+pub use scc::strongly_connected;
+
 pub trait Graph: Sized {
     type Node: Hash + Eq + Debug;
     type Edge: Debug;
+
+    // Dense index of a node, labels of a walk are kept in a Vec by it.
+    // Nodes that only exist for a walk (snapped points) go past
+    // node_count().
+    fn node_index(&self, node: &Self::Node) -> usize;
+    fn node_count(&self) -> usize;
 }
 
+// A step a walker can take: edge, cost, heuristic, node, state
+pub type Neighbor<'a, G, S> =
+    (&'a <G as Graph>::Edge, u64, u64, &'a <G as Graph>::Node, S);
+
 // A walker state needs clone/copy - try to keep it small?
 pub trait GraphWalker<'a, G: Graph> : Sized {
     type State: Debug + Sized + Clone;
 
     // Get neighbors and cost to join them for state
-    fn neighbors(&self, graph: &'a G, prev: &Step<'a, G, Self>) ->
-        // Return type being Way - cost, heuristic, node, state
-        Vec<(&'a G::Edge, u64, u64, &'a G::Node, Self::State)>;
+    fn neighbors(&self, graph: &'a G, prev: &Step<'a, G, Self>)
+        -> Vec<Neighbor<'a, G, Self::State>>;
 
     fn initial_state(&self, node: &G::Node) -> Self::State;
 
@@ -40,9 +54,11 @@ pub trait GraphWalker<'a, G: Graph> : Sized {
     fn include(&self, step: &Step<'a, G, Self>) -> bool;
 }
 
+// Steps live in the arena of their WalkResult, the previous one is an
+// index in there.
 #[derive(Debug)]
 pub struct Step<'a, G: Graph, W: GraphWalker<'a, G>> {
-    pub from: Option<Rc<Step<'a, G, W>>>, // Node_id
+    pub from: Option<u32>,         // previous step
     pub edge: Option<&'a G::Edge>, // Edge used
     pub cost: u64,                 // cost for this step
     pub total: u64,                // total cost so far
@@ -54,7 +70,7 @@ pub struct Step<'a, G: Graph, W: GraphWalker<'a, G>> {
 impl<'a, G: Graph, W: GraphWalker<'a, G>> fmt::Display for Step<'a, G, W> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.from {
-            Some(prev) => write!(f, "Step from {:?}", prev.to)?,
+            Some(prev) => write!(f, "Step from #{}", prev)?,
             None => write!(f, "?")?
         }
         write!(f, "->{:?}", self.to)?;
@@ -62,33 +78,39 @@ impl<'a, G: Graph, W: GraphWalker<'a, G>> fmt::Display for Step<'a, G, W> {
     }
 }
 
-// PartialOrd for the priority queue
-impl<'a, G: Graph, W: GraphWalker<'a, G>> PartialOrd for Step<'a, G, W> {
-    fn partial_cmp(&self, other: &Step<'a, G, W>) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+#[derive(Debug)]
+pub struct WalkResult<'a, G: Graph, W: GraphWalker<'a, G>> {
+    arena: Vec<Step<'a, G, W>>, // every step we relaxed
+    included: Vec<u32>,         // the ones the walker wanted, in order
+    pub nr_inv: u64 // Number of steps to build the graph (debug)
 }
 
-impl<'a, G: Graph, W: GraphWalker<'a, G>> Ord for Step<'a, G, W> {
-    fn cmp(&self, other: &Step<'a, G, W>) -> Ordering {
-        // Reversed for a minqueue
-        other.total_f.cmp(&self.total_f)
+impl<'a, G: Graph, W: GraphWalker<'a, G>> WalkResult<'a, G, W> {
+    fn new() -> Self {
+        WalkResult { arena: Vec::new(), included: Vec::new(), nr_inv: 0 }
     }
-}
 
-impl<'a, G: Graph, W: GraphWalker<'a, G>> PartialEq for Step<'a, G, W> {
-    fn eq(&self, other: &Step<'a, G, W>) -> bool {
-        //XXX
-        self.total == other.total && self.to == self.to
+    pub fn steps(&self) -> impl Iterator<Item=&Step<'a, G, W>> {
+        self.included.iter().map(move |i| &self.arena[*i as usize])
     }
-}
 
-impl<'a, G: Graph, W: GraphWalker<'a, G>> Eq for Step<'a, G, W> {}
+    pub fn len(&self) -> usize {
+        self.included.len()
+    }
 
-#[derive(Debug)]
-pub struct WalkResult<'a, G: Graph, W: GraphWalker<'a, G>> {
-    pub steps: Vec<Rc<Step<'a, G, W>>>,
-    pub nr_inv: u64 // Number of steps to build the graph (debug)
+    pub fn is_empty(&self) -> bool {
+        self.included.is_empty()
+    }
+
+    pub fn prev(&self, step: &Step<'a, G, W>) -> Option<&Step<'a, G, W>> {
+        step.from.map(|i| &self.arena[i as usize])
+    }
+
+    // From step back to the start
+    pub fn path<'r>(&'r self, step: &'r Step<'a, G, W>)
+        -> ReverseStepIterator<'r, 'a, G, W> {
+        ReverseStepIterator { res: self, current: Some(step) }
+    }
 }
 
 // neighbors() has the previous step with state
@@ -102,100 +124,159 @@ pub struct WalkResult<'a, G: Graph, W: GraphWalker<'a, G>> {
 //  - not turn right cause constraint
 //  - etc.
 
-struct DistanceWithGraph<'a, G: Graph> {
-    node: &'a G::Node,
-    graph_id: u64
+// Best totals of a walk, by dense node index, for each graph id. Labels
+// from older walks are told apart by their generation, so nothing is
+// cleared between walks.
+#[derive(Debug, Clone, Copy, Default)]
+struct Label {
+    generation: u32,
+    total: u64
 }
 
-impl<'a, G: Graph> Eq for DistanceWithGraph<'a, G> {}
-impl<'a, G: Graph> PartialEq for DistanceWithGraph<'a, G> {
-    fn eq(&self, other: &DistanceWithGraph<'a, G>) -> bool {
-        //XXX
-        self.node == other.node &&
-            self.graph_id == other.graph_id
-    }
+#[derive(Debug, Default)]
+struct Scratch {
+    queue: BinaryHeap<Reverse<(u64, u32)>>, // total_f, step index
+    layers: Vec<(u64, Vec<Label>)>,         // graph id, labels
+    generation: u32
 }
-impl<'a, G: Graph> Hash for DistanceWithGraph<'a, G> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.node.hash(state);
-        self.graph_id.hash(state);
+
+impl Scratch {
+    fn reset(&mut self) {
+        self.queue.clear();
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.layers.clear();
+            self.generation = 1;
+        }
+    }
+
+    fn known(&self, graph_id: u64, node: usize) -> Option<u64> {
+        let generation = self.generation;
+        self.layers.iter()
+            .find(|(id, _)| *id == graph_id)
+            .and_then(|(_, labels)| labels.get(node))
+            .filter(|label| label.generation == generation)
+            .map(|label| label.total)
+    }
+
+    fn set(&mut self, graph_id: u64, node: usize, total: u64, size: usize) {
+        let layer = match self.layers.iter().position(|(id, _)| *id == graph_id) {
+            Some(i) => i,
+            None => {
+                self.layers.push((graph_id, Vec::new()));
+                self.layers.len() - 1
+            }
+        };
+        let labels = &mut self.layers[layer].1;
+        if node >= labels.len() {
+            labels.resize(size.max(node + 1), Label::default());
+        }
+        labels[node] = Label { generation: self.generation, total };
     }
 }
 
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
 // Visit nodes, relaxing distances
 pub fn walk<'a, G: Graph, W: GraphWalker<'a, G>>(graph: &'a G, walker: W, start: &'a G::Node) -> WalkResult<'a, G, W> {
-    let mut queue = BinaryHeap::new();
-    let mut distances = HashMap::<DistanceWithGraph<'a, G>, u64>::new();
+    walk_from(graph, walker, &[(start, 0)])
+}
 
-    let mut res = WalkResult{ steps: Vec::new(), nr_inv: 0 };
-    let start_time = Instant::now();
+// Same as walk, from several starts at once, each with the total it
+// starts at: a step goes back to the start it's the closest to. Starts
+// are settled as for walk, they only show up when walked back to for less.
+pub fn walk_from<'a, G: Graph, W: GraphWalker<'a, G>>(graph: &'a G, walker: W,
+    starts: &[(&'a G::Node, u64)]) -> WalkResult<'a, G, W> {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut scratch) => walk_with(graph, walker, starts, &mut scratch),
+        // A walker walking from its neighbors() gets its own
+        Err(_) => walk_with(graph, walker, starts, &mut Scratch::default())
+    })
+}
 
-    queue.push(Rc::new(Step{
-        from: None,
-        edge: None,
-        cost: 0,
-        total: 0,
-        total_f: 0, // As in f = g+h
-        to: start,
-        state: walker.initial_state(start)}));
-
-    while let Some(step) = queue.pop() {
-        // XXX Is this still needed?
-        let known = distances.get(&DistanceWithGraph{
-            graph_id: walker.graph_id(&step.state),
-            node: &step.to
-        });
+fn walk_with<'a, G: Graph, W: GraphWalker<'a, G>>(graph: &'a G, walker: W,
+    starts: &[(&'a G::Node, u64)], scratch: &mut Scratch)
+    -> WalkResult<'a, G, W> {
+    let mut res = WalkResult::new();
+    let start_time = Instant::now();
+    let size = graph.node_count();
+
+    scratch.reset();
+    for &(start, total) in starts {
+        let state = walker.initial_state(start);
+        let graph_id = walker.graph_id(&state);
+        let index = graph.node_index(start);
+        if total >= scratch.known(graph_id, index).unwrap_or(total + 1) {
+            continue;
+        }
+        scratch.set(graph_id, index, total, size);
+        let at = res.arena.len() as u32;
+        res.arena.push(Step{
+            from: None,
+            edge: None,
+            cost: 0,
+            total,
+            total_f: total, // As in f = g+h
+            to: start,
+            state});
+        scratch.queue.push(Reverse((total, at)));
+    }
 
+    while let Some(Reverse((_, current))) = scratch.queue.pop() {
         if walker.stop(&res) {
             break
         }
 
-        match known {
-            Some(&distance) if step.total > distance => {
+        let step = &res.arena[current as usize];
+        match scratch.known(walker.graph_id(&step.state),
+                            graph.node_index(step.to)) {
+            Some(distance) if step.total > distance => {
                 continue;
             }
             _ => {}
         }
-        // EOXXX -- walker.stop() is needed.
 
         res.nr_inv += 1;
 
-        for (edge, w, h, node, new_state) in walker.neighbors(graph, &step) {
-            let total = step.total + w;
-            let known = distances.entry(DistanceWithGraph{
-                graph_id: walker.graph_id(&new_state),
-                node: node
-            }).or_insert(total + 1);
-            trace!("from {:?} potential {:?} {}+{} {:?}", step.to,
+        let total_so_far = step.total;
+        for (edge, w, h, node, new_state) in walker.neighbors(graph, step) {
+            let total = total_so_far + w;
+            let graph_id = walker.graph_id(&new_state);
+            let index = graph.node_index(node);
+            trace!("from #{} potential {:?} {}+{} {:?}", current,
                    node, w, h, new_state);
 
             // Relax if below
             // The closest will pop out first
-            if total < *known {
-                *known = total;
+            if total < scratch.known(graph_id, index).unwrap_or(total + 1) {
+                scratch.set(graph_id, index, total, size);
 
-                let new_step = Rc::new(Step {
-                    from: Some(Rc::clone(&step)),
+                let new_step = Step {
+                    from: Some(current),
                     edge: Some(edge),
                     cost: w,
-                    total: total,
+                    total,
                     total_f: total + h,
                     to: node,
                     state: new_state,
-                });
+                };
 
+                let at = res.arena.len() as u32;
                 if walker.include(&new_step) {
-                    res.steps.push(Rc::clone(&new_step));
+                    res.included.push(at);
                 }
+                res.arena.push(new_step);
 
-                queue.push(new_step);
+                scratch.queue.push(Reverse((total + h, at)));
             }
-
        }
     }
 
-    debug!("Walker from {:?} in {:?}, cost {}", start, start_time.elapsed(),
-        res.nr_inv);
+    debug!("Walker from {:?} in {:?}, cost {}",
+        starts.iter().map(|(start, _)| start).collect::<Vec<_>>(),
+        start_time.elapsed(), res.nr_inv);
 
     res
 }
@@ -207,27 +288,26 @@ impl<'a, G: Graph, W: GraphWalker<'a, G>> BitAnd for WalkResult<'a, G, W> {
     type Output = Self;
 
     fn bitand(self, rhs: Self) -> Self::Output {
-        let nodes = self
-            .steps
-            .iter()
-            .map(|step| step.to)
-            .collect::<HashSet<&G::Node>>();
-        let other = rhs
-            .steps
+        let common = {
+            let other = rhs
+                .steps()
+                .map(|step| step.to)
+                .collect::<HashSet<&G::Node>>();
+            self.steps()
+                .map(|step| step.to)
+                .filter(|node| other.contains(node))
+                .collect::<HashSet<&G::Node>>()
+        };
+
+        let included = self.included
             .iter()
-            .map(|step| step.to)
-            .collect::<HashSet<&G::Node>>();
-        let common = nodes
-            .intersection(&other)
             .cloned()
-            .collect::<HashSet<&G::Node>>();
+            .filter(|i| common.contains(self.arena[*i as usize].to))
+            .collect();
 
         Self {
-            steps: self
-                .steps
-                .into_iter()
-                .filter(|step| common.contains(step.to))
-                .collect(),
+            arena: self.arena,
+            included,
             nr_inv: self.nr_inv
         }
     }
@@ -237,38 +317,158 @@ impl<'a, G: Graph, W: GraphWalker<'a, G>> BitAnd for WalkResult<'a, G, W> {
 impl<'a, G: Graph, W: GraphWalker<'a, G>> BitOr for WalkResult<'a, G, W> {
     type Output = Self;
 
-    fn bitor(self, rhs: Self) -> Self::Output {
-        Self {
-            steps: self.steps.into_iter()
-                    .chain(rhs.steps.into_iter())
-                    .collect(),
-            nr_inv: self.nr_inv
-        }
+    fn bitor(mut self, rhs: Self) -> Self::Output {
+        // rhs steps move after ours, shift their indices
+        let offset = self.arena.len() as u32;
+        self.arena.extend(rhs.arena.into_iter().map(|mut step| {
+            step.from = step.from.map(|i| i + offset);
+            step
+        }));
+        self.included.extend(rhs.included.into_iter().map(|i| i + offset));
+        self
     }
 }
 
-pub struct ReverseStepIterator<'a, G: Graph, W: GraphWalker<'a, G>> {
-    current: Option<&'a Step<'a, G, W>>
+pub struct ReverseStepIterator<'r, 'a, G: Graph, W: GraphWalker<'a, G>> {
+    res: &'r WalkResult<'a, G, W>,
+    current: Option<&'r Step<'a, G, W>>
 }
 
-impl<'a, G: Graph, W: GraphWalker<'a, G>> Iterator
-	for ReverseStepIterator<'a, G, W> {
-    type Item = &'a Step<'a, G, W>;
+impl<'r, 'a, G: Graph, W: GraphWalker<'a, G>> Iterator
+	for ReverseStepIterator<'r, 'a, G, W> {
+    type Item = &'r Step<'a, G, W>;
 
-    fn next(&mut self) -> Option<&'a Step<'a, G, W>> {
-        self.current.map(|cur| {
-            self.current = cur.from.as_ref().map(|x| x.as_ref());
-            cur
-        })
+    fn next(&mut self) -> Option<&'r Step<'a, G, W>> {
+        let res = self.res;
+        let cur = self.current?;
+        self.current = res.prev(cur);
+        Some(cur)
     }
 }
 
-impl<'a, G: Graph, W: GraphWalker<'a, G>> IntoIterator
-    for &'a Step<'a, G, W> {
-    type Item = &'a Step<'a, G, W>;
-    type IntoIter = ReverseStepIterator<'a, G, W>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq, Hash)]
+    struct Point(u32);
+
+    struct Line {
+        nodes: Vec<Point>,
+        edges: Csr<u64>
+    }
+
+    impl Graph for Line {
+        type Node = Point;
+        type Edge = u64;
+
+        fn node_index(&self, node: &Point) -> usize { node.0 as usize }
+        fn node_count(&self) -> usize { self.nodes.len() }
+    }
+
+    struct Reach(u64);
+
+    impl<'a> GraphWalker<'a, Line> for Reach {
+        type State = ();
+
+        fn neighbors(&self, line: &'a Line, prev: &Step<'a, Line, Self>)
+            -> Vec<(&'a u64, u64, u64, &'a Point, ())> {
+            line.edges.edges(prev.to.0)
+                .filter(|(_, w)| prev.total + **w <= self.0)
+                .map(|(to, w)| (w, *w, 0, &line.nodes[to as usize], ()))
+                .collect()
+        }
+
+        fn initial_state(&self, _: &Point) {}
+        fn graph_id(&self, _: &()) -> u64 { 0 }
+        fn stop(&self, _: &WalkResult<'a, Line, Self>) -> bool { false }
+        fn include(&self, _: &Step<'a, Line, Self>) -> bool { true }
+    }
+
+    // 0 -1- 1 -2- 2 -3- 3, and a 10 long shortcut 0-3
+    fn line() -> Line {
+        let edges = vec![(0, 1, 1), (1, 2, 2), (2, 3, 3), (0, 3, 10)]
+            .into_iter()
+            .flat_map(|(a, b, w)| vec![(a, b, w), (b, a, w)])
+            .collect();
+        Line {
+            nodes: (0..4).map(Point).collect(),
+            edges: Csr::new(4, edges)
+        }
+    }
+
+    #[test]
+    fn csr() {
+        let line = line();
+        assert_eq!(line.edges.node_count(), 4);
+        assert_eq!(line.edges.edge_count(), 8);
+        assert_eq!(line.edges.degree(0), 2);
+        assert_eq!(line.edges.degree(7), 0);
+        let mut from_2 = line.edges.edges(2).collect::<Vec<(u32, &u64)>>();
+        from_2.sort();
+        assert_eq!(from_2, vec![(1, &2), (3, &3)]);
+    }
+
+    #[test]
+    fn shortest() {
+        let line = line();
+        let res = walk(&line, Reach(100), &line.nodes[0]);
+        let to_3 = res.steps().filter(|step| step.to.0 == 3)
+            .min_by_key(|step| step.total).unwrap();
+        assert_eq!(to_3.total, 6);
+        assert_eq!(res.path(to_3).map(|step| step.to.0).collect::<Vec<u32>>(),
+                   vec![3, 2, 1, 0]);
+        // The start is settled, never walked back to
+        assert!(res.steps().all(|step| step.to.0 != 0));
+    }
+
+    #[test]
+    fn bounded() {
+        let line = line();
+        let res = walk(&line, Reach(3), &line.nodes[0]);
+        let mut reached = res.steps().map(|step| step.to.0).collect::<Vec<u32>>();
+        reached.sort();
+        assert_eq!(reached, vec![1, 2]);
+    }
+
+    #[test]
+    fn from_many() {
+        let line = line();
+        // 3 starts late, 1 is still closer to it than 0
+        let res = walk_from(&line, Reach(6),
+                            &[(&line.nodes[0], 0), (&line.nodes[3], 4)]);
+        let mut reached = res.steps()
+            .map(|step| (step.to.0, step.total,
+                         res.path(step).last().unwrap().to.0))
+            .collect::<Vec<(u32, u64, u32)>>();
+        reached.sort();
+        assert_eq!(reached, vec![(1, 1, 0), (2, 3, 0)]);
+
+        let res = walk_from(&line, Reach(6),
+                            &[(&line.nodes[0], 4), (&line.nodes[3], 0)]);
+        let mut reached = res.steps()
+            .map(|step| (step.to.0, step.total,
+                         res.path(step).last().unwrap().to.0))
+            .collect::<Vec<(u32, u64, u32)>>();
+        reached.sort();
+        assert_eq!(reached, vec![(1, 5, 3), (2, 3, 3)]);
+    }
 
-    fn into_iter(self) -> ReverseStepIterator<'a, G, W> {
-        ReverseStepIterator{ current: Some(self) }
+    #[test]
+    fn and_or() {
+        let line = line();
+        let a = walk(&line, Reach(3), &line.nodes[0]);
+        let b = walk(&line, Reach(3), &line.nodes[3]);
+        let both = a & b;
+        assert_eq!(both.steps().map(|step| step.to.0).collect::<Vec<u32>>(),
+                   vec![2]);
+
+        let a = walk(&line, Reach(1), &line.nodes[0]);
+        let b = walk(&line, Reach(3), &line.nodes[3]);
+        let any = a | b;
+        assert_eq!(any.len(), 2);
+        let last = any.steps().last().unwrap();
+        assert_eq!(any.path(last).map(|step| step.to.0).collect::<Vec<u32>>(),
+                   vec![2, 3]);
     }
 }
@@ -0,0 +1,99 @@
+use super::Csr;
+
+const NONE: u32 = u32::MAX;
+
+// Strongly connected components of the edges allowed, Tarjan without
+// recursion so a whole state doesn't blow the stack. Gives the component
+// of each node, numbered from 0 in no particular order.
+pub fn strongly_connected<E, F: Fn(&E) -> bool>(graph: &Csr<E>, allowed: F)
+    -> Vec<u32> {
+    let n = graph.node_count();
+    let mut index = vec![NONE; n];
+    let mut lowlink = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut component = vec![NONE; n];
+    let mut stack = Vec::new();
+    let mut calls = Vec::new(); // node, next edge to look at
+    let mut next_index = 0;
+    let mut next_component = 0;
+
+    for root in 0..n as u32 {
+        if index[root as usize] != NONE {
+            continue;
+        }
+
+        index[root as usize] = next_index;
+        lowlink[root as usize] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root as usize] = true;
+        calls.push((root, 0));
+
+        while let Some((node, pos)) = calls.pop() {
+            let v = node as usize;
+            match graph.edge_at(node, pos) {
+                Some((to, edge)) => {
+                    calls.push((node, pos + 1));
+                    if !allowed(edge) {
+                        continue;
+                    }
+                    let w = to as usize;
+                    if index[w] == NONE {
+                        index[w] = next_index;
+                        lowlink[w] = next_index;
+                        next_index += 1;
+                        stack.push(to);
+                        on_stack[w] = true;
+                        calls.push((to, 0));
+                    } else if on_stack[w] {
+                        lowlink[v] = lowlink[v].min(index[w]);
+                    }
+                },
+                None => {
+                    // Done with node, is it the root of a component?
+                    if lowlink[v] == index[v] {
+                        while let Some(w) = stack.pop() {
+                            on_stack[w as usize] = false;
+                            component[w as usize] = next_component;
+                            if w == node {
+                                break;
+                            }
+                        }
+                        next_component += 1;
+                    }
+                    if let Some(&(parent, _)) = calls.last() {
+                        let p = parent as usize;
+                        lowlink[p] = lowlink[p].min(lowlink[v]);
+                    }
+                }
+            }
+        }
+    }
+
+    component
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components() {
+        // 0 -> 1 -> 2 -> 0 cycle, 2 -> 3 one way, 3 <-> 4, 5 alone,
+        // 6 <-> 7 only through a forbidden edge
+        let graph = Csr::new(8, vec![
+            (0, 1, true), (1, 2, true), (2, 0, true), (2, 3, true),
+            (3, 4, true), (4, 3, true), (6, 7, false), (7, 6, false)
+        ]);
+        let c = strongly_connected(&graph, |allowed| *allowed);
+
+        assert_eq!(c[0], c[1]);
+        assert_eq!(c[1], c[2]);
+        assert_eq!(c[3], c[4]);
+        assert_ne!(c[0], c[3]);
+        assert_ne!(c[5], c[0]);
+        assert_ne!(c[5], c[3]);
+        assert_ne!(c[6], c[7]);
+        assert_eq!(c.iter().max(), Some(&4));
+    }
+}
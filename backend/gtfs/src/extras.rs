@@ -0,0 +1,497 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+
+use super::LoadError;
+
+// The files of a feed (zip or directory) gtfs_structures doesn't read
+// for us. Missing ones are empty.
+fn read_csv<T: DeserializeOwned>(feed: &str, name: &str)
+    -> Result<Vec<T>, LoadError> {
+    let mut content = vec![];
+    if Path::new(feed).is_dir() {
+        match File::open(Path::new(feed).join(name)) {
+            Ok(mut file) => { file.read_to_end(&mut content)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound =>
+                return Ok(vec![]),
+            Err(err) => return Err(err.into())
+        }
+    } else {
+        let mut archive = zip::ZipArchive::new(File::open(feed)?)?;
+        // Some feeds have everything in a folder
+        let suffix = format!("/{}", name);
+        let index = (0..archive.len()).find(|i| archive.by_index(*i)
+            .map(|file| file.name() == name || file.name().ends_with(&suffix))
+            .unwrap_or(false));
+        match index {
+            Some(i) => { archive.by_index(i)?.read_to_end(&mut content)?; },
+            None => return Ok(vec![])
+        }
+    }
+
+    let content = if content.starts_with(b"\xef\xbb\xbf") {
+        &content[3..]
+    } else {
+        &content[..]
+    };
+    Ok(csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(content)
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()?)
+}
+
+// HH:MM:SS in seconds, past 24:00:00 for trips after midnight
+pub fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) =>
+            h.checked_mul(3600)?.checked_add(m.checked_mul(60)?)?.checked_add(s),
+        _ => None
+    }
+}
+
+// Back to HH:MM:SS, past 24:00:00 for trips running after midnight
+pub fn format_time(time: u32) -> String {
+    format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60)
+}
+
+fn time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    parse_time(&time).ok_or(serde::de::Error::custom(
+            format!("bad time {}", time)))
+}
+
+// Headway based service: the trip's stop_times are a template run every
+// headway_secs between start and end
+#[derive(Debug, Clone, Deserialize)]
+pub struct Frequency {
+    pub trip_id: String,
+    #[serde(deserialize_with = "time")]
+    pub start_time: u32,
+    #[serde(deserialize_with = "time")]
+    pub end_time: u32,
+    pub headway_secs: u32,
+    #[serde(default)]
+    exact_times: Option<u8>
+}
+
+impl Frequency {
+    pub fn load(feed: &str) -> Result<HashMap<String, Vec<Frequency>>, LoadError> {
+        let mut by_trip = HashMap::new();
+        for frequency in read_csv::<Frequency>(feed, "frequencies.txt")? {
+            by_trip.entry(frequency.trip_id.to_string())
+                .or_insert(vec![])
+                .push(frequency);
+        }
+        Ok(by_trip)
+    }
+
+    // Otherwise riders only know there's one every headway
+    pub fn is_exact(&self) -> bool {
+        self.exact_times == Some(1)
+    }
+
+    // When the trip starts, from after on
+    pub fn starts(&self, after: u32) -> impl Iterator<Item=u32> {
+        let headway = self.headway_secs.max(1);
+        let first = if after <= self.start_time {
+            self.start_time
+        } else {
+            self.start_time + (after - self.start_time + headway - 1)
+                / headway * headway
+        };
+        (first..self.end_time).step_by(headway as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferKind {
+    Recommended,
+    Timed,        // the second trip waits for the first one
+    MinTime(u32), // seconds
+    Forbidden
+}
+
+#[derive(Debug, Deserialize)]
+struct TransferRow {
+    from_stop_id: String,
+    to_stop_id: String,
+    #[serde(default)]
+    from_route_id: Option<String>,
+    #[serde(default)]
+    to_route_id: Option<String>,
+    #[serde(default)]
+    from_trip_id: Option<String>,
+    #[serde(default)]
+    to_trip_id: Option<String>,
+    #[serde(default)]
+    transfer_type: Option<u8>,
+    #[serde(default)]
+    min_transfer_time: Option<u32>
+}
+
+// A declared transfer between two stops, or at the same stop. Routes and
+// trips restrict it when given.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub to_stop: String,
+    from_route: Option<String>,
+    to_route: Option<String>,
+    from_trip: Option<String>,
+    to_trip: Option<String>,
+    pub kind: TransferKind
+}
+
+// (route id, trip id) of a side of a transfer, empty if on foot
+pub type Leg<'a> = (&'a str, &'a str);
+
+fn matches(field: &Option<String>, value: &str) -> bool {
+    field.as_ref().map_or(true, |field| field == value)
+}
+
+impl Transfer {
+    fn applies(&self, from: Leg, to: Leg) -> bool {
+        matches(&self.from_route, from.0) && matches(&self.from_trip, from.1)
+            && matches(&self.to_route, to.0) && matches(&self.to_trip, to.1)
+    }
+
+    // Trips are more specific than routes, than only stops
+    fn specificity(&self) -> u8 {
+        let trips = self.from_trip.is_some() as u8 + self.to_trip.is_some() as u8;
+        let routes = self.from_route.is_some() as u8 + self.to_route.is_some() as u8;
+        trips * 3 + routes
+    }
+
+    pub fn between_stops(&self) -> bool {
+        self.specificity() == 0
+    }
+}
+
+// transfers.txt, by from_stop_id
+#[derive(Debug, Default)]
+pub struct Transfers(HashMap<String, Vec<Transfer>>);
+
+impl Transfers {
+    pub fn load(feed: &str) -> Result<Self, LoadError> {
+        let mut by_stop = HashMap::new();
+        for row in read_csv::<TransferRow>(feed, "transfers.txt")? {
+            let kind = match (row.transfer_type.unwrap_or(0), row.min_transfer_time) {
+                (1, _) => TransferKind::Timed,
+                (2, Some(time)) => TransferKind::MinTime(time),
+                (3, _) => TransferKind::Forbidden,
+                // XXX 4 and 5 are in-seat, we don't do blocks
+                (4, _) => TransferKind::Timed,
+                (5, _) => TransferKind::Forbidden,
+                _ => TransferKind::Recommended
+            };
+            by_stop.entry(row.from_stop_id)
+                .or_insert(vec![])
+                .push(Transfer {
+                    to_stop: row.to_stop_id,
+                    from_route: row.from_route_id,
+                    to_route: row.to_route_id,
+                    from_trip: row.from_trip_id,
+                    to_trip: row.to_trip_id,
+                    kind: kind
+                });
+        }
+        Ok(Transfers(by_stop))
+    }
+
+    pub fn from(&self, stop_id: &str) -> &[Transfer] {
+        self.0.get(stop_id).map_or(&[], |transfers| &transfers[..])
+    }
+
+    // The most specific transfer declared for that change, if any
+    pub fn find(&self, from_stop: &str, to_stop: &str, from: Leg, to: Leg)
+        -> Option<TransferKind> {
+        self.from(from_stop).iter()
+            .filter(|transfer| transfer.to_stop == to_stop
+                    && transfer.applies(from, to))
+            .max_by_key(|transfer| transfer.specificity())
+            .map(|transfer| transfer.kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathwayMode {
+    Walkway,
+    Stairs,
+    MovingSidewalk,
+    Escalator,
+    Elevator,
+    FareGate,
+    ExitGate
+}
+
+#[derive(Debug, Deserialize)]
+struct PathwayRow {
+    from_stop_id: String,
+    to_stop_id: String,
+    pathway_mode: u8,
+    is_bidirectional: u8,
+    #[serde(default)]
+    length: Option<f64>,
+    #[serde(default)]
+    traversal_time: Option<u32>,
+    #[serde(default)]
+    stair_count: Option<i32>
+}
+
+// A way through a station, between its entrances, nodes and platforms
+#[derive(Debug, Clone)]
+pub struct Pathway {
+    pub to_stop: String,
+    pub mode: PathwayMode,
+    pub time: u32 // seconds
+}
+
+impl Pathway {
+    // In a wheelchair
+    pub fn is_accessible(&self) -> bool {
+        match self.mode {
+            PathwayMode::Stairs | PathwayMode::Escalator => false,
+            _ => true
+        }
+    }
+}
+
+impl PathwayRow {
+    // Feeds don't always give traversal times, guess from what's there
+    fn time(&self, mode: PathwayMode) -> u32 {
+        if let Some(time) = self.traversal_time {
+            return time;
+        }
+        match (mode, self.length, self.stair_count) {
+            (PathwayMode::Elevator, _, _) => 60,
+            (PathwayMode::Stairs, _, Some(stairs)) => stairs.abs() as u32,
+            (PathwayMode::Stairs, Some(length), _) => (length / 0.5) as u32,
+            (_, Some(length), _) => (length / 1.2) as u32,
+            _ => 30
+        }
+    }
+}
+
+// pathways.txt, by from_stop_id, both ways for bidirectional ones
+#[derive(Debug, Default)]
+pub struct Pathways(HashMap<String, Vec<Pathway>>);
+
+impl Pathways {
+    pub fn load(feed: &str) -> Result<Self, LoadError> {
+        let mut by_stop = HashMap::new();
+        for row in read_csv::<PathwayRow>(feed, "pathways.txt")? {
+            let mode = match row.pathway_mode {
+                2 => PathwayMode::Stairs,
+                3 => PathwayMode::MovingSidewalk,
+                4 => PathwayMode::Escalator,
+                5 => PathwayMode::Elevator,
+                6 => PathwayMode::FareGate,
+                7 => PathwayMode::ExitGate,
+                _ => PathwayMode::Walkway
+            };
+            let time = row.time(mode);
+            if row.is_bidirectional == 1 {
+                by_stop.entry(row.to_stop_id.to_string())
+                    .or_insert(vec![])
+                    .push(Pathway {
+                        to_stop: row.from_stop_id.to_string(),
+                        mode: mode,
+                        time: time
+                    });
+            }
+            by_stop.entry(row.from_stop_id)
+                .or_insert(vec![])
+                .push(Pathway {
+                    to_stop: row.to_stop_id,
+                    mode: mode,
+                    time: time
+                });
+        }
+        Ok(Pathways(by_stop))
+    }
+
+    pub fn from(&self, stop_id: &str) -> &[Pathway] {
+        self.0.get(stop_id).map_or(&[], |pathways| &pathways[..])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Seconds to every stop reachable from stop_id through the station
+    pub fn times_from(&self, stop_id: &str, accessible: bool)
+        -> HashMap<String, u32> {
+        let mut times = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse((0, stop_id.to_string())));
+
+        while let Some(Reverse((time, stop))) = queue.pop() {
+            if times.contains_key(&stop) {
+                continue;
+            }
+            for pathway in self.from(&stop) {
+                if (!accessible || pathway.is_accessible())
+                    && !times.contains_key(&pathway.to_stop) {
+                    queue.push(Reverse((time + pathway.time,
+                                        pathway.to_stop.to_string())));
+                }
+            }
+            times.insert(stop, time);
+        }
+        times
+    }
+}
+
+// How a route is shown to riders, colors are hex without the #
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteStyle {
+    pub route_id: String,
+    pub route_short_name: Option<String>,
+    pub route_long_name: Option<String>,
+    pub route_color: Option<String>,
+    pub route_text_color: Option<String>
+}
+
+impl RouteStyle {
+    pub fn load(feed: &str) -> Result<HashMap<String, RouteStyle>, LoadError> {
+        Ok(read_csv::<RouteStyle>(feed, "routes.txt")?.into_iter()
+            .map(|route| (route.route_id.to_string(), route))
+            .collect())
+    }
+
+    // Short name, long one if there's none
+    pub fn name(&self) -> Option<&str> {
+        self.route_short_name.as_ref().or(self.route_long_name.as_ref())
+            .map(|name| name.as_str())
+            .filter(|name| !name.is_empty())
+    }
+
+    pub fn color(&self) -> Option<String> {
+        self.route_color.as_ref().filter(|color| !color.is_empty())
+            .map(|color| format!("#{}", color))
+    }
+
+    pub fn text_color(&self) -> Option<String> {
+        self.route_text_color.as_ref().filter(|color| !color.is_empty())
+            .map(|color| format!("#{}", color))
+    }
+}
+
+// The part of a shape (lon/lat points in order) between two stops. Shapes
+// don't tell where stops are, take the closest points, the second one
+// after the first so loops work.
+pub fn cut_shape(shape: &[(f64, f64)], from: (f64, f64), to: (f64, f64))
+    -> Vec<(f64, f64)> {
+    let closest = |points: &[(f64, f64)], (lon, lat): (f64, f64)| points.iter()
+        .map(|(x, y)| super::distance(lat, lon, *y, *x))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+        .map(|(i, _)| i);
+
+    let start = match closest(shape, from) {
+        Some(start) => start,
+        None => return vec![]
+    };
+    let end = start + closest(&shape[start..], to).unwrap_or(0);
+    shape[start..=end].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::Fixture;
+
+    fn feed(name: &str) -> Fixture {
+        Fixture::new(name, &[("frequencies.txt", "\u{feff}trip_id,start_time,end_time,headway_secs,exact_times\n\
+            metro,06:00:00,07:00:00,600,1\n\
+            metro,23:00:00,25:30:00,1200,\n"),
+            ("transfers.txt", "from_stop_id,to_stop_id,from_route_id,to_route_id,from_trip_id,to_trip_id,transfer_type,min_transfer_time\n\
+            a,b,,,,,2,180\n\
+            a,b,N,J,,,3,\n\
+            a,a,,,,,1,\n"),
+            ("pathways.txt", "pathway_id,from_stop_id,to_stop_id,pathway_mode,is_bidirectional,length,traversal_time,stair_count\n\
+            1,entrance,hall,1,1,24,,\n\
+            2,hall,platform,2,1,,,40\n\
+            3,hall,lift,1,1,,10,\n\
+            4,lift,platform,5,1,,,\n\
+            5,platform,exit,4,0,,15,\n")])
+    }
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(parse_time("25:30:00"), Some(91800));
+        assert_eq!(parse_time("7:05"), None);
+        assert_eq!(parse_time("4294967:00:00"), None);
+        assert_eq!(parse_time("0:71582789:00"), None);
+
+        let by_trip = Frequency::load(feed("freq").path()).unwrap();
+        let metro = &by_trip["metro"];
+        assert!(metro[0].is_exact() && !metro[1].is_exact());
+        assert_eq!(metro[0].starts(0).collect::<Vec<_>>().len(), 6);
+        assert_eq!(metro[0].starts(6 * 3600 + 1).next(), Some(6 * 3600 + 600));
+        assert_eq!(metro[0].starts(7 * 3600).next(), None);
+        assert_eq!(metro[1].starts(25 * 3600 + 1).next(), Some(25 * 3600 + 1200));
+    }
+
+    #[test]
+    fn transfers() {
+        let transfers = Transfers::load(feed("transfers").path()).unwrap();
+        assert_eq!(transfers.find("a", "b", ("", ""), ("", "")),
+                   Some(TransferKind::MinTime(180)));
+        assert_eq!(transfers.find("a", "b", ("N", "1"), ("J", "2")),
+                   Some(TransferKind::Forbidden));
+        assert_eq!(transfers.find("a", "b", ("N", "1"), ("K", "2")),
+                   Some(TransferKind::MinTime(180)));
+        assert_eq!(transfers.find("a", "a", ("N", "1"), ("K", "2")),
+                   Some(TransferKind::Timed));
+        assert_eq!(transfers.find("b", "a", ("", ""), ("", "")), None);
+        assert!(transfers.from("a").iter().all(|t| t.kind != TransferKind::Forbidden
+                                              || !t.between_stops()));
+    }
+
+    #[test]
+    fn pathways() {
+        let pathways = Pathways::load(feed("pathways").path()).unwrap();
+        let times = pathways.times_from("entrance", false);
+        assert_eq!(times["hall"], 20);
+        assert_eq!(times["platform"], 60);
+        assert_eq!(times["exit"], 75);
+
+        // Around the stairs, and no escalator
+        let times = pathways.times_from("entrance", true);
+        assert_eq!(times["platform"], 90);
+        assert!(!times.contains_key("exit"));
+
+        // One way
+        assert!(!pathways.times_from("exit", false).contains_key("platform"));
+    }
+
+    #[test]
+    fn shapes() {
+        // Out, then back the same street
+        let shape = [(0.0, 0.0), (0.001, 0.0), (0.002, 0.0), (0.003, 0.0),
+                     (0.002, 0.0001), (0.001, 0.0001), (0.0, 0.0001)];
+        assert_eq!(cut_shape(&shape, (0.0011, 0.0), (0.0029, 0.0)),
+                   vec![(0.001, 0.0), (0.002, 0.0), (0.003, 0.0)]);
+        assert_eq!(cut_shape(&shape, (0.003, 0.0), (0.001, 0.0)),
+                   vec![(0.003, 0.0), (0.002, 0.0001), (0.001, 0.0001)]);
+        assert!(cut_shape(&[], (0.0, 0.0), (0.0, 0.0)).is_empty());
+
+        let feed = Fixture::new("routes", &[("routes.txt", "route_id,route_short_name,route_long_name,route_type,route_color
+            N,N,Judah,0,005B95
+            F,,Market & Wharves,0,
+")]);
+        let routes = RouteStyle::load(feed.path()).unwrap();
+        assert_eq!(routes["N"].name(), Some("N"));
+        assert_eq!(routes["N"].color(), Some("#005B95".to_string()));
+        assert_eq!(routes["F"].name(), Some("Market & Wharves"));
+        assert_eq!(routes["F"].color(), None);
+    }
+}
@@ -1,18 +1,125 @@
+#[macro_use]
+extern crate log;
+extern crate chrono;
+extern crate chrono_tz;
 extern crate gtfs_structures;
+extern crate csv;
+extern crate prost;
 extern crate quadtree;
+extern crate ureq;
+extern crate zip;
 
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::error;
+use std::io;
 
 use std::thread;
 use std::fmt;
 use std::time;
 
+mod proto;
+mod extras;
+mod raptor;
+pub mod realtime;
+mod service;
+mod validate;
+
+pub use realtime::ServiceAlert;
+pub use extras::{Frequency, PathwayMode, RouteStyle, TransferKind, format_time,
+                 parse_time};
+pub use raptor::{Journey, Leg, Reached};
+pub use service::parse_date;
+pub use chrono::NaiveDate;
+pub use validate::{validate, Problem, Report};
+
+// Why a feed couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Gtfs(gtfs_structures::Error),
+    Io(io::Error),
+    Realtime(prost::DecodeError),
+    Http(String), // the status line, or what's wrong with the response
+    Csv(csv::Error),
+    Zip(zip::result::ZipError)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Gtfs(err) => write!(f, "{}", err),
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::Realtime(err) => write!(f, "bad realtime feed: {}", err),
+            LoadError::Http(status) => write!(f, "got {}", status),
+            LoadError::Csv(err) => write!(f, "{}", err),
+            LoadError::Zip(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoadError::Gtfs(err) => Some(err),
+            LoadError::Io(err) => Some(err),
+            LoadError::Realtime(err) => Some(err),
+            LoadError::Http(_) => None,
+            LoadError::Csv(err) => Some(err),
+            LoadError::Zip(err) => Some(err)
+        }
+    }
+}
+
+impl From<gtfs_structures::Error> for LoadError {
+    fn from(err: gtfs_structures::Error) -> Self {
+        LoadError::Gtfs(err)
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<prost::DecodeError> for LoadError {
+    fn from(err: prost::DecodeError) -> Self {
+        LoadError::Realtime(err)
+    }
+}
+
+impl From<csv::Error> for LoadError {
+    fn from(err: csv::Error) -> Self {
+        LoadError::Csv(err)
+    }
+}
+
+impl From<zip::result::ZipError> for LoadError {
+    fn from(err: zip::result::ZipError) -> Self {
+        LoadError::Zip(err)
+    }
+}
+
+// Meters, close enough within a station
+fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1) * 111_320.0;
+    let dlon = (lon2 - lon1) * 111_320.0 * lat1.to_radians().cos();
+    (dlat * dlat + dlon * dlon).sqrt()
+}
+
+// Seconds since the epoch, what realtime feeds talk
+fn now() -> u64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 #[derive(Clone, Copy)]
 pub struct TripCursor<'a> {
     current_seq: u16,  // Index in the trip stops below // Mut self iterator?
-    trip: &'a gtfs_structures::Trip
+    trip: &'a gtfs_structures::Trip,
+    offset: i64, // seconds, where a frequency trip runs its template times
+    realtime: &'a realtime::Feeds
 }
 
 impl<'a> TripCursor<'a> {
@@ -26,17 +133,85 @@ impl<'a> TripCursor<'a> {
             .find(|st| st.stop_sequence == self.current_seq + 1)
     }
 
+    // Realtime delay in seconds at a stop of the trip
+    fn delay(&self, stop_time: &gtfs_structures::StopTime) -> i64 {
+        self.realtime.trip(&self.trip.id, |changes| changes.delay(
+            self.trip.stop_times.iter()
+                .filter(|st| st.stop_sequence <= stop_time.stop_sequence)
+                .map(|st| (st.stop_sequence as u32, st.stop.id.as_str()))))
+            .unwrap_or(0) as i64
+    }
+
+    // Scheduled departure from the current stop, plus the delay
+    pub fn departure_time(&self) -> Option<u32> {
+        self.stop().and_then(|cur| cur.departure_time.map(|t|
+            (t as i64 + self.offset + self.delay(cur)).max(0) as u32))
+    }
+
+    fn leg(cursor: Option<&TripCursor<'a>>) -> extras::Leg<'a> {
+        cursor.map_or(("", ""), |cursor| (cursor.route_id(), cursor.trip_id()))
+    }
+
+    pub fn route_id(&self) -> &'a str {
+        &self.trip.route_id
+    }
+
+    pub fn trip_id(&self) -> &'a str {
+        &self.trip.id
+    }
+
+    pub fn headsign(&self) -> Option<&'a str> {
+        self.trip.trip_headsign.as_ref().map(|headsign| headsign.as_str())
+    }
+
+    // Departure from the current stop on paper
+    pub fn scheduled_time(&self) -> Option<u32> {
+        self.stop().and_then(|cur| cur.departure_time.map(|t|
+            (t as i64 + self.offset).max(0) as u32))
+    }
+
+    // Seconds late at the current stop, None without realtime news of
+    // the trip
+    pub fn reported_delay(&self) -> Option<i64> {
+        let cur = self.stop()?;
+        self.realtime.trip(&self.trip.id, |_| ())?;
+        Some(self.delay(cur))
+    }
+
     // How long until next stop
     fn next_time(&self) -> u32 {
         if let Some(cur) = self.stop() {
             if let Some(next) = self.next_stop() {
-                return next.arrival_time.unwrap_or(0) -
-                        cur.departure_time.unwrap_or(0);
+                let arrival = next.arrival_time.unwrap_or(0) as i64
+                    + self.delay(next);
+                let departure = cur.departure_time.unwrap_or(0) as i64
+                    + self.delay(cur);
+                return (arrival - departure).max(0) as u32;
             }
         }
 
         return 0;
     }
+
+    pub fn is_canceled(&self) -> bool {
+        self.realtime.trip(&self.trip.id, |changes| changes.canceled)
+            .unwrap_or(false)
+    }
+
+    // Whether the trip skips the current stop today
+    pub fn skips_stop(&self) -> bool {
+        match self.stop() {
+            Some(cur) => self.realtime.trip(&self.trip.id, |changes|
+                changes.skips(cur.stop_sequence as u32, &cur.stop.id))
+                .unwrap_or(false),
+            None => false
+        }
+    }
+
+    // None when the feed doesn't tell
+    pub fn wheelchair_accessible(&self) -> Option<bool> {
+        availability(&self.trip.wheelchair_accessible)
+    }
 }
 
 impl<'a> fmt::Display for TripCursor<'a> {
@@ -75,9 +250,24 @@ impl<'a> Iterator for TripCursor<'a> {
 struct IndexedGtfs {
     db: gtfs_structures::Gtfs, // The underlying GTFS db
     trips: HashMap<String, Vec<String>>, // HashMap from stop to all trip_ids from stop
+    frequencies: HashMap<String, Vec<Frequency>>, // by trip_id
+    transfers: extras::Transfers,
+    pathways: extras::Pathways,
+    routes: HashMap<String, extras::RouteStyle>, // by route_id
+    children: HashMap<String, Vec<String>>, // stop_ids by parent_station
+    timetable: raptor::Timetable,
+    realtime: Arc<realtime::Feeds> // shared by all feeds of the map
+}
+
+impl IndexedGtfs {
+    // The service date asked, or today for the feed
+    fn date(&self, date: Option<NaiveDate>) -> NaiveDate {
+        date.unwrap_or_else(|| service::today(&self.db))
+    }
 }
 
 // Each stop has a local ptr to its gtfs index for trip lookups
+#[derive(Clone)]
 pub struct Stop(Arc<IndexedGtfs>, Arc<gtfs_structures::Stop>);
 
 impl Stop {
@@ -88,6 +278,26 @@ impl Stop {
     pub fn get_lat(&self) -> f64 {
         self.1.latitude.unwrap_or(0.00)
     }
+
+    // Realtime alerts about the stop, or a trip through it, right now
+    pub fn alerts(&self, trip: Option<&TripCursor>) -> Vec<ServiceAlert> {
+        let (route, trip) = trip.map_or(("", ""), |cursor|
+            (cursor.trip.route_id.as_str(), cursor.trip.id.as_str()));
+        self.0.realtime.alerts(&self.1.id, route, trip, now())
+    }
+
+    // None when the feed doesn't tell
+    pub fn wheelchair_boarding(&self) -> Option<bool> {
+        availability(&self.1.wheelchair_boarding)
+    }
+}
+
+fn availability(value: &gtfs_structures::Availability) -> Option<bool> {
+    match value {
+        gtfs_structures::Availability::Available => Some(true),
+        gtfs_structures::Availability::NotAvailable => Some(false),
+        _ => None
+    }
 }
 
 impl fmt::Display for Stop {
@@ -96,6 +306,12 @@ impl fmt::Display for Stop {
     }
 }
 
+impl fmt::Debug for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.1.name, self.1.id)
+    }
+}
+
 // And we keep all potential Gtfs stops in a big quadtree so they're
 // indexed by position
 impl quadtree::Point for Stop {
@@ -110,50 +326,201 @@ impl quadtree::Point for Stop {
 
 pub struct TransitMap {
     index: quadtree::QuadTree<Stop>,
+    access: Vec<Stop>, // where riders get in, see Stop::is_access
+    by_id: HashMap<String, Stop>, // the first feed wins on clashes
+    realtime: Arc<realtime::Feeds>
+}
+
+
+// Reload a feed every so often, until the map following it is gone
+fn reload(feeds: Weak<realtime::Feeds>, from: realtime::Source, source: &str,
+          every: time::Duration) {
+    loop {
+        thread::sleep(every);
+        let feeds = match feeds.upgrade() {
+            Some(feeds) => feeds,
+            None => return
+        };
+        match from.fetch().and_then(|buf| realtime::Realtime::decode(&buf)) {
+            Ok(realtime) => {
+                debug!("Reloaded {}, feed from {}", source, realtime.timestamp);
+                feeds.update(source, realtime)
+            },
+            Err(err) => warn!("Can't reload {}: {}", source, err)
+        }
+    }
 }
 
 impl TransitMap {
     pub fn new() -> Self {
         Self {
             index: quadtree::QuadTree::new(-180.0, -90.0, 360.0, 180.0),
+            access: Vec::new(),
+            by_id: HashMap::new(),
+            realtime: Arc::new(realtime::Feeds::default())
         }
     }
 
-    pub fn load(&mut self, file: &str) -> Result<usize, &str> {
-        if let Ok(gtfs) = gtfs_structures::Gtfs::new(file) {
-            let mut trips = HashMap::new();
+    pub fn load(&mut self, file: &str) -> Result<usize, LoadError> {
+        let mut gtfs = gtfs_structures::Gtfs::new(file)?;
+        let mut trips = HashMap::new();
 
-            for (_, trip) in gtfs.trips.iter() {
-                for stop_time in trip.stop_times.iter() {
-                    let stop_trips = trips.entry(stop_time.stop.id.to_string()).or_insert(Vec::new());
-                    if !stop_trips.contains(&trip.id) {
-                        stop_trips.push(trip.id.to_string());
-                    }
+        // Rides are cut in them in order
+        for points in gtfs.shapes.values_mut() {
+            points.sort_by_key(|point| point.sequence);
+        }
+
+        for (_, trip) in gtfs.trips.iter() {
+            for stop_time in trip.stop_times.iter() {
+                let stop_trips = trips.entry(stop_time.stop.id.to_string()).or_insert(Vec::new());
+                if !stop_trips.contains(&trip.id) {
+                    stop_trips.push(trip.id.to_string());
                 }
             }
+        }
 
-            let gtfs = Arc::new(IndexedGtfs{
-                db: gtfs,
-                trips: trips,
-            });
-
-            for (_, stop) in gtfs.db.stops.iter() {
-                self.index.insert(Stop(Arc::clone(&gtfs),
-                    Arc::clone(stop)))
+        let mut children = HashMap::new();
+        for stop in gtfs.stops.values() {
+            if let Some(parent) = &stop.parent_station {
+                children.entry(parent.to_string()).or_insert(vec![])
+                    .push(stop.id.to_string());
             }
+        }
 
-            Ok(gtfs.db.stops.len())
-        } else {
-            Err("Can't load it")
+        let report = validate::check(&gtfs, file, None)?;
+        if !report.is_ok() {
+            warn!("{} has problems: {:?}", file, report.summary);
+        }
+
+        let frequencies = Frequency::load(file)?;
+        let transfers = extras::Transfers::load(file)?;
+        let timetable = raptor::Timetable::new(&gtfs, &frequencies, &transfers);
+        let gtfs = Arc::new(IndexedGtfs{
+            db: gtfs,
+            trips: trips,
+            frequencies: frequencies,
+            transfers: transfers,
+            pathways: extras::Pathways::load(file)?,
+            routes: extras::RouteStyle::load(file)?,
+            children: children,
+            timetable: timetable,
+            realtime: Arc::clone(&self.realtime)
+        });
+
+        for (_, stop) in gtfs.db.stops.iter() {
+            // Not at 0,0 in the Gulf of Guinea
+            if stop.latitude.is_none() || stop.longitude.is_none() {
+                continue;
+            }
+            let stop = Stop(Arc::clone(&gtfs), Arc::clone(stop));
+            if stop.is_access() {
+                self.access.push(stop.clone());
+            }
+            if self.by_id.contains_key(stop.id()) {
+                warn!("Stop {} of {} is already in another feed", stop.id(),
+                      file);
+            } else {
+                self.by_id.insert(stop.id().to_string(), stop.clone());
+            }
+            self.index.insert(stop)
         }
+
+        Ok(gtfs.db.stops.len())
+    }
+
+    // Apply a GTFS-Realtime feed (file or http(s)://) to the trips, and
+    // reload it every so often. Reloads go on when the first load fails,
+    // and keep the previous version when they fail.
+    pub fn follow(&self, source: &str, every: time::Duration)
+        -> Result<(), LoadError> {
+        let from = realtime::Source::parse(source);
+        let first = from.fetch().and_then(|buf| realtime::Realtime::decode(&buf))
+            .map(|realtime| self.realtime.update(source, realtime));
+
+        let feeds = Arc::downgrade(&self.realtime);
+        let source = source.to_string();
+        thread::spawn(move || reload(feeds, from, &source, every));
+        first
     }
 
+    // Closest stops first
     pub fn get_stops(&self, lat: f64, lon: f64)
         -> impl Iterator<Item=&Stop> {
-        self.index.get(lon, lat)
+        self.index.around(lon, lat).map(|(stop, _)| stop)
+    }
+
+    pub fn stops_within(&self, lat: f64, lon: f64, meters: f64)
+        -> impl Iterator<Item=&Stop> {
+        self.index.within_radius(lon, lat, meters).map(|(stop, _)| stop)
+    }
+
+    pub fn stop(&self, id: &str) -> Option<&Stop> {
+        self.by_id.get(id)
+    }
+
+    pub fn access_points(&self) -> impl Iterator<Item=&Stop> {
+        self.access.iter()
+    }
+
+    // Pareto-optimal journeys (earliest arrival for each number of rides)
+    // from the platforms we can get to, with the seconds it takes, to
+    // those we can walk away from. depart_at is in seconds since midnight
+    // of date, today where the feed is without one. Feeds don't share
+    // trips, each is planned on its own.
+    pub fn journeys(&self, access: &[(Stop, u32)], egress: &[(Stop, u32)],
+                    date: Option<NaiveDate>, depart_at: u32, max_rides: usize,
+                    accessible: bool) -> Vec<Journey> {
+        let mut journeys = feeds_of(access).into_iter().flat_map(|feed|
+            raptor::plan(feed, &self.realtime, feed.date(date),
+                         &in_feed(feed, access), &in_feed(feed, egress),
+                         depart_at, max_rides, accessible))
+            .collect::<Vec<_>>();
+
+        // Across feeds, only keep those arriving earlier with more rides
+        journeys.sort_by_key(|journey| (journey.rides, journey.arrival));
+        let mut best = u32::max_value();
+        journeys.retain(|journey| {
+            let keep = journey.arrival < best;
+            best = best.min(journey.arrival);
+            keep
+        });
+        journeys
+    }
+
+    // Stops we can ride to from the access platforms, arriving within
+    // budget seconds of depart_at on date (as for journeys)
+    pub fn reach(&self, access: &[(Stop, u32)], date: Option<NaiveDate>,
+                 depart_at: u32, budget: u32, max_rides: usize,
+                 accessible: bool) -> Vec<Reached> {
+        feeds_of(access).into_iter().flat_map(|feed|
+            raptor::reach(feed, &self.realtime, feed.date(date),
+                          &in_feed(feed, access), depart_at,
+                          depart_at.saturating_add(budget), max_rides,
+                          accessible))
+            .collect()
     }
 }
 
+// The feeds of some stops, once each
+fn feeds_of(stops: &[(Stop, u32)]) -> Vec<&Arc<IndexedGtfs>> {
+    let mut feeds: Vec<&Arc<IndexedGtfs>> = vec![];
+    for (stop, _) in stops {
+        if !feeds.iter().any(|feed| Arc::ptr_eq(feed, &stop.0)) {
+            feeds.push(&stop.0);
+        }
+    }
+    feeds
+}
+
+// Those of the stops in feed, by their index in its timetable
+fn in_feed(feed: &Arc<IndexedGtfs>, stops: &[(Stop, u32)]) -> Vec<(u32, u32)> {
+    stops.iter()
+        .filter(|(stop, _)| Arc::ptr_eq(feed, &stop.0))
+        .filter_map(|(stop, time)| Some((feed.timetable.stop_index(stop.id())?,
+                                         *time)))
+        .collect()
+}
+
 impl Stop {
     // 0 db
     // 1 stop
@@ -161,30 +528,248 @@ impl Stop {
         self.1.as_ref()
     }
 
-    // Next trip cursors for a given stop
+    // A cursor on trip at this stop
+    fn cursor<'a>(&'a self, trip: &'a gtfs_structures::Trip, offset: i64)
+        -> Option<TripCursor<'a>> {
+        trip.stop_times.iter()
+            .find(|stop_time| stop_time.stop.id == self.1.id)
+            .map(|stop_time| TripCursor {
+                current_seq: stop_time.stop_sequence,
+                trip: trip,
+                offset: offset,
+                realtime: &self.0.realtime
+            })
+    }
+
+    // What's actually running
+    fn runs(&self, trip: &TripCursor) -> bool {
+        !trip.is_canceled() && !trip.skips_stop()
+            && !self.alerts(Some(trip)).iter().any(|alert| alert.no_service)
+    }
+
+    fn trips(&self) -> impl Iterator<Item=&gtfs_structures::Trip> {
+        self.0.trips.get(&self.1.id).into_iter().flatten()
+            .filter_map(move |trip_id| self.0.db.trips.get(trip_id))
+    }
+
+    // Next trip cursors for a given stop. Frequency trips are given once,
+    // at their template times.
     pub fn next_trips(&self) -> Vec<TripCursor> {
-        self.0.trips.get(&self.1.id).unwrap_or(&vec![])
-            .iter()
-            .filter_map(|trip_id| self.0.db.trips.get(trip_id))
-            .map(|trip| {
-                // Instanciate the trip - advance it to current stop
-                let mut trip_curs = TripCursor {
-                    current_seq: 1,
-                    trip: trip
+        self.trips()
+            .filter_map(|trip| self.cursor(trip, 0))
+            .filter(|trip| self.runs(trip))
+            .collect()
+    }
+
+    // Trips leaving here from time on (seconds since midnight of date,
+    // today for the feed without one), soonest first: those running that
+    // day, and those of the day before still on the way. Frequency trips
+    // give their next run of each period.
+    pub fn departures(&self, date: Option<NaiveDate>, time: u32)
+        -> Vec<TripCursor> {
+        let mut departures = vec![];
+        for (day, shift) in service::days(self.0.date(date)) {
+            let shift = shift as i64;
+            for trip in self.trips()
+                .filter(|trip| service::runs_on(&self.0.db, &trip.service_id,
+                                                day)) {
+                let frequencies = match self.0.frequencies.get(&trip.id) {
+                    Some(frequencies) => frequencies,
+                    None => {
+                        departures.extend(self.cursor(trip, -shift));
+                        continue;
+                    }
+                };
+
+                // Template times are relative to the first departure
+                let first = trip.stop_times.first()
+                    .and_then(|st| st.departure_time).unwrap_or(0) as i64;
+                let at_stop = match self.cursor(trip, 0)
+                    .and_then(|cursor| cursor.stop())
+                    .and_then(|stop_time| stop_time.departure_time) {
+                    Some(t) => t as i64 - first,
+                    None => continue
                 };
-                // println!("Trip {}", trip_curs);
-                // This advances the cursor to next stop
-                // Ignore the result
-                let _ = trip_curs.find(|stop_time|
-                   stop_time.stop.id == self.1.id);
-                trip_curs
-            }).collect()
+                for frequency in frequencies {
+                    let after = (time as i64 + shift - at_stop).max(0) as u32;
+                    if let Some(start) = frequency.starts(after).next() {
+                        departures.extend(
+                            self.cursor(trip, start as i64 - first - shift));
+                    }
+                }
+            }
+        }
+
+        let mut departures = departures.into_iter()
+            // Runs of the day before that left by midnight are gone
+            .filter(|trip| trip.stop().and_then(|st| st.departure_time)
+                    .map_or(false, |t| t as i64 + trip.offset >= 0))
+            .filter(|trip| self.runs(trip))
+            .filter_map(|trip| Some((trip.departure_time()?, trip)))
+            .filter(|(t, _)| *t >= time)
+            .collect::<Vec<_>>();
+        departures.sort_by_key(|(t, _)| *t);
+        departures.into_iter().map(|(_, trip)| trip).collect()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.1.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.1.name
+    }
+
+    // Meters, as the crow flies
+    pub fn distance_from(&self, lat: f64, lon: f64) -> u64 {
+        distance(lat, lon, self.get_lat(), self.get_lon()) as u64
+    }
+
+    // How the feed of the stop shows a route
+    pub fn route(&self, route_id: &str) -> Option<&RouteStyle> {
+        self.0.routes.get(route_id)
+    }
+
+    fn sibling(&self, id: &str) -> Option<Stop> {
+        self.0.db.stops.get(id)
+            .map(|stop| Stop(Arc::clone(&self.0), Arc::clone(stop)))
+    }
+
+    pub fn is_entrance(&self) -> bool {
+        self.1.location_type == gtfs_structures::LocationType::StationEntrance
+    }
+
+    pub fn is_platform(&self) -> bool {
+        self.1.location_type == gtfs_structures::LocationType::StopPoint
+    }
+
+    pub fn station(&self) -> Option<Stop> {
+        self.1.parent_station.as_ref().and_then(|id| self.sibling(id))
+    }
+
+    // The stops of a station (or of a platform, its boarding areas)
+    fn children(&self) -> Vec<Stop> {
+        self.0.children.get(&self.1.id).map_or(vec![], |ids| ids.iter()
+            .filter_map(|id| self.sibling(id))
+            .collect())
+    }
+
+    // Where riders get in: station entrances, and stops out of any
+    // station. Stations without entrances are their own.
+    pub fn is_access(&self) -> bool {
+        use gtfs_structures::LocationType::*;
+        match self.1.location_type {
+            StationEntrance => true,
+            StopPoint => self.1.parent_station.is_none(),
+            StopArea => !self.children().iter().any(|stop| stop.is_entrance()),
+            _ => false
+        }
+    }
+
+    // Platforms we can get to from here, with the seconds it takes. Through
+    // pathways when the feed has them, otherwise we walk straight to the
+    // platforms of the station. Accessible leaves stairs and escalators out.
+    pub fn platforms(&self, accessible: bool) -> Vec<(Stop, u32)> {
+        if self.is_platform() {
+            return vec![(self.clone(), 0)];
+        }
+
+        if !self.0.pathways.is_empty() {
+            let mut platforms = HashMap::new();
+            for (id, time) in self.0.pathways.times_from(&self.1.id, accessible) {
+                // Boarding areas are part of their platform
+                let stop = match self.sibling(&id) {
+                    Some(stop) if stop.is_platform() => stop,
+                    Some(stop) => match stop.station() {
+                        Some(platform) if platform.is_platform() => platform,
+                        _ => continue
+                    },
+                    None => continue
+                };
+                let best = platforms.entry(stop.1.id.to_string())
+                    .or_insert((stop, time));
+                best.1 = best.1.min(time);
+            }
+            if !platforms.is_empty() {
+                return platforms.into_iter().map(|(_, platform)| platform)
+                    .collect();
+            }
+        }
+
+        let station = match self.1.location_type {
+            gtfs_structures::LocationType::StopArea => Some(self.clone()),
+            _ => self.station()
+        };
+        station.map_or(vec![], |station| station.children().into_iter()
+            .filter(|stop| stop.is_platform())
+            .map(|platform| {
+                let time = distance(self.get_lat(), self.get_lon(),
+                                    platform.get_lat(), platform.get_lon()) / 1.2;
+                (platform, time as u32)
+            })
+            .collect())
+    }
+
+    // Declared transfers from here to stops of the same feed, whatever
+    // the trips. Walking elsewhere is up to the router.
+    pub fn transfers(&self) -> Vec<(Stop, TransferKind)> {
+        self.0.transfers.from(&self.1.id).iter()
+            .filter(|transfer| transfer.between_stops())
+            .filter_map(|transfer| self.0.db.stops.get(&transfer.to_stop)
+                .map(|stop| (Stop(Arc::clone(&self.0), Arc::clone(stop)),
+                             transfer.kind)))
+            .collect()
+    }
+
+    // Seconds it takes to change from trip here to to_trip at to, when
+    // feeds say. None if forbidden.
+    pub fn transfer_time(&self, trip: Option<&TripCursor>, to: &Stop,
+                         to_trip: Option<&TripCursor>) -> Option<u32> {
+        match self.0.transfers.find(&self.1.id, &to.1.id,
+                                     TripCursor::leg(trip), TripCursor::leg(to_trip)) {
+            Some(TransferKind::Forbidden) => None,
+            Some(TransferKind::MinTime(time)) => Some(time),
+            _ => Some(0)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use chrono::NaiveDate;
+
+    // A feed written for a test in a directory of its own, removed when
+    // dropped
+    pub struct Fixture(PathBuf);
+
+    impl Fixture {
+        pub fn new(test: &str, files: &[(&str, &str)]) -> Self {
+            let dir = env::temp_dir().join(format!("gtfs-{}-{}", test,
+                                                   std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            for (name, content) in files {
+                fs::write(dir.join(name), content).unwrap();
+            }
+            Fixture(dir)
+        }
+
+        pub fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
 
     fn simple_load_file() -> super::TransitMap {
         let mut now = Instant::now();
@@ -207,6 +792,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn departures() {
+        let feed = Fixture::new("departures", &[
+            ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+             x,X,http://x,America/Los_Angeles\n"),
+            ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             all,1,1,1,1,1,1,1,20200101,20301231\n\
+             weekend,0,0,0,0,0,1,1,20200101,20301231\n"),
+            ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon\n\
+             s1,One,37.70,-122.40\ns2,Two,37.71,-122.40\n"),
+            ("routes.txt", "route_id,route_short_name,route_long_name,route_type,route_color\n\
+             A,A,Alpha,3,FF0000\n"),
+            ("trips.txt", "route_id,service_id,trip_id,trip_headsign\n\
+             A,all,a1,Downtown\nA,all,a2,Downtown\nA,all,a3,Depot\n\
+             A,weekend,w1,Beach\nA,all,owl,Depot\n"),
+            ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             a1,08:00:00,08:00:00,s1,1\na1,08:05:00,08:05:00,s2,2\n\
+             a3,09:00:00,09:00:00,s1,1\na3,09:05:00,09:05:00,s2,2\n\
+             a2,08:30:00,08:30:00,s1,1\na2,08:35:00,08:35:00,s2,2\n\
+             w1,08:45:00,08:45:00,s1,1\nw1,08:50:00,08:50:00,s2,2\n\
+             owl,24:30:00,24:30:00,s1,1\nowl,24:35:00,24:35:00,s2,2\n")
+        ]);
+        let mut tm = super::TransitMap::new();
+        tm.load(feed.path()).unwrap();
+
+        assert!(tm.stop("s3").is_none());
+        let stop = tm.stop("s1").unwrap();
+        assert_eq!(stop.name(), "One");
+        assert_eq!(stop.distance_from(37.71, -122.40), 1113);
+        assert_eq!(stop.route("A").and_then(|route| route.color()),
+                   Some("#FF0000".to_string()));
+
+        // A wednesday: no w1, and tuesday's owl left last night
+        let wednesday = NaiveDate::from_ymd_opt(2020, 6, 3);
+        let board = stop.departures(wednesday, 8 * 3600 + 1).iter()
+            .map(|trip| (trip.trip_id(), trip.headsign(), trip.scheduled_time(),
+                         trip.reported_delay()))
+            .collect::<Vec<_>>();
+        assert_eq!(board, vec![
+            ("a2", Some("Downtown"), Some(8 * 3600 + 1800), None),
+            ("a3", Some("Depot"), Some(9 * 3600), None),
+            ("owl", Some("Depot"), Some(24 * 3600 + 1800), None)
+        ]);
+
+        let saturday = NaiveDate::from_ymd_opt(2020, 6, 6);
+        let board = stop.departures(saturday, 8 * 3600 + 1).iter()
+            .map(|trip| trip.trip_id())
+            .collect::<Vec<_>>();
+        assert_eq!(board, vec!["a2", "w1", "a3", "owl"]);
+
+        // After midnight, friday's owl is still to come
+        let board = stop.departures(saturday, 0).iter()
+            .map(|trip| (trip.trip_id(), trip.scheduled_time()))
+            .collect::<Vec<_>>();
+        assert_eq!(board[0], ("owl", Some(1800)));
+    }
+
+    #[test]
+    fn stations() {
+        // Ent gets into Station and its two platforms, Hall has no
+        // entrance, Lone is in no station
+        let feed = Fixture::new("stations", &[
+            ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+             x,X,http://x,America/Los_Angeles\n"),
+            ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             all,1,1,1,1,1,1,1,20200101,20301231\n"),
+            ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station\n\
+             st,Station,37.70,-122.40,1,\nent,Ent,37.70,-122.401,2,st\n\
+             p1,One,37.70,-122.40,0,st\np2,Two,37.7001,-122.40,0,st\n\
+             hall,Hall,37.71,-122.40,1,\np3,Three,37.71,-122.40,0,hall\n\
+             lone,Lone,37.72,-122.40,0,\n"),
+            ("routes.txt", "route_id,route_short_name,route_long_name,route_type\n\
+             A,A,Alpha,3\n"),
+            ("trips.txt", "route_id,service_id,trip_id\nA,all,a1\n"),
+            ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             a1,08:00:00,08:00:00,p1,1\na1,08:05:00,08:05:00,p3,2\n\
+             a1,08:10:00,08:10:00,lone,3\n")
+        ]);
+        let mut tm = super::TransitMap::new();
+        tm.load(feed.path()).unwrap();
+
+        let mut access = tm.access_points().map(|stop| stop.id())
+            .collect::<Vec<_>>();
+        access.sort();
+        assert_eq!(access, vec!["ent", "hall", "lone"]);
+
+        let mut platforms = tm.stop("ent").unwrap().platforms(false).into_iter()
+            .map(|(stop, _)| stop.id().to_string())
+            .collect::<Vec<_>>();
+        platforms.sort();
+        assert_eq!(platforms, vec!["p1", "p2"]);
+        let platforms = tm.stop("hall").unwrap().platforms(false);
+        assert_eq!(platforms.iter().map(|(stop, time)| (stop.id(), *time))
+                   .collect::<Vec<_>>(), vec![("p3", 0)]);
+        assert_eq!(tm.stop("lone").unwrap().platforms(false).len(), 1);
+    }
+
+    #[test]
+    fn reload_stops() {
+        let feeds = Arc::new(super::realtime::Feeds::default());
+        let weak = Arc::downgrade(&feeds);
+        let reload = thread::spawn(move || super::reload(
+            weak, super::realtime::Source::parse("missing.pb"), "missing.pb",
+            Duration::from_millis(10)));
+
+        // Failed reloads go on while the map is there, then give up
+        thread::sleep(Duration::from_millis(50));
+        assert!(!reload.is_finished());
+        drop(feeds);
+        reload.join().unwrap();
+    }
+
     fn test_stops() {
         let mut now = Instant::now();
         let tm = simple_load_file();
@@ -0,0 +1,867 @@
+// RAPTOR (Delling, Pajor, Werneck - Round-Based Public Transit Routing):
+// round k finds the earliest arrivals at every stop with k rides, by
+// scanning each route pattern once from the stops improved in round k-1,
+// then walking the transfers. Journeys of each round that arrive earlier
+// than all those with fewer rides are the Pareto set.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+
+use super::extras::{self, Frequency, TransferKind, Transfers};
+use super::realtime::{Feeds, Realtime};
+use super::service;
+use super::{distance, IndexedGtfs, Stop};
+
+const NEVER: u32 = u32::MAX;
+
+// Realtime delays can make a later trip leave earlier, look that much
+// around scheduled times
+const SLACK: u32 = 3600;
+
+// Trips running the same stops on the same route, sorted by departure
+#[derive(Debug)]
+struct Pattern {
+    route_id: String,
+    stops: Vec<u32>,
+    trips: Vec<TripTimes>
+}
+
+#[derive(Debug)]
+struct TripTimes {
+    trip_id: String,
+    service: u32, // index in Timetable.services
+    sequences: Vec<u16>,
+    arrivals: Vec<u32>,
+    departures: Vec<u32>,
+    accessible: bool // false only when the feed says so
+}
+
+#[derive(Debug)]
+pub struct Timetable {
+    services: Vec<String>,
+    stops: Vec<String>,
+    stop_idx: HashMap<String, u32>,
+    accessible: Vec<bool>, // boarding in a wheelchair, by stop
+    patterns: Vec<Pattern>,
+    stop_patterns: Vec<Vec<(u32, u32)>>, // (pattern, position) by stop
+    transfers: Vec<Vec<(u32, u32)>>,     // (stop, seconds) by stop
+    min_change: Vec<u32>                 // seconds to change at a stop
+}
+
+// How we got to a stop in a round
+#[derive(Debug, Clone, Copy)]
+enum Label {
+    None,
+    Access(u32), // seconds walked from the start
+    // positions, and the service day of the trip (see ServiceDay)
+    Ride { pattern: u32, trip: u32, day: u32, from: u32, to: u32 },
+    Transfer { from: u32, time: u32 }
+}
+
+// Trips we can take on a date: those of the services running that day,
+// and those of the day before still running past midnight. Trip times
+// are shifted to our day.
+struct ServiceDay {
+    days: Vec<(u32, Vec<bool>)> // seconds ahead, running by service
+}
+
+impl ServiceDay {
+    fn runs(&self, day: usize, trip: &TripTimes) -> bool {
+        self.days[day].1[trip.service as usize]
+    }
+
+    fn shift(&self, day: u32) -> u32 {
+        self.days[day as usize].0
+    }
+}
+
+// Realtime delays as they were when a plan started, worked out once
+// along the whole pattern for each trip we look at: (pattern, trip) to
+// the delay by position, None where it doesn't stop
+struct Delays {
+    feeds: Vec<Arc<Realtime>>,
+    trips: RefCell<HashMap<(u32, u32), Vec<Option<i64>>>>
+}
+
+impl Delays {
+    fn new(feeds: &Feeds) -> Self {
+        Delays {
+            feeds: feeds.snapshot(),
+            trips: RefCell::new(HashMap::new())
+        }
+    }
+}
+
+// A trip time on our day, None for runs of the day before that were
+// over by midnight
+fn shifted(time: u32, delay: i64, shift: u32) -> Option<u32> {
+    match time as i64 + delay - shift as i64 {
+        t if t < 0 && shift > 0 => None,
+        t => Some(t.max(0) as u32)
+    }
+}
+
+// Earliest arrivals and how we got there, by round then stop, and the
+// egress stops each round improved: (rides, stop, arrival there)
+struct Rounds {
+    arrivals: Vec<Vec<u32>>,
+    labels: Vec<Vec<Label>>,
+    found: Vec<(usize, u32, u32)>
+}
+
+#[derive(Debug, Clone)]
+pub enum Leg {
+    // from the start when from is None, to the end when to is None
+    Walk { from: Option<Stop>, to: Option<Stop>, departure: u32, arrival: u32 },
+    Ride {
+        route_id: String,
+        trip_id: String,
+        route_name: Option<String>,
+        headsign: Option<String>,
+        color: Option<String>, // #RRGGBB
+        text_color: Option<String>,
+        stops: Vec<Stop>, // where we board to where we leave
+        shape: Vec<(f64, f64)>, // lon/lat, straight between stops if none
+        departure: u32,
+        arrival: u32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Journey {
+    pub departure: u32, // seconds since midnight
+    pub arrival: u32,
+    pub rides: usize,
+    pub legs: Vec<Leg>
+}
+
+// A stop we can ride to
+#[derive(Debug, Clone)]
+pub struct Reached {
+    pub stop: Stop,
+    pub arrival: u32, // seconds since midnight
+    pub rides: usize
+}
+
+// Stop times without a time (not timepoints) take the previous one
+fn times(trip: &gtfs_structures::Trip) -> (Vec<u32>, Vec<u32>) {
+    let mut last = 0;
+    trip.stop_times.iter().map(|st| {
+        let arrival = st.arrival_time.or(st.departure_time).unwrap_or(last);
+        let departure = st.departure_time.unwrap_or(arrival);
+        last = departure;
+        (arrival, departure)
+    }).unzip()
+}
+
+impl Timetable {
+    // Frequency trips are run for all their periods here
+    pub fn new(gtfs: &gtfs_structures::Gtfs,
+               frequencies: &HashMap<String, Vec<Frequency>>,
+               transfers: &Transfers) -> Self {
+        let mut stops = gtfs.stops.keys().cloned().collect::<Vec<_>>();
+        stops.sort();
+        let stop_idx = stops.iter().enumerate()
+            .map(|(i, id)| (id.to_string(), i as u32))
+            .collect::<HashMap<_, _>>();
+
+        let mut services = gtfs.trips.values()
+            .map(|trip| trip.service_id.to_string())
+            .collect::<Vec<_>>();
+        services.sort();
+        services.dedup();
+
+        let mut by_key = HashMap::new();
+        let mut patterns = vec![];
+        for trip in gtfs.trips.values() {
+            let trip_stops = trip.stop_times.iter()
+                .filter_map(|st| stop_idx.get(&st.stop.id).cloned())
+                .collect::<Vec<u32>>();
+            if trip_stops.len() < 2 || trip_stops.len() != trip.stop_times.len() {
+                continue;
+            }
+
+            let idx = *by_key.entry((trip.route_id.to_string(), trip_stops.clone()))
+                .or_insert_with(|| {
+                    patterns.push(Pattern {
+                        route_id: trip.route_id.to_string(),
+                        stops: trip_stops,
+                        trips: vec![]
+                    });
+                    patterns.len() - 1
+                });
+
+            let (arrivals, departures) = times(trip);
+            let sequences = trip.stop_times.iter()
+                .map(|st| st.stop_sequence).collect::<Vec<_>>();
+            let accessible = trip.wheelchair_accessible
+                != gtfs_structures::Availability::NotAvailable;
+            let service = services.binary_search(&trip.service_id)
+                .unwrap_or(0) as u32;
+            let runs = match frequencies.get(&trip.id) {
+                Some(frequencies) => frequencies.iter()
+                    .flat_map(|frequency| frequency.starts(0))
+                    .map(|start| start as i64 - departures[0] as i64)
+                    .collect(),
+                None => vec![0]
+            };
+            for offset in runs {
+                let shift = |times: &Vec<u32>| times.iter()
+                    .map(|t| (*t as i64 + offset).max(0) as u32)
+                    .collect::<Vec<u32>>();
+                patterns[idx].trips.push(TripTimes {
+                    trip_id: trip.id.to_string(),
+                    service: service,
+                    sequences: sequences.clone(),
+                    arrivals: shift(&arrivals),
+                    departures: shift(&departures),
+                    accessible: accessible
+                });
+            }
+        }
+
+        let mut stop_patterns = vec![vec![]; stops.len()];
+        for (p, pattern) in patterns.iter_mut().enumerate() {
+            pattern.trips.sort_by_key(|trip| trip.departures[0]);
+            for (pos, stop) in pattern.stops.iter().enumerate() {
+                stop_patterns[*stop as usize].push((p as u32, pos as u32));
+            }
+        }
+
+        // Declared transfers, and walks between the platforms of a
+        // station unless forbidden
+        let mut links = vec![HashMap::new(); stops.len()];
+        let mut min_change = vec![0; stops.len()];
+        let mut stations = HashMap::new();
+        for stop in gtfs.stops.values() {
+            if stop.location_type != gtfs_structures::LocationType::StopPoint {
+                continue;
+            }
+            if let Some(station) = &stop.parent_station {
+                stations.entry(station.to_string())
+                    .or_insert(vec![])
+                    .push(Arc::clone(stop));
+            }
+        }
+        let walk = |a: &gtfs_structures::Stop, b: &gtfs_structures::Stop|
+            (distance(a.latitude.unwrap_or(0.0), a.longitude.unwrap_or(0.0),
+                      b.latitude.unwrap_or(0.0), b.longitude.unwrap_or(0.0))
+             / 1.2) as u32;
+        for platforms in stations.values() {
+            for a in platforms {
+                for b in platforms.iter().filter(|b| b.id != a.id) {
+                    links[stop_idx[&a.id] as usize]
+                        .insert(stop_idx[&b.id], walk(a, b));
+                }
+            }
+        }
+        for (id, i) in stop_idx.iter() {
+            for transfer in transfers.from(id).iter()
+                .filter(|transfer| transfer.between_stops()) {
+                let to = match stop_idx.get(&transfer.to_stop) {
+                    Some(to) => *to,
+                    None => continue
+                };
+                let time = match transfer.kind {
+                    TransferKind::MinTime(time) => time,
+                    TransferKind::Forbidden => {
+                        links[*i as usize].remove(&to);
+                        continue;
+                    },
+                    _ => walk(&gtfs.stops[id], &gtfs.stops[&transfer.to_stop])
+                };
+                if to == *i {
+                    min_change[*i as usize] = time;
+                } else {
+                    links[*i as usize].insert(to, time);
+                }
+            }
+        }
+
+        Timetable {
+            services: services,
+            accessible: stops.iter()
+                .map(|id| gtfs.stops[id].wheelchair_boarding
+                     != gtfs_structures::Availability::NotAvailable)
+                .collect(),
+            stops: stops,
+            stop_idx: stop_idx,
+            patterns: patterns,
+            stop_patterns: stop_patterns,
+            transfers: links.into_iter()
+                .map(|links| links.into_iter().collect())
+                .collect(),
+            min_change: min_change
+        }
+    }
+
+    pub fn stop_index(&self, stop_id: &str) -> Option<u32> {
+        self.stop_idx.get(stop_id).cloned()
+    }
+
+    fn service_day(&self, gtfs: &gtfs_structures::Gtfs, date: NaiveDate)
+        -> ServiceDay {
+        ServiceDay {
+            days: service::days(date).into_iter()
+                .map(|(day, shift)| (shift, self.services.iter()
+                     .map(|id| service::runs_on(gtfs, id, day))
+                     .collect()))
+                .collect()
+        }
+    }
+
+    // Realtime delay of a trip at a position of its pattern, None if it
+    // doesn't stop there today
+    fn delay(&self, delays: &Delays, p: u32, t: u32, pos: usize) -> Option<i64> {
+        if let Some(trip) = delays.trips.borrow().get(&(p, t)) {
+            return trip[pos];
+        }
+        let pattern = &self.patterns[p as usize];
+        let trip = &pattern.trips[t as usize];
+        let changes = match delays.feeds.iter()
+            .find_map(|feed| feed.trip(&trip.trip_id)) {
+            Some(changes) => changes,
+            None => return Some(0)
+        };
+        let along = if changes.canceled {
+            vec![None; pattern.stops.len()]
+        } else {
+            changes.delays(trip.sequences.iter().zip(pattern.stops.iter())
+                .map(|(seq, stop)| (*seq as u32, self.stops[*stop as usize].as_str())))
+                .into_iter()
+                .map(|delay| delay.map(|d| d as i64))
+                .collect()
+        };
+        let delay = along[pos];
+        delays.trips.borrow_mut().insert((p, t), along);
+        delay
+    }
+
+    // Times on our day of a trip run shift seconds ahead
+    fn arrival(&self, delays: &Delays, p: u32, t: u32, shift: u32,
+               pos: usize) -> Option<u32> {
+        let delay = self.delay(delays, p, t, pos)?;
+        shifted(self.patterns[p as usize].trips[t as usize].arrivals[pos],
+                delay, shift)
+    }
+
+    fn departure(&self, delays: &Delays, p: u32, t: u32, shift: u32,
+                 pos: usize) -> Option<u32> {
+        let delay = self.delay(delays, p, t, pos)?;
+        shifted(self.patterns[p as usize].trips[t as usize].departures[pos],
+                delay, shift)
+    }
+
+    // The first trip of pattern running on service leaving pos at ready
+    // or after: (trip, day, departure)
+    fn earliest_trip(&self, delays: &Delays, service: &ServiceDay, p: u32,
+                     pos: usize, ready: u32, accessible: bool)
+        -> Option<(u32, u32, u32)> {
+        let pattern = &self.patterns[p as usize];
+        let mut best: Option<(u32, u32, u32)> = None;
+        for (day, (shift, _)) in service.days.iter().enumerate() {
+            // Pattern trips are sorted by their own times
+            let earliest = (ready + shift).saturating_sub(SLACK);
+            let first = match pattern.trips.binary_search_by(|trip|
+                trip.departures[pos].cmp(&earliest)
+                    .then(std::cmp::Ordering::Greater)) {
+                Ok(i) | Err(i) => i
+            };
+
+            for (i, trip) in pattern.trips.iter().enumerate().skip(first) {
+                if let Some((_, _, departure)) = best {
+                    if trip.departures[pos]
+                        > (departure + shift).saturating_add(SLACK) {
+                        break;
+                    }
+                }
+                if (accessible && !trip.accessible) || !service.runs(day, trip) {
+                    continue;
+                }
+                match self.departure(delays, p, i as u32, *shift, pos) {
+                    Some(departure) if departure >= ready
+                        && best.map_or(true, |(_, _, best)| departure < best) =>
+                        best = Some((i as u32, day as u32, departure)),
+                    _ => ()
+                }
+            }
+        }
+        best
+    }
+
+    // Walk the transfers of the stops improved in round k
+    fn transfer(&self, improved: &[u32], arrivals: &mut Vec<u32>,
+                labels: &mut Vec<Label>, best: &mut Vec<u32>,
+                marked: &mut Vec<bool>) {
+        for stop in improved {
+            let arrival = arrivals[*stop as usize];
+            for (to, time) in &self.transfers[*stop as usize] {
+                let to = *to as usize;
+                if arrival + time < best[to] {
+                    arrivals[to] = arrival + time;
+                    best[to] = arrival + time;
+                    labels[to] = Label::Transfer { from: *stop, time: *time };
+                    marked[to] = true;
+                }
+            }
+        }
+    }
+
+    // The rounds, from stops we get to (with seconds it takes) until
+    // max_rides, only keeping arrivals before until. Egress stops we walk
+    // away from are found when they improve on fewer rides.
+    fn rounds(&self, delays: &Delays, service: &ServiceDay,
+              access: &[(u32, u32)], egress: &[(u32, u32)], depart_at: u32,
+              until: u32, max_rides: usize, accessible: bool) -> Rounds {
+        let n = self.stops.len();
+        let mut arrivals = vec![vec![NEVER; n]];
+        let mut labels = vec![vec![Label::None; n]];
+        let mut best = vec![NEVER; n];
+        let mut marked = vec![false; n];
+
+        for (stop, time) in access {
+            let stop = *stop as usize;
+            if depart_at + time < arrivals[0][stop] {
+                arrivals[0][stop] = depart_at + time;
+                best[stop] = depart_at + time;
+                labels[0][stop] = Label::Access(*time);
+                marked[stop] = true;
+            }
+        }
+        let improved = (0..n as u32).filter(|s| marked[*s as usize])
+            .collect::<Vec<_>>();
+        self.transfer(&improved, &mut arrivals[0], &mut labels[0],
+                      &mut best, &mut marked);
+
+        let mut found = vec![];
+        let mut best_arrival = until;
+        for k in 1..=max_rides {
+            let (ready, label) = (arrivals[k - 1].clone(), labels[k - 1].clone());
+            arrivals.push(ready.clone());
+            labels.push(label.clone());
+
+            // Patterns to scan, from their first marked stop
+            let mut queue = HashMap::new();
+            for stop in 0..n {
+                if !marked[stop] {
+                    continue;
+                }
+                marked[stop] = false;
+                for (pattern, pos) in &self.stop_patterns[stop] {
+                    let first = queue.entry(*pattern).or_insert(*pos);
+                    *first = (*first).min(*pos);
+                }
+            }
+            if queue.is_empty() {
+                break;
+            }
+
+            let mut improved = vec![];
+            for (p, start) in queue {
+                let pattern = &self.patterns[p as usize];
+                // trip, day, from, departure
+                let mut trip: Option<(u32, u32, u32, u32)> = None;
+                for pos in start as usize..pattern.stops.len() {
+                    let stop = pattern.stops[pos] as usize;
+                    if let Some((t, day, from, _)) = trip {
+                        if let Some(arrival) = self.arrival(
+                            delays, p, t, service.shift(day), pos) {
+                            if arrival < best[stop].min(best_arrival) {
+                                arrivals[k][stop] = arrival;
+                                best[stop] = arrival;
+                                labels[k][stop] = Label::Ride {
+                                    pattern: p, trip: t, day: day, from: from,
+                                    to: pos as u32
+                                };
+                                marked[stop] = true;
+                                improved.push(stop as u32);
+                            }
+                        }
+                    }
+
+                    // Can we catch an earlier trip here?
+                    if ready[stop] == NEVER
+                        || (accessible && !self.accessible[stop]) {
+                        continue;
+                    }
+                    let change = match label[stop] {
+                        Label::Ride { .. } => self.min_change[stop],
+                        _ => 0
+                    };
+                    let ready = ready[stop] + change;
+                    if trip.map_or(true, |(_, _, _, departure)| ready < departure) {
+                        if let Some((t, day, departure)) = self.earliest_trip(
+                            delays, service, p, pos, ready, accessible) {
+                            if trip.map_or(true, |(_, _, _, d)| departure < d) {
+                                trip = Some((t, day, pos as u32, departure));
+                            }
+                        }
+                    }
+                }
+            }
+
+            self.transfer(&improved, &mut arrivals[k], &mut labels[k],
+                          &mut best, &mut marked);
+
+            let exit = egress.iter()
+                .filter(|(stop, _)| arrivals[k][*stop as usize] != NEVER)
+                .map(|(stop, time)| (arrivals[k][*stop as usize] + time, *stop))
+                .min();
+            if let Some((arrival, stop)) = exit {
+                if arrival < best_arrival {
+                    best_arrival = arrival;
+                    found.push((k, stop, arrival));
+                }
+            }
+        }
+
+        Rounds {
+            arrivals: arrivals,
+            labels: labels,
+            found: found
+        }
+    }
+
+    // Pareto-optimal journeys by arrival and rides
+    fn journeys(&self, delays: &Delays, service: &ServiceDay,
+                access: &[(u32, u32)], egress: &[(u32, u32)], depart_at: u32,
+                max_rides: usize, accessible: bool)
+        -> Vec<(usize, Vec<(u32, Label)>, u32)> {
+        let Rounds { labels, found, .. } = self.rounds(
+            delays, service, access, egress, depart_at, NEVER, max_rides,
+            accessible);
+
+        found.into_iter().map(|(k, stop, arrival)| {
+            let mut path = vec![];
+            let (mut round, mut stop) = (k, stop as usize);
+            loop {
+                let label = labels[round][stop];
+                path.push((stop as u32, label));
+                match label {
+                    Label::Ride { pattern, from, .. } => {
+                        stop = self.patterns[pattern as usize]
+                            .stops[from as usize] as usize;
+                        round -= 1;
+                    },
+                    Label::Transfer { from, .. } => stop = from as usize,
+                    _ => break
+                }
+            }
+            path.reverse();
+            (k, path, arrival)
+        }).collect()
+    }
+
+    // Stops we can ride to before until, with the earliest arrival and
+    // the fewest rides that make it
+    fn reach(&self, delays: &Delays, service: &ServiceDay,
+             access: &[(u32, u32)], depart_at: u32, until: u32,
+             max_rides: usize, accessible: bool) -> Vec<(u32, u32, usize)> {
+        let Rounds { arrivals, .. } = self.rounds(
+            delays, service, access, &[], depart_at, until, max_rides,
+            accessible);
+        let last = arrivals.len() - 1;
+
+        (0..self.stops.len()).filter_map(|stop| {
+            let arrival = arrivals[last][stop];
+            if arrival >= until {
+                return None;
+            }
+            // Later rounds keep what the previous ones found
+            let rides = (0..=last).find(|k| arrivals[*k][stop] == arrival)?;
+            if rides == 0 {
+                return None; // walked there, not our business
+            }
+            Some((stop as u32, arrival, rides))
+        }).collect()
+    }
+}
+
+// Both ends as stops of a feed (index in its timetable) with the
+// seconds to walk there. Times are in seconds since midnight of date.
+pub fn plan(feed: &Arc<IndexedGtfs>, feeds: &Feeds, date: NaiveDate,
+            access: &[(u32, u32)], egress: &[(u32, u32)], depart_at: u32,
+            max_rides: usize, accessible: bool) -> Vec<Journey> {
+    let timetable = &feed.timetable;
+    let service = timetable.service_day(&feed.db, date);
+    let delays = Delays::new(feeds);
+    let stop = |idx: u32| Stop(Arc::clone(feed),
+                               Arc::clone(&feed.db.stops[&timetable.stops[idx as usize]]));
+
+    timetable.journeys(&delays, &service, access, egress, depart_at, max_rides,
+                       accessible)
+        .into_iter()
+        .map(|(rides, path, arrival)| {
+            let (first, walk) = match path.first() {
+                Some((stop, Label::Access(walk))) => (*stop, *walk),
+                _ => (0, 0)
+            };
+            let mut time = depart_at + walk;
+            let mut legs = vec![Leg::Walk {
+                from: None,
+                to: Some(stop(first)),
+                departure: depart_at,
+                arrival: time
+            }];
+
+            for (to, label) in path.iter().skip(1) {
+                match *label {
+                    Label::Ride { pattern, trip, day, from, to } => {
+                        let p = &timetable.patterns[pattern as usize];
+                        let shift = service.shift(day);
+                        let departure = timetable.departure(
+                            &delays, pattern, trip, shift, from as usize)
+                            .unwrap_or(time);
+                        let arrival = timetable.arrival(
+                            &delays, pattern, trip, shift, to as usize)
+                            .unwrap_or(departure);
+                        let trip_id = &p.trips[trip as usize].trip_id;
+                        let stops = p.stops[from as usize..=to as usize].iter()
+                            .map(|s| stop(*s)).collect::<Vec<_>>();
+                        let route = feed.routes.get(&p.route_id);
+                        legs.push(Leg::Ride {
+                            route_id: p.route_id.to_string(),
+                            trip_id: trip_id.to_string(),
+                            route_name: route.and_then(|r| r.name())
+                                .map(|name| name.to_string()),
+                            headsign: feed.db.trips.get(trip_id)
+                                .and_then(|trip| trip.trip_headsign.clone()),
+                            color: route.and_then(|r| r.color()),
+                            text_color: route.and_then(|r| r.text_color()),
+                            shape: shape(feed, trip_id, &stops),
+                            stops: stops,
+                            departure: departure,
+                            arrival: arrival
+                        });
+                        time = arrival;
+                    },
+                    Label::Transfer { from, time: walk } => {
+                        legs.push(Leg::Walk {
+                            from: Some(stop(from)),
+                            to: Some(stop(*to)),
+                            departure: time,
+                            arrival: time + walk
+                        });
+                        time += walk;
+                    },
+                    _ => ()
+                }
+            }
+
+            let last = path.last().map_or(first, |(stop, _)| *stop);
+            legs.push(Leg::Walk {
+                from: Some(stop(last)),
+                to: None,
+                departure: time,
+                arrival: arrival
+            });
+
+            // Leave as late as we can for the first ride
+            let boarding = match legs.get(1) {
+                Some(Leg::Ride { departure, .. }) => Some(*departure),
+                _ => None
+            };
+            if let (Some(boarding), Some(Leg::Walk { departure, arrival, .. }))
+                = (boarding, legs.first_mut()) {
+                *departure = boarding.saturating_sub(walk).max(*departure);
+                *arrival = *departure + walk;
+            }
+
+            Journey {
+                departure: match legs.first() {
+                    Some(Leg::Walk { departure, .. }) => *departure,
+                    _ => depart_at
+                },
+                arrival: arrival,
+                rides: rides,
+                legs: legs
+            }
+        }).collect()
+}
+
+// What the trip draws between the stops we ride
+fn shape(feed: &IndexedGtfs, trip_id: &str, stops: &[Stop]) -> Vec<(f64, f64)> {
+    let ends = |stop: &Stop| (stop.get_lon(), stop.get_lat());
+    let straight = stops.iter().map(ends).collect::<Vec<_>>();
+    let (first, last) = match (stops.first(), stops.last()) {
+        (Some(first), Some(last)) => (ends(first), ends(last)),
+        _ => return straight
+    };
+
+    let points = feed.db.trips.get(trip_id)
+        .and_then(|trip| trip.shape_id.as_ref())
+        .and_then(|id| feed.db.shapes.get(id))
+        .map(|points| points.iter()
+             .map(|point| (point.longitude, point.latitude))
+             .collect::<Vec<_>>())
+        .unwrap_or(vec![]);
+    let cut = extras::cut_shape(&points, first, last);
+    if cut.len() < 2 {
+        return straight;
+    }
+    cut
+}
+
+pub fn reach(feed: &Arc<IndexedGtfs>, feeds: &Feeds, date: NaiveDate,
+             access: &[(u32, u32)], depart_at: u32, until: u32,
+             max_rides: usize, accessible: bool) -> Vec<Reached> {
+    let timetable = &feed.timetable;
+    let service = timetable.service_day(&feed.db, date);
+    timetable.reach(&Delays::new(feeds), &service, access, depart_at, until,
+                    max_rides, accessible)
+        .into_iter()
+        .map(|(stop, arrival, rides)| Reached {
+            stop: Stop(Arc::clone(feed),
+                       Arc::clone(&feed.db.stops[&timetable.stops[stop as usize]])),
+            arrival: arrival,
+            rides: rides
+        }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::super::TransitMap;
+    use super::super::tests::Fixture;
+    use super::Leg;
+
+    // A wednesday, and a saturday
+    fn wednesday() -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(2020, 6, 3)
+    }
+
+    fn saturday() -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(2020, 6, 6)
+    }
+
+    // A to s3 then change for B to s4, or the slow C straight to s4. On
+    // weekends W goes straight to s4 fast, and N leaves s1 after midnight.
+    fn feed(test: &str) -> Fixture {
+        Fixture::new(test, &[
+            ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+             x,X,http://x,America/Los_Angeles\n"),
+            ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             all,1,1,1,1,1,1,1,20200101,20301231\n\
+             weekend,0,0,0,0,0,1,1,20200101,20301231\n"),
+            ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon\n\
+             s1,One,37.70,-122.40\ns2,Two,37.71,-122.40\n\
+             s3,Three,37.72,-122.40\ns4,Four,37.73,-122.40\n"),
+            ("routes.txt", "route_id,agency_id,route_short_name,route_long_name,route_type\n\
+             A,x,A,,3\nB,x,B,,3\nC,x,C,,3\nW,x,W,,3\nN,x,N,,3\n"),
+            ("trips.txt", "route_id,service_id,trip_id\n\
+             A,all,a1\nA,all,a2\nB,all,b1\nB,all,b2\nC,all,c1\n\
+             W,weekend,w1\nN,all,n1\n"),
+            ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             a1,08:00:00,08:00:00,s1,1\na1,08:05:00,08:05:00,s2,2\na1,08:10:00,08:10:00,s3,3\n\
+             a2,08:30:00,08:30:00,s1,1\na2,08:35:00,08:35:00,s2,2\na2,08:40:00,08:40:00,s3,3\n\
+             b1,08:20:00,08:20:00,s3,1\nb1,08:30:00,08:30:00,s4,2\n\
+             b2,08:40:00,08:40:00,s3,1\nb2,08:50:00,08:50:00,s4,2\n\
+             c1,08:05:00,08:05:00,s1,1\nc1,09:00:00,09:00:00,s4,2\n\
+             w1,08:01:00,08:01:00,s1,1\nw1,08:10:00,08:10:00,s4,2\n\
+             n1,24:10:00,24:10:00,s1,1\nn1,24:20:00,24:20:00,s2,2\n"),
+            // Too long to make b1
+            ("transfers.txt", "from_stop_id,to_stop_id,transfer_type,min_transfer_time\n\
+             s3,s3,2,900\n")
+        ])
+    }
+
+    #[test]
+    fn journeys() {
+        let mut map = TransitMap::new();
+        let feed = feed("journeys");
+        map.load(feed.path()).unwrap();
+        let s1 = map.get_stops(37.70, -122.40).next().unwrap().clone();
+        let s4 = map.get_stops(37.73, -122.40).next().unwrap().clone();
+
+        let journeys = map.journeys(&[(s1.clone(), 60)], &[(s4.clone(), 30)],
+                                    wednesday(), 7 * 3600 + 58 * 60, 4, false);
+        assert_eq!(journeys.len(), 2);
+        assert_eq!(journeys[0].rides, 1);
+        assert_eq!(journeys[0].arrival, 9 * 3600 + 30);
+        assert_eq!(journeys[1].rides, 2);
+        assert_eq!(journeys[1].arrival, 8 * 3600 + 50 * 60 + 30);
+        // Leaving right in time for a1
+        assert_eq!(journeys[1].departure, 8 * 3600 - 60);
+
+        let rides = journeys[1].legs.iter().filter_map(|leg| match leg {
+            Leg::Ride { trip_id, stops, .. } => Some((trip_id.as_str(), stops.len())),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(rides, vec![("a1", 3), ("b2", 2)]);
+        assert_eq!(journeys[1].legs.len(), 4);
+
+        // No shapes in there, straight from stop to stop
+        match &journeys[1].legs[1] {
+            Leg::Ride { route_name, shape, .. } => {
+                assert_eq!(route_name.as_ref().map(|n| n.as_str()), Some("A"));
+                assert_eq!(shape, &vec![(-122.40, 37.70), (-122.40, 37.71),
+                                        (-122.40, 37.72)]);
+            },
+            leg => panic!("{:?} is not a ride", leg)
+        }
+
+        // Too late for c1, and a2 doesn't make it to a B
+        assert!(map.journeys(&[(s1.clone(), 60)], &[(s4, 30)],
+                             wednesday(), 8 * 3600 + 6 * 60, 4, false)
+                .is_empty());
+    }
+
+    #[test]
+    fn service_days() {
+        let mut map = TransitMap::new();
+        let feed = feed("service_days");
+        map.load(feed.path()).unwrap();
+        let s1 = map.get_stops(37.70, -122.40).next().unwrap().clone();
+        let s2 = map.get_stops(37.71, -122.40).next().unwrap().clone();
+        let s4 = map.get_stops(37.73, -122.40).next().unwrap().clone();
+        let first_ride = |journey: &super::Journey| journey.legs.iter()
+            .find_map(|leg| match leg {
+                Leg::Ride { trip_id, departure, .. } =>
+                    Some((trip_id.to_string(), *departure)),
+                _ => None
+            });
+
+        // W only runs on weekends
+        let journeys = map.journeys(&[(s1.clone(), 60)], &[(s4.clone(), 30)],
+                                    saturday(), 7 * 3600 + 58 * 60, 4, false);
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(first_ride(&journeys[0]), Some(("w1".to_string(),
+                                                   8 * 3600 + 60)));
+        assert_eq!(journeys[0].arrival, 8 * 3600 + 10 * 60 + 30);
+
+        // Tuesday's n1 is still on the way after midnight
+        let journeys = map.journeys(&[(s1.clone(), 60)], &[(s2, 30)],
+                                    wednesday(), 5 * 60, 4, false);
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(first_ride(&journeys[0]), Some(("n1".to_string(), 10 * 60)));
+        assert_eq!(journeys[0].arrival, 20 * 60 + 30);
+
+        // but not its runs that were over by midnight
+        assert!(map.journeys(&[(s1, 60)], &[(s4, 30)], wednesday(), 0, 1,
+                             false).iter()
+                .all(|journey| journey.arrival >= 8 * 3600));
+    }
+
+    #[test]
+    fn reach() {
+        let mut map = TransitMap::new();
+        let feed = feed("reach");
+        map.load(feed.path()).unwrap();
+        let s1 = map.get_stops(37.70, -122.40).next().unwrap().clone();
+
+        // An hour: c1 gets in too late, but a1 then b2 make it
+        let mut reached = map.reach(&[(s1, 60)], wednesday(), 7 * 3600 + 58 * 60,
+                                    3600, 4, false).into_iter()
+            .map(|r| (r.stop.id().to_string(), r.arrival, r.rides))
+            .collect::<Vec<_>>();
+        reached.sort();
+        assert_eq!(reached, vec![
+            ("s2".to_string(), 8 * 3600 + 5 * 60, 1),
+            ("s3".to_string(), 8 * 3600 + 10 * 60, 1),
+            ("s4".to_string(), 8 * 3600 + 50 * 60, 2)
+        ]);
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use prost::Message;
+
+use super::proto;
+use super::LoadError;
+
+// Feeds are a few MB at most, don't take in anything a server sends
+const MAX_FEED: u64 = 64 << 20;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: u32 = 5;
+
+// Where a GTFS-Realtime feed comes from: a file or an http(s):// url
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(String),
+    Http(String)
+}
+
+impl Source {
+    pub fn parse(source: &str) -> Self {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            Source::Http(source.to_string())
+        } else {
+            Source::File(source.to_string())
+        }
+    }
+
+    pub fn fetch(&self) -> Result<Vec<u8>, LoadError> {
+        let url = match self {
+            Source::File(file) => return Ok(fs::read(file)?),
+            Source::Http(url) => url
+        };
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .redirects(MAX_REDIRECTS)
+            .build();
+        let response = agent.get(url)
+            .set("Accept", "application/x-protobuf")
+            .call()
+            .map_err(|err| match err {
+                ureq::Error::Status(code, response) =>
+                    LoadError::Http(format!("{} {}", code, response.status_text())),
+                ureq::Error::Transport(err) =>
+                    LoadError::Io(io::Error::other(err.to_string()))
+            })?;
+
+        let mut buf = vec![];
+        response.into_reader().take(MAX_FEED + 1).read_to_end(&mut buf)?;
+        if buf.len() as u64 > MAX_FEED {
+            return Err(LoadError::Http(format!("a feed over {} bytes", MAX_FEED)));
+        }
+        Ok(buf)
+    }
+}
+
+// What changed on a stop of a trip. Updates name the stop by sequence,
+// or by id when they don't know it.
+#[derive(Debug, Clone)]
+struct StopChange {
+    sequence: Option<u32>,
+    stop_id: Option<String>,
+    delay: Option<i32>,
+    skipped: bool
+}
+
+impl StopChange {
+    fn is(&self, sequence: u32, stop_id: &str) -> bool {
+        match self.sequence {
+            Some(seq) => seq == sequence,
+            None => self.stop_id.as_ref().map_or(false, |id| id == stop_id)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TripChanges {
+    pub canceled: bool,
+    delay: Option<i32>, // the whole trip, until a stop says otherwise
+    stops: Vec<StopChange>
+}
+
+impl TripChanges {
+    fn change(&self, sequence: u32, stop_id: &str) -> Option<&StopChange> {
+        self.stops.iter().find(|change| change.is(sequence, stop_id))
+    }
+
+    // Delay in seconds at the last of stops, the (sequence, id) of the
+    // trip up to it. A delay holds down the trip until another one is
+    // given.
+    pub fn delay<'s, I>(&self, stops: I) -> i32
+        where I: IntoIterator<Item=(u32, &'s str)> {
+        stops.into_iter().fold(self.delay.unwrap_or(0), |delay, (seq, id)|
+            self.change(seq, id).and_then(|change| change.delay)
+                .unwrap_or(delay))
+    }
+
+    // The delay at each of stops, all the (sequence, id) of the trip in
+    // order, None where it skips the stop
+    pub fn delays<'s, I>(&self, stops: I) -> Vec<Option<i32>>
+        where I: IntoIterator<Item=(u32, &'s str)> {
+        let mut delay = self.delay.unwrap_or(0);
+        stops.into_iter().map(|(seq, id)| match self.change(seq, id) {
+            Some(change) if change.skipped => None,
+            change => {
+                delay = change.and_then(|change| change.delay).unwrap_or(delay);
+                Some(delay)
+            }
+        }).collect()
+    }
+
+    pub fn skips(&self, sequence: u32, stop_id: &str) -> bool {
+        self.change(sequence, stop_id).map_or(false, |change| change.skipped)
+    }
+}
+
+impl From<proto::TripUpdate> for TripChanges {
+    fn from(update: proto::TripUpdate) -> Self {
+        TripChanges {
+            canceled: update.trip.schedule_relationship
+                == Some(proto::TripRelationship::Canceled as i32),
+            delay: update.delay,
+            stops: update.stop_time_update.into_iter().map(|stu| {
+                let skipped = stu.schedule_relationship
+                    == Some(proto::StopRelationship::Skipped as i32);
+                StopChange {
+                    sequence: stu.stop_sequence,
+                    stop_id: stu.stop_id,
+                    // A skipped stop says nothing of the delay. XXX
+                    // absolute times need the service day, only deltas
+                    // for now
+                    delay: if skipped {
+                        None
+                    } else {
+                        stu.departure.and_then(|event| event.delay)
+                            .or(stu.arrival.and_then(|event| event.delay))
+                    },
+                    skipped: skipped
+                }
+            }).collect()
+        }
+    }
+}
+
+// Who an alert is about - everything it sets must match
+#[derive(Debug, Clone)]
+struct Selector {
+    route_id: Option<String>,
+    stop_id: Option<String>,
+    trip_id: Option<String>
+}
+
+impl Selector {
+    fn matches(&self, stop_id: &str, route_id: &str, trip_id: &str) -> bool {
+        self.stop_id.as_ref().map_or(true, |id| id == stop_id)
+            && self.route_id.as_ref().map_or(true, |id| id == route_id)
+            && self.trip_id.as_ref().map_or(true, |id| id == trip_id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceAlert {
+    pub header: Option<String>,
+    pub description: Option<String>,
+    pub no_service: bool,
+    periods: Vec<(u64, u64)>, // end 0 is open
+    selectors: Vec<Selector>
+}
+
+impl ServiceAlert {
+    // now in seconds since the epoch
+    pub fn is_active(&self, now: u64) -> bool {
+        self.periods.is_empty() || self.periods.iter()
+            .any(|(start, end)| *start <= now && (*end == 0 || now < *end))
+    }
+
+    // Agency wide alerts have nothing we know of to select, they're
+    // about everything
+    pub fn concerns(&self, stop_id: &str, route_id: &str, trip_id: &str) -> bool {
+        self.selectors.iter()
+            .any(|selector| selector.matches(stop_id, route_id, trip_id))
+    }
+}
+
+fn text(string: Option<proto::TranslatedString>) -> Option<String> {
+    string.and_then(|s| s.translation.into_iter().next())
+        .map(|translation| translation.text)
+}
+
+impl From<proto::Alert> for ServiceAlert {
+    fn from(alert: proto::Alert) -> Self {
+        ServiceAlert {
+            no_service: alert.effect == Some(proto::Effect::NoService as i32),
+            periods: alert.active_period.iter()
+                .map(|period| (period.start.unwrap_or(0),
+                               period.end.unwrap_or(0)))
+                .collect(),
+            selectors: alert.informed_entity.into_iter()
+                .map(|entity| Selector {
+                    route_id: entity.route_id,
+                    stop_id: entity.stop_id,
+                    trip_id: entity.trip.and_then(|trip| trip.trip_id)
+                }).collect(),
+            header: text(alert.header_text),
+            description: text(alert.description_text)
+        }
+    }
+}
+
+// One decoded feed
+#[derive(Debug, Default)]
+pub struct Realtime {
+    pub timestamp: u64,
+    trips: HashMap<String, TripChanges>,
+    alerts: Vec<ServiceAlert>
+}
+
+impl Realtime {
+    pub fn decode(buf: &[u8]) -> Result<Self, LoadError> {
+        let feed = proto::FeedMessage::decode(buf)?;
+        let mut realtime = Realtime {
+            timestamp: feed.header.timestamp.unwrap_or(0),
+            ..Default::default()
+        };
+
+        for entity in feed.entity {
+            if entity.is_deleted == Some(true) {
+                continue;
+            }
+            if let Some(update) = entity.trip_update {
+                if let Some(trip_id) = update.trip.trip_id.clone() {
+                    realtime.trips.insert(trip_id, update.into());
+                }
+            }
+            if let Some(alert) = entity.alert {
+                realtime.alerts.push(alert.into());
+            }
+        }
+
+        Ok(realtime)
+    }
+
+    pub fn trip(&self, trip_id: &str) -> Option<&TripChanges> {
+        self.trips.get(trip_id)
+    }
+}
+
+// The last version of every feed we follow, by source. Reloads replace
+// a feed while the Db keeps reading.
+#[derive(Debug, Default)]
+pub struct Feeds {
+    by_source: RwLock<HashMap<String, Arc<Realtime>>>
+}
+
+impl Feeds {
+    pub fn update(&self, source: &str, realtime: Realtime) {
+        self.by_source.write().unwrap_or_else(|err| err.into_inner())
+            .insert(source.to_string(), Arc::new(realtime));
+    }
+
+    // The feeds as they are now, for reading a lot of them without
+    // holding up reloads
+    pub fn snapshot(&self) -> Vec<Arc<Realtime>> {
+        self.by_source.read().unwrap_or_else(|err| err.into_inner())
+            .values().cloned().collect()
+    }
+
+    // f of the first feed knowing about that trip
+    pub fn trip<T, F: FnOnce(&TripChanges) -> T>(&self, trip_id: &str, f: F)
+        -> Option<T> {
+        let feeds = self.by_source.read().unwrap_or_else(|err| err.into_inner());
+        feeds.values().filter_map(|feed| feed.trip(trip_id)).next().map(f)
+    }
+
+    pub fn alerts(&self, stop_id: &str, route_id: &str, trip_id: &str,
+                  now: u64) -> Vec<ServiceAlert> {
+        let feeds = self.by_source.read().unwrap_or_else(|err| err.into_inner());
+        feeds.values().flat_map(|feed| feed.alerts.iter())
+            .filter(|alert| alert.is_active(now)
+                    && alert.concerns(stop_id, route_id, trip_id))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use prost::Message;
+
+    use super::super::proto;
+    use super::*;
+
+    fn feed() -> Vec<u8> {
+        let stop = |seq, delay, skipped: bool| proto::StopTimeUpdate {
+            stop_sequence: Some(seq),
+            arrival: None,
+            departure: Some(proto::StopTimeEvent { delay: Some(delay), time: None }),
+            stop_id: None,
+            schedule_relationship: if skipped {
+                Some(proto::StopRelationship::Skipped as i32)
+            } else {
+                None
+            }
+        };
+        let trip = |id: &str, relationship| proto::TripDescriptor {
+            trip_id: Some(id.to_string()),
+            route_id: None,
+            schedule_relationship: relationship
+        };
+
+        let message = proto::FeedMessage {
+            header: proto::FeedHeader {
+                gtfs_realtime_version: "2.0".to_string(),
+                timestamp: Some(1000)
+            },
+            entity: vec![
+                proto::FeedEntity {
+                    id: "1".to_string(),
+                    is_deleted: None,
+                    trip_update: Some(proto::TripUpdate {
+                        trip: trip("late", None),
+                        stop_time_update: vec![stop(2, 60, false),
+                                               stop(3, 0, true),
+                                               stop(5, 120, false)],
+                        delay: None
+                    }),
+                    alert: None
+                },
+                proto::FeedEntity {
+                    id: "2".to_string(),
+                    is_deleted: None,
+                    trip_update: Some(proto::TripUpdate {
+                        trip: trip("gone", Some(proto::TripRelationship::Canceled as i32)),
+                        stop_time_update: vec![],
+                        delay: None
+                    }),
+                    alert: None
+                },
+                proto::FeedEntity {
+                    id: "3".to_string(),
+                    is_deleted: None,
+                    trip_update: None,
+                    alert: Some(proto::Alert {
+                        active_period: vec![proto::TimeRange {
+                            start: Some(500), end: Some(2000)
+                        }],
+                        informed_entity: vec![proto::EntitySelector {
+                            agency_id: None,
+                            route_id: Some("N".to_string()),
+                            trip: None,
+                            stop_id: Some("s1".to_string())
+                        }],
+                        effect: Some(proto::Effect::NoService as i32),
+                        header_text: Some(proto::TranslatedString {
+                            translation: vec![proto::Translation {
+                                text: "Closed".to_string(),
+                                language: None
+                            }]
+                        }),
+                        description_text: None
+                    })
+                }
+            ]
+        };
+
+        let mut buf = vec![];
+        message.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn trip_updates() {
+        let realtime = Realtime::decode(&feed()).unwrap();
+        assert_eq!(realtime.timestamp, 1000);
+        assert!(realtime.trip("gone").unwrap().canceled);
+
+        let late = realtime.trip("late").unwrap();
+        assert!(!late.canceled);
+        let stops = [(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e")];
+        assert_eq!(late.delay(stops[..1].iter().cloned()), 0);
+        assert_eq!(late.delay(stops[..2].iter().cloned()), 60);
+        // skipped, no delay given there
+        assert!(late.skips(3, "c"));
+        assert_eq!(late.delay(stops[..4].iter().cloned()), 60);
+        assert_eq!(late.delay(stops.iter().cloned()), 120);
+        assert_eq!(late.delays(stops.iter().cloned()),
+                   vec![Some(0), Some(60), None, Some(60), Some(120)]);
+        assert!(realtime.trip("other").is_none());
+    }
+
+    #[test]
+    fn alerts() {
+        let feeds = Feeds::default();
+        feeds.update("test", Realtime::decode(&feed()).unwrap());
+
+        let alerts = feeds.alerts("s1", "N", "any", 1000);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].no_service);
+        assert_eq!(alerts[0].header, Some("Closed".to_string()));
+
+        assert!(feeds.alerts("s1", "N", "any", 3000).is_empty());
+        assert!(feeds.alerts("s2", "N", "any", 1000).is_empty());
+        assert!(feeds.alerts("s1", "J", "any", 1000).is_empty());
+        assert_eq!(feeds.trip("late", |trip| trip.canceled), Some(false));
+    }
+
+    // Answers each request in turn, returns what was asked
+    fn serve(responses: Vec<Vec<u8>>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || responses.into_iter().map(|response| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream.write_all(&response).unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        }).collect());
+        (url, server)
+    }
+
+    #[test]
+    fn http_source() {
+        let mut ok = b"HTTP/1.0 200 OK\r\nContent-Type: \
+                       application/x-protobuf\r\n\r\n".to_vec();
+        ok.extend(feed());
+        let moved = b"HTTP/1.0 302 Found\r\nLocation: /feed\r\n\r\n".to_vec();
+        let (url, server) = serve(vec![moved, ok]);
+
+        let buf = Source::parse(&format!("{}/old", url)).fetch().unwrap();
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /old HTTP/1.1"));
+        assert!(requests[1].starts_with("GET /feed HTTP/1.1"));
+        assert_eq!(Realtime::decode(&buf).unwrap().timestamp, 1000);
+
+        let (url, server) = serve(vec![
+            b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec()]);
+        match Source::parse(&url).fetch() {
+            Err(LoadError::Http(status)) => assert_eq!(status, "404 Not Found"),
+            other => panic!("got {:?}", other)
+        }
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+// Which days services run, by calendar.txt and calendar_dates.txt
+use std::collections::HashSet;
+
+use chrono::{Datelike, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use gtfs_structures::{Calendar, Exception, Gtfs};
+
+pub const DAY: u32 = 24 * 3600;
+
+// Today where the agencies of the feed are, UTC if they don't say
+pub fn today(gtfs: &Gtfs) -> NaiveDate {
+    let now = Utc::now();
+    match gtfs.agencies.first()
+        .and_then(|agency| agency.timezone.parse::<Tz>().ok()) {
+        Some(tz) => now.with_timezone(&tz).naive_local().date(),
+        None => now.naive_utc().date()
+    }
+}
+
+// The service days trips on date can come from, with the seconds their
+// times are ahead: the date itself, and the day before for those still
+// running past midnight (times past 24:00)
+pub fn days(date: NaiveDate) -> Vec<(NaiveDate, u32)> {
+    let mut days = vec![(date, 0)];
+    days.extend(date.pred_opt().map(|before| (before, DAY)));
+    days
+}
+
+// YYYY-MM-DD, or YYYYMMDD like the feeds
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y%m%d"))
+        .ok()
+}
+
+fn on_weekday(calendar: &Calendar, date: NaiveDate) -> bool {
+    match date.weekday() {
+        Weekday::Mon => calendar.monday,
+        Weekday::Tue => calendar.tuesday,
+        Weekday::Wed => calendar.wednesday,
+        Weekday::Thu => calendar.thursday,
+        Weekday::Fri => calendar.friday,
+        Weekday::Sat => calendar.saturday,
+        Weekday::Sun => calendar.sunday
+    }
+}
+
+// Whether service_id runs on date. Dates added or removed in
+// calendar_dates.txt win over the weekly calendar.
+pub fn runs_on(gtfs: &Gtfs, service_id: &str, date: NaiveDate) -> bool {
+    let exception = gtfs.calendar_dates.get(service_id)
+        .and_then(|dates| dates.iter().find(|d| d.date == date));
+    match exception.map(|d| &d.exception_type) {
+        Some(Exception::Added) => true,
+        Some(Exception::Deleted) => false,
+        None => gtfs.calendar.get(service_id).map_or(false, |calendar|
+            calendar.start_date <= date && date <= calendar.end_date
+            && on_weekday(calendar, date))
+    }
+}
+
+// Services running at least a day
+pub fn running_services(gtfs: &Gtfs) -> HashSet<String> {
+    let mut services = gtfs.calendar_dates.values().flatten()
+        .filter(|d| match d.exception_type {
+            Exception::Added => true,
+            _ => false
+        })
+        .map(|d| d.service_id.to_string())
+        .collect::<HashSet<_>>();
+
+    for (id, calendar) in gtfs.calendar.iter() {
+        // Removed dates can empty a calendar, look for a day left
+        let mut date = calendar.start_date;
+        while date <= calendar.end_date {
+            if runs_on(gtfs, id, date) {
+                services.insert(id.to_string());
+                break;
+            }
+            date = match date.succ_opt() {
+                Some(next) => next,
+                None => break
+            };
+        }
+    }
+    services
+}
@@ -0,0 +1,196 @@
+// What's wrong in a feed. Nothing here stops it from loading, but riders
+// would see it: stops in the ocean, trips going back in time or never
+// running.
+use std::collections::{BTreeMap, HashSet};
+
+use serde::Serialize;
+
+use super::service;
+use super::LoadError;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    MissingCoordinates { stop_id: String },
+    OutOfBounds { stop_id: String, lat: f64, lon: f64 }, // of the map
+    UnreferencedStop { stop_id: String }, // no trip, or station without stops
+    BackwardsTime { trip_id: String, stop_sequence: u16 },
+    NoServiceDays { trip_id: String, service_id: String }
+}
+
+impl Problem {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Problem::MissingCoordinates { .. } => "missing_coordinates",
+            Problem::OutOfBounds { .. } => "out_of_bounds",
+            Problem::UnreferencedStop { .. } => "unreferenced_stop",
+            Problem::BackwardsTime { .. } => "backwards_time",
+            Problem::NoServiceDays { .. } => "no_service_days"
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub feed: String,
+    pub stops: usize,
+    pub trips: usize,
+    pub summary: BTreeMap<&'static str, usize>, // problems by kind
+    pub problems: Vec<Problem>
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Coordinates are optional for generic nodes and boarding areas
+fn needs_coordinates(stop: &gtfs_structures::Stop) -> bool {
+    use gtfs_structures::LocationType::*;
+    match stop.location_type {
+        GenericNode | BoardingArea => false,
+        _ => true
+    }
+}
+
+// bbox is the map the feed goes with, x is lon and y lat
+pub fn check(gtfs: &gtfs_structures::Gtfs, feed: &str,
+             bbox: Option<&quadtree::BBox>) -> Result<Report, LoadError> {
+    let mut problems = vec![];
+
+    let mut stops = gtfs.stops.values().collect::<Vec<_>>();
+    stops.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut trips = gtfs.trips.values().collect::<Vec<_>>();
+    trips.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let used = trips.iter()
+        .flat_map(|trip| trip.stop_times.iter().map(|st| st.stop.id.as_str()))
+        .collect::<HashSet<&str>>();
+    let parents = stops.iter()
+        .filter_map(|stop| stop.parent_station.as_ref().map(|id| id.as_str()))
+        .collect::<HashSet<&str>>();
+
+    for stop in stops.iter() {
+        match (stop.latitude, stop.longitude) {
+            (Some(lat), Some(lon)) => if let Some(bbox) = bbox {
+                if !bbox.contains(lon, lat) {
+                    problems.push(Problem::OutOfBounds {
+                        stop_id: stop.id.to_string(), lat: lat, lon: lon });
+                }
+            },
+            _ => if needs_coordinates(stop) {
+                problems.push(Problem::MissingCoordinates {
+                    stop_id: stop.id.to_string() });
+            }
+        }
+
+        let referenced = match stop.location_type {
+            gtfs_structures::LocationType::StopPoint =>
+                used.contains(stop.id.as_str()),
+            gtfs_structures::LocationType::StopArea =>
+                parents.contains(stop.id.as_str()),
+            _ => true
+        };
+        if !referenced {
+            problems.push(Problem::UnreferencedStop {
+                stop_id: stop.id.to_string() });
+        }
+    }
+
+    let running = service::running_services(gtfs);
+    for trip in trips.iter() {
+        let mut last = 0;
+        for st in trip.stop_times.iter() {
+            let arrival = st.arrival_time.or(st.departure_time);
+            let departure = st.departure_time.or(st.arrival_time);
+            if arrival.map_or(false, |t| t < last)
+                || departure.map_or(false, |t| t < arrival.unwrap_or(t)) {
+                problems.push(Problem::BackwardsTime {
+                    trip_id: trip.id.to_string(),
+                    stop_sequence: st.stop_sequence });
+            }
+            last = departure.unwrap_or(last).max(last);
+        }
+
+        if !running.contains(&trip.service_id) {
+            problems.push(Problem::NoServiceDays {
+                trip_id: trip.id.to_string(),
+                service_id: trip.service_id.to_string() });
+        }
+    }
+
+    let mut summary = BTreeMap::new();
+    for problem in problems.iter() {
+        *summary.entry(problem.kind()).or_insert(0) += 1;
+    }
+    Ok(Report {
+        feed: feed.to_string(),
+        stops: stops.len(),
+        trips: trips.len(),
+        summary: summary,
+        problems: problems
+    })
+}
+
+// Load a feed only to check it
+pub fn validate(feed: &str, bbox: Option<&quadtree::BBox>)
+    -> Result<Report, LoadError> {
+    check(&gtfs_structures::Gtfs::new(feed)?, feed, bbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::Fixture;
+
+    #[test]
+    fn problems() {
+        let feed = Fixture::new("validate", &[
+            ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+             x,X,http://x,America/Los_Angeles\n"),
+            ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             week,1,1,1,1,1,0,0,20200101,20301231\n\
+             never,0,0,0,0,0,0,0,20200101,20301231\n\
+             gone,0,0,0,0,0,1,0,20201226,20201226\n"),
+            ("calendar_dates.txt", "service_id,date,exception_type\n\
+             holiday,20201225,1\ngone,20201226,2\n"),
+            ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station\n\
+             s1,One,37.70,-122.40,,\n\
+             s2,Two,,,,\n\
+             s3,Three,0.0,0.0,,\n\
+             lonely,Lonely,37.71,-122.40,,\n\
+             hall,Hall,37.72,-122.40,1,\n"),
+            ("routes.txt", "route_id,agency_id,route_short_name,route_long_name,route_type\n\
+             A,x,A,,3\n"),
+            ("trips.txt", "route_id,service_id,trip_id\n\
+             A,week,ok\nA,never,idle\nA,holiday,back\nA,gone,dropped\n"),
+            ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             ok,08:00:00,08:00:00,s1,1\nok,08:05:00,08:05:00,s2,2\n\
+             idle,08:00:00,08:00:00,s1,1\nidle,08:05:00,08:05:00,s3,2\n\
+             back,08:00:00,08:00:00,s1,1\nback,07:55:00,07:56:00,s3,2\n\
+             dropped,08:00:00,08:00:00,s1,1\ndropped,08:05:00,08:05:00,s2,2\n")
+        ]);
+
+        let bbox = quadtree::BBox::new(-123.0, 37.0, -122.0, 38.0);
+        let report = validate(feed.path(), Some(&bbox)).unwrap();
+        assert_eq!((report.stops, report.trips), (5, 4));
+        assert_eq!(report.problems, vec![
+            Problem::UnreferencedStop { stop_id: "hall".to_string() },
+            Problem::UnreferencedStop { stop_id: "lonely".to_string() },
+            Problem::MissingCoordinates { stop_id: "s2".to_string() },
+            Problem::OutOfBounds { stop_id: "s3".to_string(), lat: 0.0, lon: 0.0 },
+            Problem::BackwardsTime { trip_id: "back".to_string(), stop_sequence: 2 },
+            Problem::NoServiceDays { trip_id: "dropped".to_string(),
+                                     service_id: "gone".to_string() },
+            Problem::NoServiceDays { trip_id: "idle".to_string(),
+                                     service_id: "never".to_string() }
+        ]);
+        assert_eq!(report.summary["unreferenced_stop"], 2);
+        assert!(!report.is_ok());
+
+        // Without a map we can't tell
+        let report = validate(feed.path(), None).unwrap();
+        assert_eq!(report.summary.get("out_of_bounds"), None);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde::Deserialize;
+
+use super::Error;
+
+// A bike share station. How many bikes and free docks it has only comes
+// from GBFS, OSM doesn't tell.
+#[derive(Debug, Clone)]
+pub struct Dock {
+    pub id: String,
+    pub name: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    pub bikes: Option<u32>, // we can rent
+    pub docks: Option<u32>  // free to leave one
+}
+
+impl Dock {
+    // Unknown counts are trusted, like OSM docks
+    pub fn rents(&self) -> bool {
+        self.bikes != Some(0)
+    }
+
+    pub fn returns(&self) -> bool {
+        self.docks != Some(0)
+    }
+}
+
+// station_information.json and station_status.json look alike, we take
+// what's there from either
+#[derive(Debug, Deserialize)]
+struct Feed {
+    data: Data
+}
+
+#[derive(Debug, Deserialize)]
+struct Data {
+    #[serde(default)]
+    stations: Vec<Station>
+}
+
+#[derive(Debug, Deserialize)]
+struct Station {
+    station_id: String,
+    name: Option<Name>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    num_bikes_available: Option<u32>,
+    num_docks_available: Option<u32>
+}
+
+// GBFS 3 has names by language
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Name {
+    Plain(String),
+    Localized(Vec<Text>)
+}
+
+#[derive(Debug, Deserialize)]
+struct Text {
+    text: String
+}
+
+impl Name {
+    fn text(self) -> Option<String> {
+        match self {
+            Name::Plain(name) => Some(name),
+            Name::Localized(texts) => texts.into_iter().next().map(|t| t.text)
+        }
+    }
+}
+
+// Snapshot files of GBFS feeds, merged by station. Stations we only have
+// a status for have no position, they're dropped.
+pub fn load(files: &[String]) -> Result<Vec<Dock>, Error> {
+    let mut stations: HashMap<String, Station> = HashMap::new();
+    for file in files {
+        let feed: Feed = fs::read_to_string(file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content)
+                      .map_err(|e| e.to_string()))
+            .map_err(|why| Error::Gbfs(file.to_string(), why))?;
+
+        for station in feed.data.stations {
+            match stations.get_mut(&station.station_id) {
+                Some(known) => {
+                    known.name = known.name.take().or(station.name);
+                    known.lat = known.lat.or(station.lat);
+                    known.lon = known.lon.or(station.lon);
+                    known.num_bikes_available = known.num_bikes_available
+                        .or(station.num_bikes_available);
+                    known.num_docks_available = known.num_docks_available
+                        .or(station.num_docks_available);
+                }
+                None => {
+                    stations.insert(station.station_id.to_string(), station);
+                }
+            }
+        }
+    }
+
+    Ok(stations.into_iter()
+        .filter_map(|(id, station)| Some(Dock {
+            id: id,
+            lat: station.lat?,
+            lon: station.lon?,
+            name: station.name.and_then(Name::text),
+            bikes: station.num_bikes_available,
+            docks: station.num_docks_available
+        }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    // Writes the feeds in a directory of their own, gone after the test
+    fn load(test: &str, feeds: &[(&str, &str)]) -> Vec<super::Dock> {
+        let dir = env::temp_dir().join(format!("gbfs-{}-{}", test,
+                                               std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = feeds.iter().map(|(name, content)| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            path.to_string_lossy().to_string()
+        }).collect::<Vec<_>>();
+        let docks = super::load(&files);
+        fs::remove_dir_all(&dir).unwrap();
+        let mut docks = docks.unwrap();
+        docks.sort_by(|a, b| a.id.cmp(&b.id));
+        docks
+    }
+
+    #[test]
+    fn names() {
+        let docks = load("names", &[("station_information.json", r#"{"data": {"stations": [
+            {"station_id": "a", "name": "Plain", "lat": 37.7, "lon": -122.4},
+            {"station_id": "b", "name": [{"text": "Localized", "language": "en"},
+                                         {"text": "Localisée", "language": "fr"}],
+             "lat": 37.8, "lon": -122.4},
+            {"station_id": "c", "lat": 37.9, "lon": -122.4}
+        ]}}"#)]);
+        let names = docks.iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec![Some("Plain".to_string()),
+                               Some("Localized".to_string()), None]);
+    }
+
+    #[test]
+    fn merge() {
+        let docks = load("merge", &[
+            ("station_information.json", r#"{"data": {"stations": [
+                {"station_id": "a", "name": "A", "lat": 37.7, "lon": -122.4},
+                {"station_id": "b", "name": "B", "lat": 37.8, "lon": -122.4}
+            ]}}"#),
+            ("station_status.json", r#"{"data": {"stations": [
+                {"station_id": "a", "num_bikes_available": 0,
+                 "num_docks_available": 5},
+                {"station_id": "ghost", "num_bikes_available": 3}
+            ]}}"#)]);
+        // Status without information has no position, it's dropped
+        assert_eq!(docks.len(), 2);
+        let (a, b) = (&docks[0], &docks[1]);
+        assert_eq!(a.id, "a");
+        assert_eq!((a.bikes, a.docks), (Some(0), Some(5)));
+        assert!(!a.rents() && a.returns());
+        assert_eq!(b.id, "b");
+        assert_eq!((b.bikes, b.docks), (None, None));
+        assert!(b.rents() && b.returns());
+    }
+
+    #[test]
+    fn bad_feed() {
+        let dir = env::temp_dir();
+        let file = dir.join(format!("gbfs-bad-{}.json", std::process::id()));
+        fs::write(&file, "{}").unwrap();
+        let res = super::load(&[file.to_string_lossy().to_string()]);
+        fs::remove_file(&file).unwrap();
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use quadtree;
+use quadtree::rtree::{RTree, Segment};
+
+use super::Edge;
+
+// A piece of way between two consecutive nodes, part of the graph edge
+// between a and b
+#[derive(Debug, Clone, Copy)]
+pub struct EdgeRef {
+    pub way: i64,
+    pub a: i64,
+    pub b: i64,
+    pub index: u32,  // of the segment along the edge
+    pub from_a: u64, // meters from a to the segment
+    pub edge: Edge
+}
+
+// Spatial index of graph edges, so we can find ways around a point
+// instead of the vertices
+#[derive(Debug)]
+pub struct EdgeIndex {
+    by_pos: RTree<Segment<EdgeRef>>
+}
+
+impl EdgeIndex {
+    pub fn new(segments: Vec<Segment<EdgeRef>>) -> Self {
+        EdgeIndex {
+            by_pos: RTree::bulk_load(segments)
+        }
+    }
+
+    pub fn in_bbox(&self, bbox: quadtree::BBox)
+        -> impl Iterator<Item=&Segment<EdgeRef>> {
+        self.by_pos.intersecting(bbox)
+    }
+
+    // Closest edges first, with their distance in meters
+    pub fn within<'a>(&'a self, lat: f64, lon: f64, dist: u64)
+        -> impl Iterator<Item=(&'a Segment<EdgeRef>, u64)> + 'a {
+        self.by_pos.within_radius(lon, lat, dist as f64)
+            .map(|(seg, dist)| (seg, dist as u64))
+    }
+}
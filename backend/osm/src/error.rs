@@ -0,0 +1,55 @@
+use std::error;
+use std::fmt;
+
+use gtfs;
+
+// What can go wrong when asking the Db something
+#[derive(Debug)]
+pub enum Error {
+    UnknownNode(i64),
+    NoGraphNode { lat: f64, lon: f64 }, // nothing routable close enough
+    UnknownPoiKind(String),
+    Unreachable { from: i64, to: i64 },
+    Inaccessible { from: i64, to: i64, issues: Vec<String> }, // by foot only
+    UnknownProfile(String),
+    UnknownStop(String),
+    UnknownMode(String),
+    EmptyMap, // no nodes in the PBF files
+    Gtfs(String, gtfs::LoadError), // file, why
+    Gbfs(String, String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownNode(id) => write!(f, "unknown node {}", id),
+            Error::NoGraphNode { lat, lon } =>
+                write!(f, "no routable way near {},{}", lat, lon),
+            Error::UnknownPoiKind(kind) =>
+                write!(f, "unknown POI kind {}", kind),
+            Error::Unreachable { from, to } =>
+                write!(f, "can't reach {} from {}", to, from),
+            Error::Inaccessible { from, to, issues } =>
+                write!(f, "can't reach {} from {} in a wheelchair: {}",
+                       to, from, issues.join(", ")),
+            Error::UnknownProfile(profile) =>
+                write!(f, "unknown profile {}", profile),
+            Error::UnknownStop(id) => write!(f, "unknown stop {}", id),
+            Error::UnknownMode(mode) => write!(f, "unknown mode {}", mode),
+            Error::EmptyMap => write!(f, "no nodes in the map"),
+            Error::Gtfs(file, err) =>
+                write!(f, "can't load GTFS {}: {}", file, err),
+            Error::Gbfs(file, why) =>
+                write!(f, "can't load GBFS {}: {}", file, why)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Gtfs(_, err) => Some(err),
+            _ => None
+        }
+    }
+}
@@ -2,25 +2,35 @@
 extern crate log;
 extern crate env_logger;
 extern crate pbf_reader;
+extern crate rayon;
 
 use pbf_reader::PBFData;
+use rayon::prelude::*;
 
 use std::sync::mpsc;
 use std::fs::File;
 use std::io::BufReader;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::{Hash, Hasher};
 
-use gtfs::{TransitMap, Stop};
+use gtfs::{Journey, Leg, NaiveDate, TransitMap, Stop};
 
 use serde::Deserialize;
 
 pub mod walkers;
+pub mod bikeshare;
 mod node_index;
+mod edge_index;
 mod poi;
+mod profile;
+mod error;
+
+pub use profile::{Issue, Profile};
+pub use error::Error;
+pub use bikeshare::Dock;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Tag {
@@ -58,7 +68,9 @@ pub struct Node {
     pub id: i64,
     pub lat: f64,
     pub lon: f64,
-    tags: Vec<Tag>
+    tags: Vec<Tag>,
+    #[serde(skip)]
+    idx: u32 // dense index in the Db, for the graph
 }
 
 impl Eq for Node {}
@@ -74,7 +86,16 @@ impl Hash for Node {
     }
 }
 
+fn to_tags(tags: &[(&str, &str)]) -> Vec<Tag> {
+    tags.iter().map(|(k, v)| Tag { k: k.to_string(), v: v.to_string() })
+        .collect()
+}
+
 impl Node {
+    pub fn new(id: i64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> Self {
+        Node { id: id, lat: lat, lon: lon, tags: to_tags(tags), idx: 0 }
+    }
+
     // Returns the distance in meters
     pub fn distance_from(&self, lat: f64, lon: f64) -> u64 {
         let phi1 = self.lat.to_radians();
@@ -147,6 +168,10 @@ pub struct Way {
 }
 
 impl Way {
+    pub fn new(id: i64, nodes: Vec<i64>, tags: &[(&str, &str)]) -> Self {
+        Way { id: id, nodes: nodes, tags: to_tags(tags) }
+    }
+
     pub fn name(&self) -> Option<&String> {
         self.tags.get_key("name")
     }
@@ -162,6 +187,45 @@ impl Way {
             || self.tags.has_key("cycleway:right")
     }
 
+    // Where we can ride a bike, footways only if they say so
+    pub fn is_bikeable(&self) -> bool {
+        match self.tags.get_key("bicycle").map(|v| v.as_str()) {
+            Some("no") | Some("dismount") => return false,
+            Some(_) => return self.is_highway(),
+            None => {}
+        }
+        match self.tags.get_key("highway").map(|v| v.as_str()) {
+            Some("footway") | Some("steps") | Some("pedestrian")
+                | Some("corridor") | Some("motorway") | Some("motorway_link")
+                | Some("trunk") | Some("trunk_link") => false,
+            Some(_) => true,
+            None => false
+        }
+    }
+
+    // Where cars go, private roads aside
+    pub fn is_drivable(&self) -> bool {
+        let closed = |k: &str| self.tags.get_key(k)
+            .map_or(false, |v| v == "no" || v == "private");
+        if closed("access") || closed("motor_vehicle") {
+            return false;
+        }
+        match self.tags.get_key("highway").map(|v| v.as_str()) {
+            Some("motorway") | Some("trunk") | Some("primary")
+                | Some("secondary") | Some("tertiary") | Some("unclassified")
+                | Some("residential") | Some("service")
+                | Some("living_street") | Some("road") => true,
+            Some(link) => link.ends_with("_link"),
+            None => false
+        }
+    }
+
+    // Some one way streets let bikes in both ways
+    pub fn is_oneway_for_bikes(&self) -> bool {
+        self.is_oneway() && self.tags.get_key("oneway:bicycle")
+            .map_or(true, |v| v != "no")
+    }
+
     // TODO: extend "way_ok(nodeA, nodeB)?
     pub fn is_oneway(&self) -> bool {
         match self.tags.get_key("oneway") {
@@ -205,11 +269,86 @@ pub struct BoundingBox {
     maxlon: f64
 }
 
-type Adjacency = (i64, u64, i64);
+// A lat/lon projected on the closest edge a-b of a way: a virtual node
+// splitting that edge for a single query. Walkers are given the snaps to
+// route through, the Db itself is never touched.
+#[derive(Debug)]
+pub struct Snap {
+    pub node: Node,
+    pub way: i64,
+    pub a: i64,
+    pub b: i64,
+    edges: [Edge; 4], // to a, to b, from a, from b
+    along: Vec<(i64, Edge)> // straight to other snaps on the same edge
+}
+
+impl Snap {
+    // Two snaps splitting the same edge can't reach each other through
+    // its ends, link them with the piece of edge in between
+    pub fn link(a: &mut Snap, b: &mut Snap) {
+        let (from_a, to_b) = (a.edges[2], a.edges[1]);
+        let same_edge = a.a == b.a && a.b == b.b
+            && from_a.shape == b.edges[2].shape
+            && to_b.shape + to_b.points == b.edges[1].shape + b.edges[1].points;
+        if !same_edge || a.node.id == b.node.id {
+            return;
+        }
+
+        // Shape points are split by index along the edge
+        let (first, second) = match from_a.dist <= b.edges[2].dist {
+            true => (&*a, &*b),
+            false => (&*b, &*a)
+        };
+        let (first_a, second_a) = (first.edges[2], second.edges[2]);
+        let between = Edge {
+            dist: second_a.dist - first_a.dist,
+            shape: first_a.shape + first_a.points,
+            points: second_a.points - first_a.points,
+            forward: true,
+            ..first_a
+        };
+        let (first, second) = (first.node.id, second.node.id);
+        for snap in [a, b] {
+            snap.along.retain(|(id, _)| *id != first && *id != second);
+            match snap.node.id == first {
+                true => snap.along.push((second, between)),
+                false => snap.along.push((first, between.reversed()))
+            }
+        }
+    }
+}
+
+// An edge of the graph: a piece of way between two nodes we kept, going
+// through the shape points of the nodes contracted in between
+#[derive(Debug, Clone, Copy)]
+pub struct Edge {
+    way: u32,
+    dist: u64,
+    shape: u32,   // first point in Db.shapes
+    points: u32,
+    forward: bool // walked in the order of the shape
+}
+
+impl Edge {
+    fn reversed(&self) -> Self {
+        Edge { forward: !self.forward, ..*self }
+    }
+
+    pub fn dist(&self) -> u64 {
+        self.dist
+    }
+}
+
+// Edges while we build the graph, both directions, by node index
 #[derive(Debug)]
-struct AdjacencyMap(HashMap<i64, Vec<Adjacency>>);
+struct Adjacencies {
+    edges: Vec<(u32, u32, Edge)>,
+    nodes: usize
+}
 
+#[derive(Debug, Clone)]
 pub struct SquareScore {
+    pub hash: String, // geohash of the square
     pub left: f64,
     pub right: f64,
     pub top: f64,
@@ -217,202 +356,646 @@ pub struct SquareScore {
     pub scores: HashMap<String, u64>
 }
 
+// What we keep of a PBF file
+#[derive(Default)]
+struct Extract {
+    nodes: HashMap<i64, Node>,
+    ways: HashMap<i64, Way>,
+    used: HashSet<i64>, // nodes of the routable ways
+    bbox: Option<quadtree::BBox>
+}
+
+// Peak resident memory so far, linux only
+fn peak_memory() -> String {
+    std::fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| status.lines()
+                  .find(|line| line.starts_with("VmHWM:"))
+                  .map(|line| line["VmHWM:".len()..].trim().to_string()))
+        .unwrap_or("unknown".to_string())
+}
+
+fn union(a: &quadtree::BBox, b: &quadtree::BBox) -> quadtree::BBox {
+    quadtree::BBox::new(a.min_x.min(b.min_x), a.min_y.min(b.min_y),
+                        a.max_x.max(b.max_x), a.max_y.max(b.max_y))
+}
+
+// String tables of a PBF file by id, ids are per file. A block can come
+// before its table: it waits for it.
+struct StringTables<B> {
+    strings: HashMap<u64, Vec<String>>,
+    waiting: Vec<(u64, B)>
+}
+
+impl<B> StringTables<B> {
+    fn new() -> Self {
+        StringTables {
+            strings: HashMap::new(),
+            waiting: Vec::new()
+        }
+    }
+
+    // The blocks that were waiting for this table
+    fn insert(&mut self, id: u64, strings: Vec<String>) -> Vec<B> {
+        self.strings.insert(id, strings);
+        let (ready, waiting) = self.waiting.drain(..)
+            .partition::<Vec<_>, _>(|(table, _)| *table == id);
+        self.waiting = waiting;
+        ready.into_iter().map(|(_, block)| block).collect()
+    }
+
+    fn get(&self, id: u64) -> Option<&Vec<String>> {
+        self.strings.get(&id)
+    }
+
+    fn wait(&mut self, id: u64, block: B) {
+        self.waiting.push((id, block));
+    }
+
+    fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+}
+
+// What the header of a PBF file says it covers
+fn header_bbox(data: &PBFData) -> Option<quadtree::BBox> {
+    match data {
+        PBFData::PbfInfo(inf) => Some(quadtree::BBox::new(
+                inf.bbox.top_left.lon, inf.bbox.bottom_right.lat,
+                inf.bbox.bottom_right.lon, inf.bbox.top_left.lat)),
+        _ => None
+    }
+}
+
+// Meters per second, to and from transit
+const WALK_SPEED: f64 = 1.2;
+const BIKE_SPEED: f64 = 4.0;
+const DRIVE_SPEED: f64 = 8.0; // in town
+
+// How fast we get to a node, see Db::transit_reach
+#[derive(Debug, Clone)]
+pub struct Reach {
+    pub time: u32,         // seconds since we left
+    pub rides: usize,      // none if we walked all the way
+    pub stop: Option<Stop> // where we got off
+}
+
+// Where a park and ride journey leaves the car, see Db::park_and_ride
+#[derive(Debug, Clone)]
+pub struct Parked<'a> {
+    pub at: &'a Node,          // the parking
+    pub drive: u32,            // seconds driving there
+    pub shape: Vec<(f64, f64)> // lon/lat of the drive
+}
+
 pub struct Db {
-    nodes: HashMap<i64, Node>, // node_id to <nodes> index
-    ways: HashMap<i64, Way>,  // way_id to <ways> index
-    adjacencies: AdjacencyMap,
+    nodes: Vec<Node>, // by Node.idx
+    node_ids: HashMap<i64, u32>, // node_id to <nodes> index
+    ways: Vec<Way>,
+    way_ids: HashMap<i64, u32>,  // way_id to <ways> index
+    graph: graph::Csr<Edge>,
+    shapes: Vec<(f64, f64)>, // lon/lat of the nodes contracted in edges
+    routable: HashMap<Profile, Vec<bool>>, // by Node.idx
     transit: TransitMap,
+    access: HashMap<u32, Vec<Stop>>, // station entrances, by Node.idx
+    docks: HashMap<u32, Vec<Dock>>,  // bike share, by Node.idx
+    parkings: HashMap<u32, u32>,     // parking node idx, by Node.idx
+    switch: Edge, // to stay on a node while changing how we move
     pub node_index: node_index::NodeIndex,
-    pub scores: HashMap<u64, SquareScore>,
+    pub edge_index: edge_index::EdgeIndex,
+    scores: BTreeMap<String, SquareScore>, // by geohash, sorted for prefixes
+    options: Options
 }
 
-impl AdjacencyMap {
-    fn connect(&mut self, a: i64, b: i64, way: i64, dist: u64) {
-        self.0.entry(a)
-            .or_insert(Vec::new())
-            .push((way, dist, b));
-        self.0.entry(b)
-            .or_insert(Vec::new())
-            .push((way, dist, a));
+impl Adjacencies {
+    fn new(nodes: usize) -> Self {
+        Adjacencies {
+            edges: Vec::new(),
+            nodes: nodes
+        }
+    }
+
+    fn connect(&mut self, a: u32, b: u32, edge: Edge) {
+        self.edges.push((a, b, edge));
+        self.edges.push((b, a, edge.reversed()));
     }
-    fn get(&self, key: &i64) -> Option<&Vec<Adjacency>> {
-        self.0.get(key)
+
+    fn into_graph(self) -> graph::Csr<Edge> {
+        graph::Csr::new(self.nodes, self.edges)
     }
 }
 
-impl Db {
-    pub fn new(filename: &'static str, gtfs: Vec<&str>) -> Self {
-        info!("Loading {}", filename);
+// How a Db is built and queried
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub scores: bool,           // precompute POI score squares at boot
+    pub score_precision: usize, // geohash length of the score squares
+    pub score_max_cost: u64,    // how far we walk from each square
+    pub snap_distance: u64,     // how far from a point we look for the graph
+    pub connect_distance: u64,  // how far from a POI we look for the graph
+    pub min_component: usize,   // smaller graph islands are not routable
+    pub realtime_interval: u64, // seconds between GTFS-Realtime reloads
+    pub transit_walk: u64,      // how far we walk to and from transit
+    pub max_rides: usize,       // in a journey, transfers are one less
+    pub bike_unlock: u64,       // seconds to get a bike out of its dock
+    pub park_time: u64,         // seconds to park and get out of the car
+    pub max_drive: u64          // seconds we drive to park and ride
+}
 
-        let (mut node_tx, node_rx) = mpsc::channel::<PBFData>();
-        let h = thread::spawn(move || {
-            return pbf_reader::read_pbf(&filename.to_string(), 9, &mut node_tx);
-        });
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            scores: true,
+            score_precision: 6,
+            score_max_cost: 1500,
+            snap_distance: 150,
+            connect_distance: 35,
+            min_component: 1000,
+            realtime_interval: 30,
+            transit_walk: 800,
+            max_rides: 4,
+            bike_unlock: 60,
+            park_time: 300,
+            max_drive: 1800
+        }
+    }
+}
 
-        let mut index : Option<node_index::NodeIndex> = None;
+impl Db {
+    // Several extracts can be given, nodes and ways they share are only
+    // kept once. Realtime feeds apply to the trips of all GTFS feeds.
+    // Bike docks come from GBFS snapshots if any, OSM otherwise.
+    pub fn new(pbfs: &[String], gtfs: &[String], realtime: &[String],
+               gbfs: &[String], options: Options)
+        -> Result<Self, Error> {
+        let start = Instant::now();
+        let mut nodes = HashMap::new();
+        let mut ways = HashMap::new();
+        let mut used = HashSet::new();
+        let mut bbox: Option<quadtree::BBox> = None;
+
+        // Extracts are read side by side, merged in the given order. First
+        // the ways, so we know which nodes are worth keeping.
+        let extracts = pbfs.par_iter()
+            .map(|filename| (filename, Self::read_pbf(filename, None)))
+            .collect::<Vec<_>>();
+
+        for (filename, extract) in extracts {
+            let mut merged = 0;
+            for (id, way) in extract.ways {
+                merged += ways.insert(id, way).map(|_| 1).unwrap_or(0);
+            }
+            if merged > 0 {
+                info!("{} ways of {} were already loaded", merged, filename);
+            }
+            used.extend(extract.used);
 
-        // Find a better use of this later as this is a good memory saver
-        let mut pbf_ways = HashMap::new();
-        let mut pbf_nodes = HashMap::new();
-        let mut pbf_strings = HashMap::new();
+            bbox = match (bbox, extract.bbox) {
+                (Some(a), Some(b)) => Some(union(&a, &b)),
+                (a, b) => a.or(b)
+            };
+        }
+        info!("{} routable ways use {} nodes, peak memory {}", ways.len(),
+              used.len(), peak_memory());
 
-        loop {
-            match node_rx.recv().unwrap() {
-                PBFData::NodesSet(set) => {
-                    for (id, node) in set {
-                        pbf_nodes.insert(id, node);
-                    }
-                }
-                PBFData::WaysSet(set) => {
-                    for (id, way) in set {
-                        pbf_ways.insert(id, way);
-                    }
-                }
-                PBFData::RelationsSet(_) => { }
-                PBFData::Strings(id, string) => {
-                    pbf_strings.insert(id, string);
-                }
-                PBFData::PbfInfo(inf) => {
-                    if !index.is_some() {
-                        debug!("Setting BBOX BL to TR x,y = {},{} {},{}",
-                               inf.bbox.top_left.lon, inf.bbox.bottom_right.lat,
-                               inf.bbox.bottom_right.lon, inf.bbox.top_left.lat);
-                        index = Some(node_index::NodeIndex::new(
-                                inf.bbox.top_left.lon, inf.bbox.bottom_right.lat,
-                               inf.bbox.bottom_right.lon, inf.bbox.top_left.lat));
-                    }
-                }
-                PBFData::ParseEnd => {
-                                break;
-                }
+        let extracts = pbfs.par_iter()
+            .map(|filename| (filename, Self::read_pbf(filename, Some(&used))))
+            .collect::<Vec<_>>();
+
+        for (filename, extract) in extracts {
+            let mut merged = 0;
+            for (id, node) in extract.nodes {
+                merged += nodes.insert(id, node).map(|_| 1).unwrap_or(0);
+            }
+            if merged > 0 {
+                info!("{} nodes of {} were already loaded", merged, filename);
             }
         }
+        info!("Read {} nodes and {} ways in {:?}, peak memory {}",
+              nodes.len(), ways.len(), start.elapsed(), peak_memory());
 
-        let r = h.join().unwrap();
-
-        let mut db = Db {
-            nodes: HashMap::new(),
-            ways: HashMap::new(),
-            adjacencies: AdjacencyMap(HashMap::new()),
-            node_index: index.unwrap(),
-            scores: HashMap::new(),
-            transit: TransitMap::new()
+        // Many extracts have no bbox in their header, the nodes do
+        let bbox = match bbox.or_else(|| Self::nodes_bbox(nodes.values())) {
+            Some(bbox) => bbox,
+            None => return Err(Error::EmptyMap)
         };
+        debug!("Setting BBOX BL to TR x,y = {},{} {},{}",
+               bbox.min_x, bbox.min_y, bbox.max_x, bbox.max_y);
+
+        let nodes = nodes.into_iter().map(|(_, node)| node).collect();
+        let ways = ways.into_iter().map(|(_, way)| way).collect();
+        let mut db = Self::of_elements(nodes, ways, bbox, options);
 
         // Load GTFS before we index/prewalk anything
         for file in gtfs {
             info!("Loading GTFS {}", &file);
-            db.transit.load(&file);
+            let stops = db.transit.load(&file)
+                .map_err(|err| Error::Gtfs(file.to_string(), err))?;
+            info!("Loaded {} stops from {}", stops, &file);
+        }
+        // Schedules still work without realtime, it may come back later
+        for source in realtime {
+            info!("Following GTFS-Realtime {}", source);
+            if let Err(err) = db.transit.follow(source, Duration::from_secs(
+                    db.options.realtime_interval)) {
+                warn!("Can't load {} yet: {}", source, err);
+            }
         }
+        let docks = if gbfs.is_empty() {
+            None
+        } else {
+            let docks = bikeshare::load(gbfs)?;
+            info!("Loaded {} bike docks from GBFS", docks.len());
+            Some(docks)
+        };
+
+        db.build(docks);
+        info!("Db ready in {:?}, peak memory {}", start.elapsed(),
+              peak_memory());
+        Ok(db)
+    }
 
+    // A Db of the given nodes and ways alone, without transit: bike
+    // docks come from the nodes, as they would from a PBF file
+    pub fn from_elements(nodes: Vec<Node>, ways: Vec<Way>, options: Options)
+        -> Result<Self, Error> {
+        let bbox = Self::nodes_bbox(nodes.iter()).ok_or(Error::EmptyMap)?;
+        let mut db = Self::of_elements(nodes, ways, bbox, options);
+        db.build(None);
+        Ok(db)
+    }
+
+    fn of_elements(mut nodes: Vec<Node>, mut ways: Vec<Way>,
+                   bbox: quadtree::BBox, options: Options) -> Self {
         // Artifical way for poi connections
-        db.ways.insert(1, Way{
+        ways.retain(|way| way.id != 1);
+        ways.push(Way{
             id: 1,
             nodes: vec![],
             tags: vec![]
         });
 
-        pbf_ways.iter().for_each(|(id, way)| {
-            let strings = pbf_strings.get(&way.tags.string_table_id).unwrap();
-            db.ways.insert(*id, Way {
-                id: *id,
-                nodes: way.nodes.iter().map(|a| *a).collect(),
-                tags: way.tags.get_keys_vals(strings).iter()
-                    .map(|(k, v)| Tag{
-                        k: k.to_string(),
-                        v: v.to_string()
-                    })
-                    .collect()
-            });
-        });
+        // Dense indices from now on, sorted by id to stay stable
+        nodes.sort_by_key(|node| node.id);
+        for (idx, node) in nodes.iter_mut().enumerate() {
+            node.idx = idx as u32;
+        }
+        ways.sort_by_key(|way| way.id);
+
+        let mut db = Db {
+            node_ids: nodes.iter().map(|node| (node.id, node.idx)).collect(),
+            nodes: nodes,
+            way_ids: ways.iter().enumerate()
+                .map(|(idx, way)| (way.id, idx as u32)).collect(),
+            ways: ways,
+            graph: graph::Csr::empty(),
+            shapes: Vec::new(),
+            routable: HashMap::new(),
+            node_index: node_index::NodeIndex::new(bbox.min_x, bbox.min_y,
+                                                   bbox.max_x, bbox.max_y),
+            edge_index: edge_index::EdgeIndex::new(vec![]),
+            scores: BTreeMap::new(),
+            options: options,
+            transit: TransitMap::new(),
+            access: HashMap::new(),
+            docks: HashMap::new(),
+            parkings: HashMap::new(),
+            switch: Edge { way: 0, dist: 0, shape: 0, points: 0, forward: true }
+        };
+        db.switch.way = db.way_ids[&1];
+        db
+    }
 
-        pbf_nodes.iter().for_each(|(id, node)| {
-            let strings = pbf_strings.get(&node.tags.string_table_id).unwrap();
-            db.nodes.insert(*id, Node {
-                id: *id,
-                lat: node.coord.lat,
-                lon: node.coord.lon,
-                //XXX factor
-                tags: node.tags.get_keys_vals(strings).iter()
-                    .map(|(k, v)| Tag{
-                        k: k.to_string(),
-                        v: v.to_string()
-                    })
-                .collect()
+    // Everything past reading: the graph, what links to it, the scores
+    fn build(&mut self, docks: Option<Vec<Dock>>) {
+        info!("Indexing {} nodes", self.nodes.len());
+        self.index_nodes();
+        let uses = self.way_uses();
+        info!("Connecting nodes");
+        let connections = self.connect_nodes(&uses);
+        info!("Building graph");
+        self.graph = self.make_graph(&uses, &connections).into_graph();
+        info!("Graph has {} nodes, {} edges", self.graph.node_count(),
+              self.graph.edge_count());
+        self.mark_components();
+        self.link_stations();
+        let docks = docks.unwrap_or_else(|| self.osm_docks());
+        self.link_docks(docks);
+        self.link_parkings();
+
+        if self.options.scores {
+            info!("Preparing POI score map");
+            self.scores = self.score_squares();
+            info!("Done scoring {} squares", self.scores.len());
+        }
+    }
 
-            });
+    fn nodes_bbox<'n, I: Iterator<Item=&'n Node>>(nodes: I)
+        -> Option<quadtree::BBox> {
+        nodes.fold(None, |bbox: Option<quadtree::BBox>, node| {
+            let point = quadtree::BBox::new(node.lon, node.lat,
+                                            node.lon, node.lat);
+            Some(bbox.map_or(point, |bbox| union(&bbox, &point)))
+        })
+    }
+
+    // What PBF files cover, for checks that don't need the graph: the
+    // bbox of their header, files without one are left out. The header
+    // is the first block, we stop listening to the reader after it.
+    pub fn extent(pbfs: &[String]) -> Option<quadtree::BBox> {
+        pbfs.iter()
+            .filter_map(|filename| {
+                let (mut tx, rx) = mpsc::channel::<PBFData>();
+                let path = filename.to_string();
+                thread::spawn(move || pbf_reader::read_pbf(&path, 1, &mut tx));
+                rx.recv().ok().as_ref().and_then(header_bbox)
+            })
+            .fold(None, |extent: Option<quadtree::BBox>, bbox|
+                  Some(extent.map_or(bbox, |extent| union(&extent, &bbox))))
+    }
+
+    // Stream a PBF file, converting blocks as they come in. Without used
+    // nodes this is the first pass: we keep routable ways and note the
+    // nodes they use. Nothing needs the other ways (buildings, areas),
+    // they're dropped. The second pass keeps these nodes and the tagged
+    // ones (POIs, addresses), dropping all other shape points.
+    fn read_pbf(filename: &str, used: Option<&HashSet<i64>>) -> Extract {
+        info!("Reading {} of {}", if used.is_some() { "nodes" } else { "ways" },
+              filename);
+
+        let (mut node_tx, node_rx) = mpsc::channel::<PBFData>();
+        let path = filename.to_string();
+        let h = thread::spawn(move || {
+            return pbf_reader::read_pbf(&path, 9, &mut node_tx);
         });
 
+        let mut extract = Extract::default();
 
-        info!("Building graph");
-        db.make_graph();
-        info!("Indexing {} nodes", db.nodes.len());
-        db.index_nodes();
-        info!("Connecting nodes");
-        db.connect_nodes();
-        info!("Preparing POI score map");
-
-        let mut scores = HashMap::new();
-        // Now for each square evaluate ~center dist from POIs
-        // by walking from the center and logging POIs as we see them
-        for cell in db.node_index.squares() {
-            if let Some(node) = db.initial_node(cell.y+cell.ysize/2.0,
-                                                cell.x+cell.xsize/2.0, 500) {
-                let walker = walkers::explore::Explore {
-                    max_cost: Some(1500),
-                    predicate: |x| x.is_poi(),
-                    target: None
-                };
-                let res = graph::walk(&db, walker, &node);
-                for step in &res.steps {
-                    step.to.poi_types().iter().for_each(|pt| {
-                        let cellmap = scores.entry(cell.id).or_insert(
-                            SquareScore {
-                                top: cell.y + cell.ysize,
-                                bottom: cell.y,
-                                left: cell.x,
-                                right: cell.x + cell.xsize,
-                                scores: HashMap::new()
+        let mut tables = StringTables::new();
+        let mut ready = VecDeque::new();
+
+        while let Ok(data) = node_rx.recv() {
+            match data {
+                PBFData::ParseEnd => break,
+                PBFData::RelationsSet(_) => { }
+                PBFData::Strings(id, strings) =>
+                    ready.extend(tables.insert(id, strings)),
+                PBFData::PbfInfo(_) => {
+                    if !extract.bbox.is_some() {
+                        extract.bbox = header_bbox(&data);
+                    }
+                }
+                data => ready.push_back(data)
+            }
+
+            while let Some(data) = ready.pop_front() {
+                match data {
+                    PBFData::WaysSet(set) if used.is_none() => {
+                        let table = match set.iter().next() {
+                            Some((_, way)) => way.tags.string_table_id,
+                            None => continue
+                        };
+                        let strings = match tables.get(table) {
+                            Some(strings) => strings,
+                            None => {
+                                tables.wait(table, PBFData::WaysSet(set));
+                                continue;
                             }
-                        );
-                        let dist = cellmap.scores.entry(pt.to_string())
-                            .or_insert(step.total);
-                        *dist = step.total.min(*dist);
-                    });
+                        };
+
+                        for (id, way) in set {
+                            let way = Way {
+                                id: id,
+                                nodes: way.nodes.iter().map(|a| *a).collect(),
+                                tags: way.tags.get_keys_vals(strings).iter()
+                                    .map(|(k, v)| Tag{
+                                        k: k.to_string(),
+                                        v: v.to_string()
+                                    })
+                                    .collect()
+                            };
+                            if way.is_highway() {
+                                extract.used.extend(way.nodes.iter());
+                                extract.ways.insert(id, way);
+                            }
+                        }
+                    }
+                    PBFData::NodesSet(set) if used.is_some() => {
+                        let table = match set.iter().next() {
+                            Some((_, node)) => node.tags.string_table_id,
+                            None => continue
+                        };
+                        let strings = match tables.get(table) {
+                            Some(strings) => strings,
+                            None => {
+                                tables.wait(table, PBFData::NodesSet(set));
+                                continue;
+                            }
+                        };
+
+                        let used = used.unwrap();
+                        for (id, node) in set {
+                            let node = Node {
+                                id: id,
+                                lat: node.coord.lat,
+                                lon: node.coord.lon,
+                                //XXX factor
+                                tags: node.tags.get_keys_vals(strings).iter()
+                                    .map(|(k, v)| Tag{
+                                        k: k.to_string(),
+                                        v: v.to_string()
+                                    })
+                                .collect(),
+                                idx: 0
+                            };
+                            if used.contains(&id) || !node.tags.is_empty() {
+                                extract.nodes.insert(id, node);
+                            }
+                        }
+                    }
+                    _ => { }
                 }
             }
         }
 
-        db.scores = scores;
-        db
+        let r = h.join().unwrap();
+
+        if tables.waiting() > 0 {
+            warn!("{} blocks of {} had no string table", tables.waiting(),
+                  filename);
+        }
+        extract
+    }
+
+    // Every square holding a routable node gets the walking distance from its
+    // center to the closest POI of each type. Squares are independent,
+    // they're walked in parallel.
+    fn score_squares(&self) -> BTreeMap<String, SquareScore> {
+        // Squares with only POIs or shape points have nowhere to walk from
+        let hashes = self.nodes.iter()
+            .filter(|node| self.graph.degree(node.idx) > 0
+                    && self.is_routable(node, Profile::Foot))
+            .map(|node| geohash::hash(node.lat, node.lon,
+                                      self.options.score_precision))
+            .collect::<HashSet<String>>();
+
+        hashes.into_par_iter().filter_map(|hash| {
+            let gbox = geohash::geobox_for_hash(&hash);
+            let (lat, lon) = ((gbox.y_range.min + gbox.y_range.max) / 2.0,
+                              (gbox.x_range.min + gbox.x_range.max) / 2.0);
+            let node = self.initial_node(lat, lon, 500, Profile::Foot)?;
+            let walker = walkers::explore::Explore {
+                max_cost: Some(self.options.score_max_cost),
+                predicate: |x| x.is_poi(),
+                target: None,
+                profile: Profile::Foot,
+                via: vec![]
+            };
+            let res = graph::walk(self, walker, &node);
+            let mut square = SquareScore {
+                hash: hash.to_string(),
+                top: gbox.y_range.max,
+                bottom: gbox.y_range.min,
+                left: gbox.x_range.min,
+                right: gbox.x_range.max,
+                scores: HashMap::new()
+            };
+            for step in res.steps() {
+                step.to.poi_types().iter().for_each(|pt| {
+                    let dist = square.scores.entry(pt.to_string())
+                        .or_insert(step.total);
+                    *dist = step.total.min(*dist);
+                });
+            }
+            Some((hash, square))
+        }).collect()
+    }
+
+    // Scored squares with their center within radius meters of lat/lon,
+    // looked up by geohash prefixes of the circle
+    pub fn squares_within(&self, lat: f64, lon: f64, radius: u64)
+        -> Vec<&SquareScore> {
+        let prefixes = geohash::cover_circle(lat, lon, radius as f64)
+            .into_iter()
+            .map(|h| h.chars().take(self.options.score_precision)
+                 .collect())
+            .collect::<HashSet<String>>();
+
+        prefixes.iter()
+            .flat_map(|prefix| self.scores.range(prefix.to_string()..)
+                      .take_while(move |(h, _)| h.starts_with(prefix.as_str())))
+            .map(|(_, square)| square)
+            .filter(|square| quadtree::distance(
+                (square.left + square.right) / 2.0,
+                (square.top + square.bottom) / 2.0, lon, lat) <= radius as f64)
+            .collect()
+    }
+
+    // The square hash for lat/lon - we can't go finer than what we scored
+    pub fn square_hash(&self, lat: f64, lon: f64, precision: usize) -> String {
+        geohash::hash(lat, lon,
+                      precision.max(1).min(self.options.score_precision))
     }
 
-    pub fn connect_nodes(&mut self) {
-        let mut connected = 0;
-        for node in self.nodes.values() {
+    // Score of a square. Shorter hashes are coarser squares: they get the
+    // best distance of the scored squares they contain.
+    pub fn square(&self, hash: &str) -> Option<SquareScore> {
+        let precision = self.options.score_precision;
+        if hash.len() >= precision {
+            return self.scores.get(&hash[..precision]).cloned();
+        }
+
+        let gbox = geohash::geobox_for_hash(hash);
+        self.scores.range(hash.to_string()..)
+            .take_while(|(h, _)| h.starts_with(hash))
+            .map(|(_, square)| square)
+            .fold(None, |acc: Option<SquareScore>, square| {
+                let mut acc = acc.unwrap_or(SquareScore {
+                    hash: hash.to_string(),
+                    top: gbox.y_range.max,
+                    bottom: gbox.y_range.min,
+                    left: gbox.x_range.min,
+                    right: gbox.x_range.max,
+                    scores: HashMap::new()
+                });
+                for (poi, dist) in &square.scores {
+                    let best = acc.scores.entry(poi.to_string())
+                        .or_insert(*dist);
+                    *best = (*dist).min(*best);
+                }
+                Some(acc)
+            })
+    }
+
+    // How many times routable ways go through each node
+    fn way_uses(&self) -> Vec<u8> {
+        let mut uses = vec![0u8; self.nodes.len()];
+        // Not the artificial way 1, it has no nodes
+        for way in self.ways.iter().filter(|way| way.is_highway() && way.id != 1) {
+            for node in way.nodes.iter().filter_map(|id| self.node_by_id(*id)) {
+                let n = &mut uses[node.idx as usize];
+                *n = n.saturating_add(1);
+            }
+        }
+        uses
+    }
+
+    // POIs and named nodes are linked to the closest node of a way
+    fn connect_nodes(&self, uses: &[u8]) -> Vec<(u32, u32)> {
+        let mut connections = Vec::new();
+        for node in self.nodes.iter() {
             if node.is_poi() || node.name().is_some() {
-                connected += 1;
-                let gnode = self.node_index.around(node.lat, node.lon)
-                    .filter(|id| self.adjacencies.get(&id).is_some())
+                let gnode = self.node_index.within(node.lat, node.lon,
+                                                   self.options.connect_distance)
                     .filter_map(|id| self.node_by_id(id))
-                    .find(|n| n.distance_from(node.lat, node.lon) < 35)
-                    .map(|n| n.id);
+                    .find(|b| b.idx != node.idx && uses[b.idx as usize] > 0);
 
                 match gnode {
                     Some(b) => {
-                        self.adjacencies.connect(node.id, b, 1, 1)
+                        connections.push((node.idx, b.idx))
                     },
                     None => {}
                 }
             }
         }
-        info!("Done connected {} nodes", connected);
+        info!("Done connected {} nodes", connections.len());
+        connections
     }
 
-    pub fn make_graph(&mut self) {
-        for way in self.ways.values() {
-            // Only connect walkable nodes
-            if !way.is_highway() {
+    // Nodes in the middle of a single way, with nothing special about
+    // them, are contracted: chains of them become one edge carrying their
+    // positions as its shape. Way ends and junctions stay, as do tagged
+    // nodes (crossings, kerbs), transit stops and where POIs connect.
+    fn make_graph(&mut self, uses: &[u8], connections: &[(u32, u32)])
+        -> Adjacencies {
+        let mut keep = self.nodes.iter()
+            .map(|node| uses[node.idx as usize] > 1
+                 || (uses[node.idx as usize] > 0
+                     && (!node.tags.is_empty()
+                         || self.transit.stops_within(node.lat, node.lon, 1.0)
+                            .next().is_some())))
+            .collect::<Vec<bool>>();
+        for (a, b) in connections {
+            keep[*a as usize] = true;
+            keep[*b as usize] = true;
+        }
+
+        let mut adjacencies = Adjacencies::new(self.nodes.len());
+        let poi_way = self.way_ids[&1];
+        for (a, b) in connections {
+            adjacencies.connect(*a, *b, Edge {
+                way: poi_way,
+                dist: 1,
+                shape: 0,
+                points: 0,
+                forward: true
+            });
+        }
+
+        let mut segments = Vec::new();
+        let mut shapes = Vec::new();
+        for (way_idx, way) in self.ways.iter().enumerate() {
+            // Only connect walkable nodes, POIs are connected above
+            if !way.is_highway() || way.id == 1 {
                 continue;
             }
 
@@ -421,81 +1004,737 @@ impl Db {
                 continue;
             }
 
-            for (a, b) in way.nodes.iter().zip(way.nodes[1..].iter()) {
-                if let Some(a) = self.node_by_id(*a) {
-                    if let Some(b) = self.node_by_id(*b) {
-                        let distance = a.distance(b);
-                        self.adjacencies.connect(a.id, b.id, way.id, distance)
+            // Ends of the runs of nodes we have
+            let nodes = way.nodes.iter()
+                .map(|id| self.node_by_id(*id))
+                .collect::<Vec<Option<&Node>>>();
+            for (i, node) in nodes.iter().enumerate() {
+                if let Some(node) = node {
+                    if i == 0 || i == nodes.len() - 1
+                        || nodes[i - 1].is_none() || nodes[i + 1].is_none() {
+                        keep[node.idx as usize] = true;
+                    }
+                }
+            }
+
+            // The chain from the last node we kept
+            let mut start: Option<&Node> = None;
+            let mut prev: Option<&Node> = None;
+            let mut dist = 0;
+            let mut shape = shapes.len() as u32;
+            let mut chain = Vec::new();
+
+            for node in nodes {
+                let node = match node {
+                    Some(node) => node,
+                    None => {
+                        start = None;
+                        prev = None;
+                        continue;
+                    }
+                };
+
+                if let (Some(a), Some(p)) = (start, prev) {
+                    chain.push((p, node, dist));
+                    dist += p.distance(node);
+
+                    if keep[node.idx as usize] {
+                        let edge = Edge {
+                            way: way_idx as u32,
+                            dist: dist,
+                            shape: shape,
+                            points: shapes.len() as u32 - shape,
+                            forward: true
+                        };
+                        adjacencies.connect(a.idx, node.idx, edge);
+                        for (i, (p, q, from_a)) in chain.drain(..).enumerate() {
+                            segments.push(quadtree::rtree::Segment::new(
+                                edge_index::EdgeRef {
+                                    way: way.id,
+                                    a: a.id,
+                                    b: node.id,
+                                    index: i as u32,
+                                    from_a: from_a,
+                                    edge: edge
+                                }, p.lon, p.lat, q.lon, q.lat));
+                        }
+                    } else {
+                        shapes.push((node.lon, node.lat));
                     }
                 }
+
+                if keep[node.idx as usize] {
+                    start = Some(node);
+                    dist = 0;
+                    shape = shapes.len() as u32;
+                }
+                prev = Some(node);
             }
         }
+
+        let contracted = (0..self.nodes.len())
+            .filter(|i| uses[*i] > 0 && !keep[*i])
+            .count();
+        info!("Contracted {} nodes, indexing {} edges", contracted,
+              segments.len());
+        self.shapes = shapes;
+        self.edge_index = edge_index::EdgeIndex::new(segments);
+        adjacencies
+    }
+
+    // Islands of the graph (private driveways, mistagged footways) would
+    // start walks that reach nothing. Per profile, only the largest
+    // component and the big enough ones stay routable.
+    fn mark_components(&mut self) {
+        for profile in Profile::all() {
+            let ways = &self.ways;
+            let components = graph::strongly_connected(&self.graph, |edge|
+                profile.allows(&ways[edge.way as usize]));
+
+            let count = components.iter().max().map(|c| *c as usize + 1)
+                .unwrap_or(0);
+            let mut sizes = vec![0; count];
+            for c in &components {
+                sizes[*c as usize] += 1;
+            }
+            let largest = (0..count).max_by_key(|c| sizes[*c]);
+            let keep = (0..count)
+                .map(|c| Some(c) == largest
+                     || sizes[c] >= self.options.min_component)
+                .collect::<Vec<bool>>();
+
+            let routable = components.iter()
+                .map(|c| keep[*c as usize])
+                .collect::<Vec<bool>>();
+
+            // Nodes without edges are components too, don't count them
+            let islands = (0..count).filter(|c| sizes[*c] > 1).count();
+            let kept = keep.iter().filter(|k| **k).count();
+            let dropped = routable.iter().enumerate()
+                .filter(|(i, r)| !**r && self.graph.degree(*i as u32) > 0)
+                .count();
+            info!("{:?}: kept {} of {} components, {} nodes unroutable, \
+                   largest has {} nodes", profile, kept, islands, dropped,
+                   largest.map(|c| sizes[c]).unwrap_or(0));
+
+            self.routable.insert(profile, routable);
+        }
+    }
+
+    // Virtual nodes are routable, their edge is
+    pub fn is_routable(&self, node: &Node, profile: Profile) -> bool {
+        self.routable.get(&profile)
+            .and_then(|routable| routable.get(node.idx as usize))
+            .cloned()
+            .unwrap_or(true)
     }
 
     pub fn index_nodes(&mut self) {
-        for node in self.nodes.values() {
+        for node in self.nodes.iter() {
             self.node_index.insert(&node);
         }
     }
 
-    pub fn distance(&self, a: i64, b: i64) -> u64 {
-        let a = self.node_by_id(a).unwrap();
-        let b = self.node_by_id(b).unwrap();
-        a.distance(b)
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    // The extent of the loaded map, x is lon and y lat
+    pub fn bbox(&self) -> quadtree::BBox {
+        self.node_index.bbox()
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.bbox().contains(lon, lat)
+    }
+
+    pub fn distance(&self, a: i64, b: i64) -> Result<u64, Error> {
+        Ok(self.node(a)?.distance(self.node(b)?))
     }
 
     pub fn node_by_id(&self, id: i64) -> Option<&Node> {
-        self.nodes.get(&id)
+        self.node_ids.get(&id).map(|idx| &self.nodes[*idx as usize])
+    }
+
+    pub fn node(&self, id: i64) -> Result<&Node, Error> {
+        self.node_by_id(id).ok_or(Error::UnknownNode(id))
     }
 
     pub fn way_by_id(&self, id: i64) -> Option<&Way> {
-        self.ways.get(&id)
-    }
-
-    //XXX just testing
-    pub fn transit_stop(&self, node: &Node) -> Option<&Stop> {
-        // Get all <5meters stops?
-        self.transit.get_stops(node.lat, node.lon).find(|stop|
-                node.distance_from(stop.get_lat(), stop.get_lon()) < 1)
-    }
-
-    //XXX todo faster/lighter would help
-    pub fn neighbors(&self, node: &Node) -> Vec<(&Way, u64, &Node)> {
-        match self.adjacencies.get(&node.id) {
-            Some(vec) => vec
-                .iter()
-                .map(|(way, dist, node)| {
-                    (
-                        self.way_by_id(*way).unwrap(),
-                        *dist,
-                        self.node_by_id(*node).unwrap(),
-                    )
+        self.way_ids.get(&id).map(|idx| &self.ways[*idx as usize])
+    }
+
+    // Riders get in stations by their entrances (or at stops out of any
+    // station): tie each to the closest routable node
+    fn link_stations(&mut self) {
+        let mut access = HashMap::new();
+        let mut unlinked = 0;
+        for stop in self.transit.access_points() {
+            match self.initial_node(stop.get_lat(), stop.get_lon(),
+                                    self.options.connect_distance,
+                                    Profile::Foot) {
+                Some(node) => access.entry(node.idx)
+                    .or_insert(vec![])
+                    .push(stop.clone()),
+                None => unlinked += 1
+            }
+        }
+        info!("Linked {} nodes to transit, {} entrances too far from the \
+               graph", access.len(), unlinked);
+        self.access = access;
+    }
+
+    fn osm_docks(&self) -> Vec<Dock> {
+        self.nodes.iter()
+            .filter(|node| node.tags.get_key("amenity")
+                    .map_or(false, |v| v == "bicycle_rental"))
+            .map(|node| Dock {
+                id: format!("osm:{}", node.id),
+                name: node.name(),
+                lat: node.lat,
+                lon: node.lon,
+                bikes: None,
+                docks: None
+            })
+            .collect()
+    }
+
+    // Ties each item at lat/lon to the closest node we can walk from and
+    // take a way passing takes from. Returns them by node index, and how
+    // many were too far from the graph.
+    fn link_nodes<T, I, F>(&self, items: I, takes: F)
+        -> (HashMap<u32, Vec<T>>, usize)
+        where I: Iterator<Item=(f64, f64, T)>, F: Fn(&Way) -> bool {
+        let mut linked = HashMap::new();
+        let mut unlinked = 0;
+        for (lat, lon, item) in items {
+            let node = self.node_index.within(lat, lon,
+                                              self.options.connect_distance)
+                .filter_map(|id| self.node_by_id(id))
+                .filter(|node| self.is_routable(node, Profile::Foot))
+                .find(|node| self.neighbors(node)
+                      .any(|(edge, _, _)| takes(self.way_of(edge))));
+            match node {
+                Some(node) => linked.entry(node.idx)
+                    .or_insert(vec![])
+                    .push(item),
+                None => unlinked += 1
+            }
+        }
+        (linked, unlinked)
+    }
+
+    // Same as stations, on a node we can walk and ride from
+    fn link_docks(&mut self, docks: Vec<Dock>) {
+        let (linked, unlinked) = self.link_nodes(
+            docks.into_iter().map(|dock| (dock.lat, dock.lon, dock)),
+            Way::is_bikeable);
+        info!("Linked {} nodes to bike docks, {} docks too far from the \
+               graph", linked.len(), unlinked);
+        self.docks = linked;
+    }
+
+    // Parkings mapped as nodes, and entrances of the mapped as areas: tie
+    // them to a node we can drive to and walk from
+    fn link_parkings(&mut self) {
+        let (linked, unlinked) = self.link_nodes(
+            self.nodes.iter()
+                .filter(|node| node.tags.get_key("amenity").map_or(false, |v|
+                        v == "parking" || v == "parking_entrance"))
+                .map(|parking| (parking.lat, parking.lon, parking.idx)),
+            Way::is_drivable);
+        info!("Linked {} nodes to parkings, {} parkings too far from the \
+               graph", linked.len(), unlinked);
+        // One parking per node is enough, the first one
+        self.parkings = linked.into_iter()
+            .map(|(node, parkings)| (node, parkings[0]))
+            .collect();
+    }
+
+    // The parking we can leave a car at from node
+    pub fn parking_at(&self, node: &Node) -> Option<&Node> {
+        self.parkings.get(&node.idx).map(|idx| &self.nodes[*idx as usize])
+    }
+
+    // Bike docks at node
+    pub fn docks_at(&self, node: &Node) -> &[Dock] {
+        self.docks.get(&node.idx).map_or(&[], |docks| &docks[..])
+    }
+
+    // Walkers changing modes take it to stay where they are
+    pub fn mode_switch(&self) -> &Edge {
+        &self.switch
+    }
+
+    // Stops we can board at within radius meters, closest first
+    pub fn stops_near(&self, lat: f64, lon: f64, radius: u64)
+        -> Vec<(&Stop, u64)> {
+        let mut stops = self.transit.stops_within(lat, lon, radius as f64)
+            .filter(|stop| stop.is_platform())
+            .map(|stop| (stop, stop.distance_from(lat, lon)))
+            .collect::<Vec<_>>();
+        stops.sort_by_key(|(_, dist)| *dist);
+        stops
+    }
+
+    pub fn transit_stop(&self, id: &str) -> Result<&Stop, Error> {
+        self.transit.stop(id).ok_or(Error::UnknownStop(id.to_string()))
+    }
+
+    // Entrances and stops we can get in transit from at node
+    pub fn transit_access(&self, node: &Node) -> &[Stop] {
+        self.access.get(&node.idx).map_or(&[], |stops| &stops[..])
+    }
+
+    // Platforms we can walk to from node, with the seconds it takes to get
+    // on them. Walks are undirected so that's also the way back.
+    pub fn platforms_near(&self, node: &Node, profile: Profile,
+                          via: Vec<&Snap>) -> Vec<(Stop, u32)> {
+        let walker = walkers::explore::Explore {
+            max_cost: Some(self.options.transit_walk),
+            predicate: |x: &Node| !self.transit_access(x).is_empty(),
+            target: None,
+            profile: profile,
+            via: via
+        };
+        let accessible = profile == Profile::Wheelchair;
+
+        let mut platforms: HashMap<String, (Stop, u32)> = HashMap::new();
+        for step in graph::walk(self, walker, node).steps() {
+            let walked = (step.total as f64 / WALK_SPEED) as u32;
+            for entrance in self.transit_access(step.to) {
+                for (platform, time) in entrance.platforms(accessible) {
+                    let time = walked + time;
+                    let best = platforms.entry(platform.id().to_string())
+                        .or_insert((platform, time));
+                    best.1 = best.1.min(time);
+                }
+            }
+        }
+        platforms.into_iter().map(|(_, platform)| platform).collect()
+    }
+
+    // Transit journeys between two nodes leaving at depart_at (seconds
+    // since midnight of date, today for the feeds without one), fastest
+    // for each number of rides
+    pub fn journeys(&self, from: &Node, to: &Node, via: Vec<&Snap>,
+                    date: Option<NaiveDate>, depart_at: u32, profile: Profile)
+        -> Result<Vec<Journey>, Error> {
+        let access = self.platforms_near(from, profile, via.clone());
+        let egress = self.platforms_near(to, profile, via);
+        let journeys = self.transit.journeys(&access, &egress, date, depart_at,
+                                             self.options.max_rides,
+                                             profile == Profile::Wheelchair);
+        if journeys.is_empty() {
+            return Err(Error::Unreachable { from: from.id, to: to.id });
+        }
+        Ok(journeys)
+    }
+
+    // Platforms we can get to driving from node then parking and walking,
+    // by stop id, with the seconds it takes and where we parked
+    fn park_access<'a>(&'a self, from: &'a Node, via: Vec<&'a Snap>,
+                       profile: Profile)
+        -> HashMap<String, (Stop, u32, Parked<'a>)> {
+        use walkers::multimodal::{legs, Mode, Multimodal};
+
+        let secs = |cost: u64| (cost as f64 / WALK_SPEED) as u32;
+        let drive = (self.options.max_drive as f64 * WALK_SPEED) as u64;
+        let park = (self.options.park_time as f64 * WALK_SPEED) as u64;
+        let walker = Multimodal {
+            target: None,
+            max_cost: Some(drive + park + self.options.transit_walk),
+            profile: profile,
+            modes: vec![Mode::Drive],
+            via: via
+        };
+        let accessible = profile == Profile::Wheelchair;
+
+        let res = graph::walk(self, walker, from);
+        let mut platforms: HashMap<String, (Stop, u32, Parked)> =
+            HashMap::new();
+        for step in res.steps()
+            .filter(|step| !self.transit_access(step.to).is_empty()) {
+            // Driving, if we didn't park where we started, then walking
+            let legs = legs(&res, step);
+            let (drove, walked) = match &legs[..] {
+                [walked] => (None, walked),
+                [drove, walked] => (Some(drove), walked),
+                _ => continue
+            };
+            if walked.cost > park + self.options.transit_walk {
+                continue;
+            }
+            let at = match self.parking_at(walked.from) {
+                Some(at) => at,
+                None => continue
+            };
+
+            let shape = drove.map_or(vec![], |drove| drove.steps.iter()
+                .flat_map(|step| {
+                    let mut line = res.prev(step).map_or(vec![], |prev|
+                        vec![(prev.to.lon, prev.to.lat)]);
+                    line.extend(step.edge.map_or(vec![], |edge|
+                        self.edge_shape(edge)));
+                    line.push((step.to.lon, step.to.lat));
+                    line
                 })
-                .collect(),
-            _ => vec![],
+                .collect::<Vec<_>>());
+            let parked = Parked {
+                at: at,
+                drive: drove.map_or(0, |drove| secs(drove.cost)),
+                shape: shape
+            };
+
+            for entrance in self.transit_access(step.to) {
+                for (platform, time) in entrance.platforms(accessible) {
+                    let time = secs(step.total) + time;
+                    match platforms.get(platform.id()) {
+                        Some((_, best, _)) if *best <= time => (),
+                        _ => {
+                            platforms.insert(platform.id().to_string(),
+                                             (platform, time, parked.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        platforms
+    }
+
+    // Transit journeys leaving from node by car: we drive to a parking,
+    // walk to a platform and ride from there. Each comes with where we
+    // parked.
+    pub fn park_and_ride<'a>(&'a self, from: &'a Node, to: &'a Node,
+                             via: Vec<&'a Snap>, date: Option<NaiveDate>,
+                             depart_at: u32, profile: Profile)
+        -> Result<Vec<(Journey, Parked<'a>)>, Error> {
+        let parks = self.park_access(from, via.clone(), profile);
+        let access = parks.values()
+            .map(|(stop, time, _)| (stop.clone(), *time))
+            .collect::<Vec<_>>();
+        let egress = self.platforms_near(to, profile, via);
+        let journeys = self.transit.journeys(&access, &egress, date, depart_at,
+                                             self.options.max_rides,
+                                             profile == Profile::Wheelchair)
+            .into_iter()
+            .filter_map(|journey| {
+                // The access walk ends at the platform we parked for
+                let boarding = match journey.legs.first() {
+                    Some(Leg::Walk { to: Some(to), .. }) => to.id(),
+                    _ => return None
+                };
+                let parked = parks.get(boarding)?.2.clone();
+                Some((journey, parked))
+            })
+            .collect::<Vec<_>>();
+        if journeys.is_empty() {
+            return Err(Error::Unreachable { from: from.id, to: to.id });
+        }
+        Ok(journeys)
+    }
+
+    // Where we can be within budget seconds of leaving node at depart_at
+    // on date (as for journeys), walking or riding then walking: the
+    // fastest way to each node
+    pub fn transit_reach<'a>(&'a self, from: &'a Node, via: Vec<&'a Snap>,
+                             date: Option<NaiveDate>, depart_at: u32,
+                             budget: u32, profile: Profile)
+        -> HashMap<&'a Node, Reach> {
+        // Nodes walked to from starts (node, seconds in), with the start
+        // each one is the closest to
+        let walk = |starts: &[(&'a Node, u32)], via: Vec<&'a Snap>| {
+            let walker = walkers::explore::Explore {
+                max_cost: Some((budget as f64 * WALK_SPEED) as u64),
+                predicate: |_: &Node| true,
+                target: None,
+                profile: profile,
+                via: via
+            };
+            let starts = starts.iter()
+                .map(|(node, time)| (*node, (*time as f64 * WALK_SPEED) as u64))
+                .collect::<Vec<_>>();
+            let res = graph::walk_from(self, walker, &starts);
+            res.steps()
+                .filter_map(|step| Some((step.to,
+                                         (step.total as f64 / WALK_SPEED) as u32,
+                                         res.path(step).last()?.to)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut reach: HashMap<&Node, Reach> = walk(&[(from, 0)], via.clone())
+            .into_iter()
+            .map(|(node, time, _)| (node, Reach { time: time, rides: 0, stop: None }))
+            .collect();
+
+        let accessible = profile == Profile::Wheelchair;
+        let access = self.platforms_near(from, profile, via);
+        let reached = self.transit.reach(&access, date, depart_at, budget,
+                                         self.options.max_rides, accessible);
+        if reached.is_empty() {
+            return reach;
+        }
+
+        // Back out of the stations we got to
+        // XXX This goes through all entrances, keep it around if it's slow
+        let mut exits: HashMap<&str, Vec<(&Node, u32)>> = HashMap::new();
+        let wanted = reached.iter().map(|r| r.stop.id())
+            .collect::<HashSet<&str>>();
+        for (idx, entrances) in &self.access {
+            for entrance in entrances {
+                for (platform, time) in entrance.platforms(accessible) {
+                    if let Some(id) = wanted.get(platform.id()) {
+                        exits.entry(id).or_insert(vec![])
+                            .push((&self.nodes[*idx as usize], time));
+                    }
+                }
+            }
+        }
+
+        // One walk out of every exit, each starting once we're there
+        let mut seeds: HashMap<&Node, (u32, usize)> = HashMap::new();
+        for (i, r) in reached.iter().enumerate() {
+            let time = r.arrival - depart_at;
+            for (node, exit) in exits.get(r.stop.id()).into_iter().flatten() {
+                if time + exit >= budget {
+                    continue;
+                }
+                match seeds.get(node) {
+                    Some((known, _)) if *known <= time + exit => (),
+                    _ => { seeds.insert(node, (time + exit, i)); }
+                }
+            }
+        }
+        let starts = seeds.iter()
+            .map(|(node, (time, _))| (*node, *time))
+            .collect::<Vec<_>>();
+        let walked = walk(&starts, vec![]).into_iter()
+            .filter_map(|(node, time, start)| Some((node, time, seeds.get(start)?.1)))
+            // Getting out is getting there
+            .chain(seeds.iter().map(|(node, (time, i))| (*node, *time, *i)))
+            .collect::<Vec<_>>();
+
+        for (node, time, i) in walked {
+            match reach.get(node) {
+                Some(known) if known.time <= time => (),
+                _ => {
+                    reach.insert(node, Reach {
+                        time: time,
+                        rides: reached[i].rides,
+                        stop: Some(reached[i].stop.clone())
+                    });
+                }
+            }
         }
+        reach
     }
 
-    pub fn initial_node(&self, lat: f64, lon: f64, dist: u64) -> Option<&Node> {
-        // Return the first close enough node suitable for a walk
-        // XXX: the quadmap is unsorted, anything in the square comes out
-        // dist is unused
+    // Same as neighbors, going through the virtual nodes of snaps
+    pub fn neighbors_via<'a, 's>(&'a self, node: &'a Node,
+                                 snaps: &'s [&'a Snap])
+        -> impl Iterator<Item=(&'a Edge, u64, &'a Node)> + 's
+        where 'a: 's {
+        // A virtual node only leads to both ends of its edge, and to the
+        // snaps it's linked with on that edge
+        let ends = snaps.iter()
+            .filter(move |snap| snap.node.id == node.id)
+            .flat_map(move |snap| {
+                vec![(snap.a, &snap.edges[0]), (snap.b, &snap.edges[1])]
+                    .into_iter()
+                    .filter_map(move |(id, edge)|
+                                Some((edge, edge.dist, self.node_by_id(id)?)))
+                    .chain(snap.along.iter().filter_map(move |(id, edge)| {
+                        let other = snaps.iter().find(|s| s.node.id == *id)?;
+                        Some((edge, edge.dist, &other.node))
+                    }))
+            });
+
+        // And these ends lead to it
+        let snapped = snaps.iter()
+            .filter_map(move |snap| {
+                let edge = if snap.a == node.id {
+                    &snap.edges[2]
+                } else if snap.b == node.id {
+                    &snap.edges[3]
+                } else {
+                    return None;
+                };
+                Some((edge, edge.dist, &snap.node))
+            });
+
+        self.neighbors(node).chain(ends).chain(snapped)
+    }
+
+    pub fn neighbors<'a>(&'a self, node: &Node)
+        -> impl Iterator<Item=(&'a Edge, u64, &'a Node)> {
+        // Virtual nodes are past the graph, they have no edges there
+        self.graph.edges(node.idx).map(move |(to, edge)| (
+            edge,
+            edge.dist,
+            &self.nodes[to as usize]
+        ))
+    }
+
+    pub fn way_of(&self, edge: &Edge) -> &Way {
+        &self.ways[edge.way as usize]
+    }
+
+    // What a profile would have to deal with taking edge to node
+    pub fn issues(&self, profile: Profile, edge: Option<&Edge>, node: &Node)
+        -> Vec<Issue> {
+        let mut issues = edge.map(|edge| profile.way_issues(self.way_of(edge)))
+            .unwrap_or(vec![]);
+        issues.extend(profile.node_issues(node));
+        issues
+    }
+
+    // What profile would meet along the path to step, in walk order
+    pub fn path_issues<'a, W>(&'a self, profile: Profile,
+                              res: &graph::WalkResult<'a, Db, W>,
+                              step: &graph::Step<'a, Db, W>) -> Vec<Issue>
+        where W: graph::GraphWalker<'a, Db> {
+        let mut issues: Vec<Issue> = res.path(step)
+            .flat_map(|step| self.issues(profile, step.edge, step.to))
+            .collect();
+        issues.reverse();
+        issues.dedup();
+        issues
+    }
+
+    // Why profile can't go from a node to another: walk it on foot to
+    // tell what's in the way
+    pub fn unreachable<'a>(&'a self, from: &'a Node, to: &'a Node,
+                           profile: Profile) -> Error {
+        if profile != Profile::Foot {
+            let walker = walkers::explore::Explore {
+                max_cost: None,
+                predicate: |x| x == to,
+                target: Some(to),
+                profile: Profile::Foot,
+                via: vec![]
+            };
+
+            let res = graph::walk(self, walker, from);
+            let step = res.steps().find(|step| step.to == to);
+            if let Some(step) = step {
+                return Error::Inaccessible {
+                    from: from.id,
+                    to: to.id,
+                    issues: self.path_issues(profile, &res, step).iter()
+                        .filter(|issue| issue.blocks())
+                        .map(|issue| issue.to_string())
+                        .collect()
+                };
+            }
+        }
+        Error::Unreachable { from: from.id, to: to.id }
+    }
+
+    // The points an edge goes through between its nodes, in the
+    // direction it's walked
+    pub fn edge_shape(&self, edge: &Edge) -> Vec<(f64, f64)> {
+        let points = &self.shapes[edge.shape as usize..
+                                  (edge.shape + edge.points) as usize];
+        if edge.forward {
+            points.to_vec()
+        } else {
+            points.iter().rev().cloned().collect()
+        }
+    }
 
+    pub fn initial_node(&self, lat: f64, lon: f64, dist: u64, profile: Profile)
+        -> Option<&Node> {
+        // Return the closest node suitable for a walk within dist meters
         let start = Instant::now();
-        let ret = self.node_index.around(lat, lon)
-            .filter(|id| self.adjacencies.get(&id).is_some())
+        let ret = self.node_index.within(lat, lon, dist)
             .filter_map(|id| self.node_by_id(id))
-            .find(|n| n.distance_from(lat, lon) < dist);
+            .filter(|node| self.graph.degree(node.idx) > 0)
+            .filter(|node| self.is_routable(node, profile))
+            .next();
 
         trace!("initial_node for {}/{} took {:?}", lat, lon, start.elapsed());
         ret
     }
 
-    pub fn closest_initial(&self, node: i64, dist: u64) -> Option<&Node> {
+    // Routable ways with at least one edge crossing bbox. Other ways
+    // (buildings, areas) aren't loaded, see read_pbf.
+    pub fn ways_within(&self, bbox: quadtree::BBox) -> Vec<&Way> {
+        let mut seen = HashSet::new();
+        self.edge_index.in_bbox(bbox)
+            .filter(|seg| seen.insert(seg.data.way))
+            .filter_map(|seg| self.way_by_id(seg.data.way))
+            .collect()
+    }
+
+    // Closest edge to lat/lon within dist meters, and its distance
+    pub fn closest_edge(&self, lat: f64, lon: f64, dist: u64)
+        -> Option<(edge_index::EdgeRef, u64)> {
+        self.edge_index.within(lat, lon, dist)
+            .next()
+            .map(|(seg, dist)| (seg.data, dist))
+    }
+
+    // Project lat/lon on the closest edge the profile can use, within dist
+    // meters. The virtual node gets id, which must be unique in the query.
+    pub fn snap(&self, lat: f64, lon: f64, dist: u64, profile: Profile,
+                id: i64) -> Option<Snap> {
+        self.edge_index.within(lat, lon, dist)
+            .filter(|(seg, _)| profile.allows(self.way_of(&seg.data.edge)))
+            .filter_map(|(seg, _)| {
+                let a = self.node_by_id(seg.data.a)?;
+                let b = self.node_by_id(seg.data.b)?;
+                if !self.is_routable(a, profile)
+                    || !self.is_routable(b, profile) {
+                    return None;
+                }
+                let (_, x, y) = seg.project(lon, lat);
+                // ids are negative, the index goes past the real nodes
+                let node = Node {
+                    id: id,
+                    lat: y,
+                    lon: x,
+                    tags: vec![],
+                    idx: (self.nodes.len() as i64 - id - 1) as u32
+                };
+
+                // The segment is one of the edge, split its shape there
+                let edge = seg.data.edge;
+                let to_a = (seg.data.from_a + node.distance_from(seg.ay, seg.ax))
+                    .min(edge.dist);
+                let to_b = edge.dist - to_a;
+                let (index, points) = (seg.data.index, edge.points);
+                let half_a = Edge {
+                    dist: to_a,
+                    points: index,
+                    forward: true,
+                    ..edge
+                };
+                let half_b = Edge {
+                    dist: to_b,
+                    shape: edge.shape + index,
+                    points: points - index,
+                    forward: true,
+                    ..edge
+                };
+
+                Some(Snap {
+                    node: node,
+                    way: seg.data.way,
+                    a: a.id,
+                    b: b.id,
+                    edges: [half_a.reversed(), half_b, half_a, half_b.reversed()],
+                    along: vec![]
+                })
+            })
+            .next()
+    }
+
+    pub fn closest_initial(&self, node: i64, dist: u64, profile: Profile)
+        -> Result<&Node, Error> {
         // Same as above from a starting node
-        self.node_by_id(node).and_then(|node|
-             self.initial_node(node.lat, node.lon, dist)
-        )
+        let node = self.node(node)?;
+        self.initial_node(node.lat, node.lon, dist, profile)
+            .ok_or(Error::NoGraphNode { lat: node.lat, lon: node.lon })
     }
 }
 
@@ -504,5 +1743,367 @@ impl Db {
 // XXX impl. rush check why this is required for both
 impl graph::Graph for Db {
     type Node = Node;
-    type Edge = Way;
+    type Edge = Edge;
+
+    fn node_index(&self, node: &Node) -> usize {
+        node.idx as usize
+    }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walkers, Adjacencies, Db, Edge, Error, Issue, Node, Options,
+                Profile, Snap, SquareScore, StringTables, Way};
+
+    // A footway 10-11-12-13 with a crossing at 12, a street 13-14-15-16
+    // missing a node between 14 and 15, a closed footway around 30, a
+    // cafe by 16 and a building that isn't routable
+    fn db() -> Db {
+        let nodes = vec![
+            Node::new(10, 37.700, -122.400, &[]),
+            Node::new(11, 37.701, -122.400, &[]),
+            Node::new(12, 37.702, -122.400, &[("highway", "crossing")]),
+            Node::new(13, 37.703, -122.400, &[]),
+            Node::new(14, 37.703, -122.401, &[]),
+            Node::new(15, 37.703, -122.403, &[]),
+            Node::new(16, 37.703, -122.404, &[]),
+            Node::new(30, 37.710, -122.410, &[]),
+            Node::new(31, 37.711, -122.410, &[]),
+            Node::new(32, 37.711, -122.411, &[]),
+            Node::new(40, 37.7032, -122.404, &[("amenity", "cafe")]),
+            Node::new(50, 37.700, -122.399, &[])
+        ];
+        let ways = vec![
+            Way::new(100, vec![10, 11, 12, 13], &[("highway", "footway")]),
+            Way::new(101, vec![13, 14, 99, 15, 16],
+                     &[("highway", "residential")]),
+            Way::new(102, vec![30, 31, 32, 30], &[("highway", "footway")]),
+            Way::new(103, vec![10, 50], &[("building", "yes")])
+        ];
+        Db::from_elements(nodes, ways, Options {
+            scores: false,
+            min_component: 1,
+            ..Default::default()
+        }).unwrap()
+    }
+
+    // Where the edges of a node go: (node, meters, way, shape)
+    fn edges(db: &Db, id: i64) -> Vec<(i64, u64, i64, Vec<(f64, f64)>)> {
+        let node = db.node(id).unwrap();
+        let mut edges = db.neighbors(node)
+            .map(|(edge, dist, to)| (to.id, dist, db.way_of(edge).id,
+                                     db.edge_shape(edge)))
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| a.0.cmp(&b.0).then(a.3.len().cmp(&b.3.len())));
+        edges
+    }
+
+    fn dist(db: &Db, ids: &[i64]) -> u64 {
+        ids.windows(2).map(|w| db.distance(w[0], w[1]).unwrap()).sum()
+    }
+
+    #[test]
+    fn way_uses() {
+        let db = db();
+        let uses = db.way_uses();
+        let used = |id| uses[db.node(id).unwrap().idx as usize];
+        assert_eq!(used(13), 2); // both ways
+        assert_eq!(used(30), 2); // both ends of the closed way
+        assert_eq!(used(11), 1);
+        assert_eq!(used(50), 0); // only on the building
+        assert_eq!(used(40), 0);
+    }
+
+    #[test]
+    fn contraction() {
+        let db = db();
+
+        // 11 is in the middle of the footway, its edge carries it
+        assert!(edges(&db, 11).is_empty());
+        assert_eq!(edges(&db, 10), vec![
+            (12, dist(&db, &[10, 11, 12]), 100, vec![(-122.400, 37.701)])]);
+
+        // Tagged nodes and junctions stay
+        assert_eq!(edges(&db, 12).iter().map(|e| (e.0, e.3.len()))
+                   .collect::<Vec<_>>(), vec![(10, 1), (13, 0)]);
+        assert_eq!(edges(&db, 13).iter().map(|e| (e.0, e.2))
+                   .collect::<Vec<_>>(), vec![(12, 100), (14, 101)]);
+
+        // The cafe is linked to the closest used node by way 1
+        assert_eq!(edges(&db, 40), vec![(16, 1, 1, vec![])]);
+        assert!(edges(&db, 50).is_empty());
+    }
+
+    #[test]
+    fn missing_nodes() {
+        // The ends of what we have of the street are kept, nothing goes
+        // through the missing node
+        let db = db();
+        assert_eq!(edges(&db, 14),
+                   vec![(13, dist(&db, &[13, 14]), 101, vec![])]);
+        assert_eq!(edges(&db, 15),
+                   vec![(16, dist(&db, &[15, 16]), 101, vec![])]);
+    }
+
+    #[test]
+    fn closed_way() {
+        // Around from 30 back to 30, both ways
+        let db = db();
+        assert!(edges(&db, 31).is_empty());
+        assert!(edges(&db, 32).is_empty());
+        let around = dist(&db, &[30, 31, 32, 30]);
+        let mut shapes = edges(&db, 30).into_iter()
+            .map(|(to, dist, way, shape)| {
+                assert_eq!((to, dist, way), (30, around, 102));
+                shape
+            })
+            .collect::<Vec<_>>();
+        shapes.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+        assert_eq!(shapes, vec![vec![(-122.411, 37.711), (-122.410, 37.711)],
+                                vec![(-122.410, 37.711), (-122.411, 37.711)]]);
+    }
+
+    #[test]
+    fn edge_refs() {
+        // Segments know the edge they're part of and how far along
+        let db = db();
+        let (edge, _) = db.closest_edge(37.7015, -122.400, 20).unwrap();
+        assert_eq!((edge.way, edge.a, edge.b, edge.index), (100, 10, 12, 1));
+        assert_eq!(edge.from_a, dist(&db, &[10, 11]));
+        assert_eq!(db.edge_shape(&edge.edge), vec![(-122.400, 37.701)]);
+
+        let (edge, _) = db.closest_edge(37.711, -122.4105, 20).unwrap();
+        assert_eq!((edge.way, edge.a, edge.b, edge.index), (102, 30, 30, 1));
+        assert_eq!(edge.from_a, dist(&db, &[30, 31]));
+        assert_eq!(db.edge_shape(&edge.edge).len(), 2);
+    }
+
+    #[test]
+    fn snap() {
+        // A quarter of the way from 11 to 12, a bit to the east
+        let db = db();
+        let snap = db.snap(37.70125, -122.39995, 50, Profile::Foot, -1)
+            .unwrap();
+        assert_eq!((snap.way, snap.a, snap.b), (100, 10, 12));
+        assert_eq!(snap.node.lon, -122.400);
+        assert!((snap.node.lat - 37.70125).abs() < 1e-9);
+
+        // The edge is split at 11: the way to 10 goes through it
+        let to_a = dist(&db, &[10, 11])
+            + snap.node.distance_from(37.701, -122.400);
+        let [back_a, to_b, from_a, back_b] = snap.edges;
+        assert_eq!((back_a.dist, to_b.dist),
+                   (to_a, dist(&db, &[10, 11, 12]) - to_a));
+        assert_eq!(db.edge_shape(&back_a), vec![(-122.400, 37.701)]);
+        assert_eq!(db.edge_shape(&from_a), vec![(-122.400, 37.701)]);
+        assert!(db.edge_shape(&to_b).is_empty());
+        assert!(db.edge_shape(&back_b).is_empty());
+
+        // Too far, or on a way we can't take
+        assert!(db.snap(37.70125, -122.39, 50, Profile::Foot, -1).is_none());
+    }
+
+    #[test]
+    fn snap_ids() {
+        // Requests give the two ends of constraint i -2i-1 and -2i-2:
+        // their nodes land past the real ones without colliding
+        let db = db();
+        let mut idx = (0..3).flat_map(|i| vec![-2 * i - 1, -2 * i - 2])
+            .map(|id| db.snap(37.7015, -122.400, 50, Profile::Foot, id)
+                 .unwrap().node.idx as usize)
+            .collect::<Vec<_>>();
+        idx.sort();
+        let n = db.nodes.len();
+        assert_eq!(idx, (n..n + 6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snap_link() {
+        // Two snaps on both sides of 11, on the same edge
+        let db = db();
+        let snap = |lat, lon, id| db.snap(lat, lon, 50, Profile::Foot, id)
+            .unwrap();
+        let (mut a, mut b) = (snap(37.7005, -122.400, -1),
+                              snap(37.7015, -122.400, -2));
+        let mut far = snap(37.703, -122.4005, -3);
+        Snap::link(&mut b, &mut a);
+        Snap::link(&mut a, &mut b); // once is enough
+        Snap::link(&mut a, &mut far);
+
+        assert!(far.along.is_empty());
+        assert_eq!(a.along.len(), 1);
+        assert_eq!(b.along.len(), 1);
+        let (to_b, to_a) = (a.along[0], b.along[0]);
+        assert_eq!((to_b.0, to_a.0), (-2, -1));
+        assert_eq!(to_b.1.dist, b.edges[2].dist - a.edges[2].dist);
+        assert_eq!(to_a.1.dist, to_b.1.dist);
+        assert_eq!(db.edge_shape(&to_b.1), vec![(-122.400, 37.701)]);
+        assert_eq!(db.edge_shape(&to_a.1), vec![(-122.400, 37.701)]);
+
+        // Walks go straight from one to the other
+        let via = [&a, &b];
+        let next = db.neighbors_via(&a.node, &via)
+            .map(|(_, dist, node)| (node.id, dist))
+            .collect::<Vec<_>>();
+        assert_eq!(next, vec![(10, a.edges[0].dist), (12, a.edges[1].dist),
+                              (-2, to_b.1.dist)]);
+    }
+
+    #[test]
+    fn inaccessible() {
+        // Steps between a crossing without tactile paving and 23
+        let nodes = vec![
+            Node::new(20, 37.700, -122.400, &[]),
+            Node::new(21, 37.701, -122.400, &[("highway", "crossing")]),
+            Node::new(22, 37.702, -122.400, &[]),
+            Node::new(23, 37.703, -122.400, &[])
+        ];
+        let ways = vec![
+            Way::new(200, vec![20, 21, 22], &[("highway", "footway")]),
+            Way::new(201, vec![22, 23], &[("highway", "steps")])
+        ];
+        let db = Db::from_elements(nodes, ways, Options {
+            scores: false,
+            min_component: 1,
+            ..Default::default()
+        }).unwrap();
+        let node = |id| db.node(id).unwrap();
+
+        // Only what blocks says why we can't go
+        match db.unreachable(node(20), node(23), Profile::Wheelchair) {
+            Error::Inaccessible { from, to, issues } => {
+                assert_eq!((from, to), (20, 23));
+                assert_eq!(issues, vec!["steps on way 201".to_string()]);
+            },
+            err => panic!("{:?}", err)
+        }
+        match db.unreachable(node(20), node(23), Profile::Foot) {
+            Error::Unreachable { from: 20, to: 23 } => (),
+            err => panic!("{:?}", err)
+        }
+
+        // Along a path, in walk order
+        let walker = walkers::explore::Explore {
+            max_cost: None,
+            predicate: |x: &Node| x.id == 23,
+            target: Some(node(23)),
+            profile: Profile::Foot,
+            via: vec![]
+        };
+        let res = graph::walk(&db, walker, node(20));
+        let step = res.steps().find(|step| step.to.id == 23).unwrap();
+        assert_eq!(db.path_issues(Profile::Wheelchair, &res, step),
+                   vec![Issue::NoTactilePaving(21), Issue::Steps(201)]);
+        assert!(db.path_issues(Profile::Foot, &res, step).is_empty());
+    }
+
+    #[test]
+    fn components() {
+        // 1-2-3 up steps to 4-5, and 7-8 on their own
+        let nodes = (1..=8).map(|id| Node::new(id, 37.7 + id as f64 * 0.001,
+                                               -122.4, &[]))
+            .collect();
+        let ways = vec![
+            Way::new(300, vec![1, 2], &[("highway", "footway")]),
+            Way::new(301, vec![3, 4], &[("highway", "steps")])
+        ];
+        let mut db = Db::from_elements(nodes, ways, Options {
+            scores: false,
+            ..Default::default()
+        }).unwrap();
+
+        let mut graph = Adjacencies::new(db.nodes.len());
+        for (a, b, way) in &[(1, 2, 300), (2, 3, 300), (3, 4, 301),
+                             (4, 5, 300), (7, 8, 300)] {
+            graph.connect(db.node_ids[a], db.node_ids[b], Edge {
+                way: db.way_ids[way],
+                dist: 1,
+                shape: 0,
+                points: 0,
+                forward: true
+            });
+        }
+        db.graph = graph.into_graph();
+        let routable = |db: &Db, profile| (1..=8)
+            .filter(|id| db.is_routable(db.node(*id).unwrap(), profile))
+            .collect::<Vec<i64>>();
+
+        // The largest island stays however small, the steps cut it in
+        // two in a wheelchair
+        db.options.min_component = 3;
+        db.mark_components();
+        assert_eq!(routable(&db, Profile::Foot), vec![1, 2, 3, 4, 5]);
+        assert_eq!(routable(&db, Profile::Wheelchair), vec![1, 2, 3]);
+
+        // Big enough ones stay too
+        db.options.min_component = 2;
+        db.mark_components();
+        for profile in Profile::all() {
+            assert_eq!(routable(&db, profile), vec![1, 2, 3, 4, 5, 7, 8]);
+        }
+
+        db.options.min_component = 1000;
+        db.mark_components();
+        assert_eq!(routable(&db, Profile::Foot), vec![1, 2, 3, 4, 5]);
+        assert_eq!(routable(&db, Profile::Wheelchair), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn string_tables() {
+        // Blocks wait for their own table only
+        let mut tables = StringTables::new();
+        assert!(tables.get(1).is_none());
+        tables.wait(1, "a");
+        tables.wait(2, "b");
+        tables.wait(1, "c");
+        assert!(tables.insert(3, vec![]).is_empty());
+        assert_eq!(tables.insert(1, vec!["highway".to_string()]),
+                   vec!["a", "c"]);
+        assert_eq!(tables.get(1), Some(&vec!["highway".to_string()]));
+        assert_eq!(tables.waiting(), 1);
+        assert_eq!(tables.insert(2, vec![]), vec!["b"]);
+        assert_eq!(tables.waiting(), 0);
+    }
+
+    #[test]
+    fn squares() {
+        let mut db = db();
+        let square = |hash: &str, scores: &[(&str, u64)]| {
+            let gbox = geohash::geobox_for_hash(hash);
+            (hash.to_string(), SquareScore {
+                hash: hash.to_string(),
+                top: gbox.y_range.max,
+                bottom: gbox.y_range.min,
+                left: gbox.x_range.min,
+                right: gbox.x_range.max,
+                scores: scores.iter().map(|(k, v)| (k.to_string(), *v))
+                    .collect()
+            })
+        };
+        db.scores = vec![square("9q8yyk", &[("cafe", 100), ("bar", 300)]),
+                         square("9q8yym", &[("cafe", 50)]),
+                         square("9q8yzz", &[("cafe", 10)])]
+            .into_iter().collect();
+
+        // Shorter hashes get the best of the squares they hold
+        let coarse = db.square("9q8yy").unwrap();
+        assert_eq!(coarse.hash, "9q8yy");
+        assert_eq!(coarse.scores["cafe"], 50);
+        assert_eq!(coarse.scores["bar"], 300);
+        let gbox = geohash::geobox_for_hash("9q8yy");
+        assert_eq!((coarse.left, coarse.top),
+                   (gbox.x_range.min, gbox.y_range.max));
+        assert_eq!(db.square("9q8y").unwrap().scores["cafe"], 10);
+
+        // Longer ones are the square they're in
+        assert_eq!(db.square("9q8yykpz").unwrap().hash, "9q8yyk");
+        assert!(db.square("9q8yyj").is_none());
+        assert!(db.square("9q9").is_none());
+        assert_eq!(db.square_hash(37.7, -122.4, 9).len(),
+                   db.options().score_precision);
+    }
 }
@@ -5,7 +5,10 @@ use quadtree;
 use radix_trie;
 use radix_trie::TrieCommon;
 
-use super::Node;
+use super::{Error, Node};
+
+// Nodes per quadtree cell before it splits
+const CELL_CAPACITY: usize = 2000;
 
 #[derive(Debug)]
 pub struct NodePos {
@@ -26,6 +29,8 @@ pub struct NodeIndex {
     by_poi: HashMap<String, Vec<i64>>,
     by_name: radix_trie::Trie<String, Vec<i64>>,
     by_pos: quadtree::QuadTree<NodePos>,
+    poi_pos: HashMap<String, quadtree::QuadTree<NodePos>>, // one per POI type
+    bbox: quadtree::BBox
 }
 
 impl NodeIndex {
@@ -34,21 +39,16 @@ impl NodeIndex {
             by_poi: HashMap::new(),
             by_name: radix_trie::Trie::new(),
             by_pos: quadtree::QuadTree::of_capacity(min_lon, min_lat,
-                                max_lon-min_lon, max_lat-min_lat, 2000)
+                                max_lon-min_lon, max_lat-min_lat, CELL_CAPACITY),
+            poi_pos: HashMap::new(),
+            bbox: quadtree::BBox::new(min_lon, min_lat, max_lon, max_lat)
         }
     }
 
-    pub fn squares(&self) -> impl Iterator<Item=&quadtree::QuadCell<NodePos>> + '_ {
-        //XXX hash(7)
-        self.by_pos.walk()
-    }
-
-    //XXX refactor? used for debugging now
-    pub fn square_for<'a>(&'a self, lat: f64, lon: f64) -> &'a quadtree::QuadCell<NodePos> {
-        self.by_pos.get_cell(lon, lat)
+    pub fn bbox(&self) -> quadtree::BBox {
+        self.bbox
     }
 
-
     pub fn insert(&mut self, node: &Node) {
         self.by_pos.insert(NodePos {
             lat: node.lat,
@@ -79,14 +79,30 @@ impl NodeIndex {
             self.by_poi.entry(poi.to_lowercase().to_string())
                 .or_insert(Vec::new())
                 .push(node.id);
+            let bbox = &self.bbox;
+            self.poi_pos.entry(poi.to_lowercase().to_string())
+                .or_insert_with(|| quadtree::QuadTree::of_capacity(bbox.min_x,
+                        bbox.min_y, bbox.max_x - bbox.min_x,
+                        bbox.max_y - bbox.min_y, CELL_CAPACITY))
+                .insert(NodePos {
+                    lat: node.lat,
+                    lon: node.lon,
+                    id: node.id
+                });
         }
     }
 
+    // Closest nodes first, lazily descending into neighbouring squares
     pub fn around<'a>(&'a self, lat: f64, lon: f64) -> impl Iterator<Item=i64> + 'a {
-        // Nearest with distance: using square root
-        self.by_pos.get(lon, lat)
+        self.by_pos.around(lon, lat)
             .inspect(|n| trace!("nearest inspecting {:?}", n))
-            .map(|node| node.id)
+            .map(|(node, _)| node.id)
+    }
+
+    // Same as around, stopping at dist meters
+    pub fn within<'a>(&'a self, lat: f64, lon: f64, dist: u64) -> impl Iterator<Item=i64> + 'a {
+        self.by_pos.within_radius(lon, lat, dist as f64)
+            .map(|(node, _)| node.id)
     }
 
     pub fn poi_types(&self) -> Vec<&String> {
@@ -100,11 +116,63 @@ impl NodeIndex {
         }
     }
 
-    pub fn of_poi<'a>(&'a self, poi: &String) -> &'a Vec<i64> {
-        self.by_poi.get(&poi.to_lowercase()).unwrap()
+    // Nodes of a POI type within radius meters, closest first with their
+    // distance.
+    pub fn poi_within<'a>(&'a self, kind: &str, lat: f64, lon: f64,
+                          radius: u64)
+        -> Result<impl Iterator<Item=(i64, u64)> + 'a, Error> {
+        self.poi_pos.get(&kind.to_lowercase())
+            .ok_or(Error::UnknownPoiKind(kind.to_string()))
+            .map(|tree|
+            tree.within_radius(lon, lat, radius as f64)
+                .map(|(node, dist)| (node.id, dist as u64)))
+    }
+
+    pub fn of_poi<'a>(&'a self, poi: &String) -> Result<&'a Vec<i64>, Error> {
+        self.by_poi.get(&poi.to_lowercase())
+            .ok_or(Error::UnknownPoiKind(poi.to_string()))
     }
 
     pub fn named<'a>(&'a self) -> impl Iterator<Item=&i64> + 'a {
         self.by_name.values().flatten()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, Node};
+    use super::NodeIndex;
+
+    #[test]
+    fn poi_within() {
+        // Cafes going north 111m apart, a bar in between
+        let mut index = NodeIndex::new(-122.41, 37.69, -122.39, 37.72);
+        for i in 0..5 {
+            index.insert(&Node::new(i, 37.700 + i as f64 * 0.001, -122.400,
+                                    &[("amenity", "cafe")]));
+        }
+        index.insert(&Node::new(9, 37.7005, -122.400, &[("amenity", "bar")]));
+
+        // Closest first, from the north end
+        let cafes = index.poi_within("Cafe", 37.704, -122.400, 350).unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(cafes.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                   vec![4, 3, 2, 1]);
+        assert_eq!(cafes[0].1, 0);
+        assert!(cafes.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(cafes[3].1 <= 350);
+
+        // Pages of that
+        let page = index.poi_within("cafe", 37.704, -122.400, 350).unwrap()
+            .skip(1).take(2)
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(page, vec![3, 2]);
+
+        let unknown = index.poi_within("pub", 37.704, -122.400, 350).map(|_| ());
+        match unknown {
+            Err(Error::UnknownPoiKind(kind)) => assert_eq!(kind, "pub"),
+            _ => panic!("pub is not a kind here")
+        }
+    }
+}
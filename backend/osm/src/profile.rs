@@ -0,0 +1,276 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use gtfs;
+
+use super::{Error, Node, OsmTags, Way};
+
+// Steeper than that in percent is too much in a wheelchair, ramps are
+// supposed to stay under 1:12
+const MAX_INCLINE: f64 = 8.0;
+
+// Extra meters to go through a node we'd rather avoid but can use.
+// Costs can't go below the distance, so preferring crossings with
+// tactile paving is only this penalty on the ones without. Streets
+// crossed where no crossing node is mapped can't be told at all.
+const DISCOMFORT_COST: u64 = 50;
+
+// How we move on the map - decides which ways we can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Profile {
+    Foot,
+    Wheelchair
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Foot
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "foot" => Ok(Profile::Foot),
+            "wheelchair" => Ok(Profile::Wheelchair),
+            _ => Err(Error::UnknownProfile(s.to_string()))
+        }
+    }
+}
+
+// Why a way or a node is a problem for a profile, with the osm id
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    Steps(i64),
+    NotForWheelchairs(i64), // wheelchair=no
+    Incline(i64, String),
+    Surface(i64, String),
+    RaisedKerb(i64),
+    NoTactilePaving(i64)    // crossing without
+}
+
+impl Issue {
+    // Blocking issues make a way or node unusable, others only cost more
+    pub fn blocks(&self) -> bool {
+        match self {
+            Issue::NoTactilePaving(_) => false,
+            _ => true
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Steps(way) => write!(f, "steps on way {}", way),
+            Issue::NotForWheelchairs(id) =>
+                write!(f, "{} is tagged wheelchair=no", id),
+            Issue::Incline(way, incline) =>
+                write!(f, "incline of {} on way {}", incline, way),
+            Issue::Surface(way, surface) =>
+                write!(f, "{} surface on way {}", surface, way),
+            Issue::RaisedKerb(node) => write!(f, "raised kerb at node {}", node),
+            Issue::NoTactilePaving(node) =>
+                write!(f, "crossing without tactile paving at node {}", node)
+        }
+    }
+}
+
+// In percent, None for up/down/yes and other things we can't measure
+fn incline(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.ends_with('°') {
+        let degrees = value[..value.len() - '°'.len_utf8()].parse::<f64>().ok()?;
+        Some(degrees.to_radians().tan() * 100.0)
+    } else {
+        value.trim_end_matches('%').parse().ok()
+    }
+}
+
+impl Profile {
+    pub fn all() -> Vec<Profile> {
+        vec![Profile::Foot, Profile::Wheelchair]
+    }
+
+    pub fn allows(&self, way: &Way) -> bool {
+        way.is_highway() && self.way_issues(way).iter().all(|i| !i.blocks())
+    }
+
+    // Whether we can go through a node at all
+    pub fn passes(&self, node: &Node) -> bool {
+        self.node_issues(node).iter().all(|i| !i.blocks())
+    }
+
+    // Extra cost of going through a node
+    pub fn penalty(&self, node: &Node) -> u64 {
+        self.node_issues(node).len() as u64 * DISCOMFORT_COST
+    }
+
+    // Whether we can board this trip at this stop. Feeds that don't
+    // tell are trusted.
+    pub fn boards(&self, stop: &gtfs::Stop, trip: &gtfs::TripCursor) -> bool {
+        match self {
+            Profile::Foot => true,
+            Profile::Wheelchair =>
+                stop.wheelchair_boarding() != Some(false)
+                    && trip.wheelchair_accessible() != Some(false)
+        }
+    }
+
+    pub fn way_issues(&self, way: &Way) -> Vec<Issue> {
+        let mut issues = vec![];
+        if *self != Profile::Wheelchair {
+            return issues;
+        }
+
+        let tag = |k: &str| way.tags.get_key(k).map(|v| v.as_str());
+        if tag("highway") == Some("steps") {
+            issues.push(Issue::Steps(way.id));
+        }
+        if tag("wheelchair") == Some("no") {
+            issues.push(Issue::NotForWheelchairs(way.id));
+        }
+        if let Some(value) = tag("incline") {
+            if incline(value).map_or(false, |i| i.abs() > MAX_INCLINE) {
+                issues.push(Issue::Incline(way.id, value.to_string()));
+            }
+        }
+        if let Some(surface) = tag("surface") {
+            if ["gravel", "cobblestone", "unhewn_cobblestone"].contains(&surface) {
+                issues.push(Issue::Surface(way.id, surface.to_string()));
+            }
+        }
+        issues
+    }
+
+    pub fn node_issues(&self, node: &Node) -> Vec<Issue> {
+        let mut issues = vec![];
+        if *self != Profile::Wheelchair {
+            return issues;
+        }
+
+        let tag = |k: &str| node.tags.get_key(k).map(|v| v.as_str());
+        if tag("kerb") == Some("raised") {
+            issues.push(Issue::RaisedKerb(node.id));
+        }
+        if tag("wheelchair") == Some("no") {
+            issues.push(Issue::NotForWheelchairs(node.id));
+        }
+        let crossing = tag("highway") == Some("crossing")
+            || node.tags.has_key("crossing");
+        if crossing && tag("tactile_paving") != Some("yes") {
+            issues.push(Issue::NoTactilePaving(node.id));
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::super::{Node, Way};
+    use super::{incline, Issue, Profile};
+
+    #[test]
+    fn inclines() {
+        assert!((incline("10°").unwrap() - 17.63).abs() < 0.01);
+        assert_eq!(incline("-8%"), Some(-8.0));
+        assert_eq!(incline(" 5 "), Some(5.0));
+        assert_eq!(incline("up"), None);
+        assert_eq!(incline("°"), None);
+    }
+
+    #[test]
+    fn way_issues() {
+        let way = |tags| Way::new(1000, vec![], tags);
+        let issues = |tags| Profile::Wheelchair.way_issues(&way(tags));
+
+        assert_eq!(issues(&[("highway", "steps")]), vec![Issue::Steps(1000)]);
+        assert_eq!(issues(&[("highway", "footway"), ("wheelchair", "no")]),
+                   vec![Issue::NotForWheelchairs(1000)]);
+        assert_eq!(issues(&[("highway", "footway"), ("surface", "gravel")]),
+                   vec![Issue::Surface(1000, "gravel".to_string())]);
+        assert_eq!(issues(&[("highway", "footway"), ("incline", "10°")]),
+                   vec![Issue::Incline(1000, "10°".to_string())]);
+        assert!(issues(&[("highway", "footway"), ("incline", "-8%")])
+                .is_empty());
+        assert!(issues(&[("highway", "footway"), ("incline", "up")])
+                .is_empty());
+
+        // Anything goes on foot, as long as it's a highway
+        let steps = way(&[("highway", "steps")]);
+        assert!(Profile::Foot.way_issues(&steps).is_empty());
+        assert!(Profile::Foot.allows(&steps));
+        assert!(!Profile::Wheelchair.allows(&steps));
+        assert!(!Profile::Foot.allows(&way(&[("building", "yes")])));
+    }
+
+    #[test]
+    fn node_issues() {
+        let node = |tags| Node::new(7, 37.7, -122.4, tags);
+        let kerb = node(&[("kerb", "raised")]);
+        let closed = node(&[("wheelchair", "no")]);
+        let crossing = node(&[("highway", "crossing")]);
+        let paved = node(&[("highway", "crossing"), ("tactile_paving", "yes")]);
+
+        assert_eq!(Profile::Wheelchair.node_issues(&kerb),
+                   vec![Issue::RaisedKerb(7)]);
+        assert_eq!(Profile::Wheelchair.node_issues(&closed),
+                   vec![Issue::NotForWheelchairs(7)]);
+        assert_eq!(Profile::Wheelchair.node_issues(&crossing),
+                   vec![Issue::NoTactilePaving(7)]);
+        assert!(Profile::Wheelchair.node_issues(&paved).is_empty());
+
+        // A raised kerb blocks, a crossing without paving only costs more
+        assert!(!Profile::Wheelchair.passes(&kerb));
+        assert!(Profile::Wheelchair.passes(&crossing));
+        assert_eq!(Profile::Wheelchair.penalty(&crossing),
+                   super::DISCOMFORT_COST);
+        assert_eq!(Profile::Wheelchair.penalty(&paved), 0);
+        assert!(Profile::Foot.passes(&kerb));
+        assert_eq!(Profile::Foot.penalty(&crossing), 0);
+    }
+
+    #[test]
+    fn boards() {
+        // s2 says it can't board wheelchairs
+        let dir = env::temp_dir().join(format!("osm-boards-{}",
+                                               std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in &[
+            ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+             x,X,http://x,America/Los_Angeles\n"),
+            ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             all,1,1,1,1,1,1,1,20200101,20301231\n"),
+            ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon,wheelchair_boarding\n\
+             s1,One,37.70,-122.40,1\ns2,Two,37.71,-122.40,2\n"),
+            ("routes.txt", "route_id,route_short_name,route_long_name,route_type\n\
+             A,A,Alpha,3\n"),
+            ("trips.txt", "route_id,service_id,trip_id\nA,all,a1\n"),
+            ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             a1,08:00:00,08:00:00,s1,1\na1,08:05:00,08:05:00,s2,2\n")
+        ] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let mut map = gtfs::TransitMap::new();
+        let loaded = map.load(dir.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+
+        let date = gtfs::NaiveDate::from_ymd_opt(2020, 6, 3);
+        let boards = |id, profile: Profile| {
+            let stop = map.stop(id).unwrap();
+            let trip = stop.departures(date, 0).into_iter().next().unwrap();
+            profile.boards(stop, &trip)
+        };
+        assert!(boards("s1", Profile::Wheelchair));
+        assert!(!boards("s2", Profile::Wheelchair));
+        assert!(boards("s2", Profile::Foot));
+    }
+}
@@ -1,6 +1,6 @@
 use graph;
 use gtfs::TripCursor;
-use super::super::{Node, Way, Db};
+use super::super::{Node, Edge, Db, Profile, Snap};
 
 /* Walkers: strategy + how to walk and report data from the walked graphs */
 
@@ -8,7 +8,9 @@ use super::super::{Node, Way, Db};
 pub struct Explore<'a, P: Fn(&Node) -> bool> {
     pub max_cost: Option<u64>,
     pub predicate: P,
-    pub target: Option<&'a Node>
+    pub target: Option<&'a Node>,
+    pub profile: Profile,
+    pub via: Vec<&'a Snap> // virtual nodes we can walk through
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -32,7 +34,7 @@ for Explore<'a, P> {
 
     fn stop(&self, res: &graph::WalkResult<'a, Db, Self>) -> bool {
         match self.target {
-            Some(t) => res.steps.iter().any(|x| x.to == t),
+            Some(t) => res.steps().any(|x| x.to == t),
             None => false
         }
     }
@@ -46,12 +48,12 @@ for Explore<'a, P> {
 
     fn neighbors(&self, osm: &'a Db,
                      prev: &graph::Step<'a, Db, Self>)
-        -> Vec<(&'a Way, u64, u64, &'a Node, State<'a>)> {
+        -> Vec<(&'a Edge, u64, u64, &'a Node, State<'a>)> {
 
-            let mut neighbors = osm.neighbors(prev.to)
-            .iter()
+            let neighbors = osm.neighbors_via(prev.to, &self.via)
             // filter appropriate lanes
-            .filter(|(way, _, _)| way.is_highway())
+            .filter(|(edge, _, _)| self.profile.allows(osm.way_of(edge)))
+            .filter(|(_, _, node)| self.profile.passes(node))
             // remove edges if past max distance
             .filter(|(_, b, _)| match self.max_cost {
                 Some(cost) => prev.total + b <  cost,
@@ -66,26 +68,14 @@ for Explore<'a, P> {
                     state.found += 1;
                 }
 
-                (*a, *b/state.speed, h, *c, state)
+                (a, (b + self.profile.penalty(c))/state.speed, h, c, state)
             })
             .collect();
 
-            // If we're riding a bus now, simply add the
-            // next stop
-            // TODO
+            // XXX Boarding from here would cost every walk a lookup of
+            // the next trips at each entrance. Transit is planned apart,
+            // see Db::journeys and Db::transit_reach.
 
-            // If we're at a bus stop, maybe find the next
-            // departure
-            if let Some(stop) = osm.transit_stop(prev.to) {
-                //TODO - wait 5mn, then push cursor into state
-                for trip in stop.next_trips() {
-                    // way,cost,heuristic,node,state
-                    // push next stop with cost estimate + state
-                    // https://lichess.org/yiZxhfusDIkR
-                }
-            }
-
- 
             neighbors
     }
 }
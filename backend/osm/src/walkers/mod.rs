@@ -1 +1,2 @@
 pub mod explore;
+pub mod multimodal;
@@ -0,0 +1,233 @@
+use std::str::FromStr;
+
+use graph;
+use super::super::{Node, Edge, Db, Error, Profile, Snap, Way};
+use super::super::{WALK_SPEED, BIKE_SPEED, DRIVE_SPEED};
+
+/* Moving in several ways: each mode walks its own copy of the graph
+ * (graph_id), switching modes jumps from one to the other on the same
+ * node. Costs are in meters walked, like Explore. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Walk,
+    Bike, // shared, from a dock to another
+    Drive // from the start to a parking
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "walk" => Ok(Mode::Walk),
+            "bike" => Ok(Mode::Bike),
+            "drive" => Ok(Mode::Drive),
+            _ => Err(Error::UnknownMode(s.to_string()))
+        }
+    }
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Walk => "walk",
+            Mode::Bike => "bike",
+            Mode::Drive => "drive"
+        }
+    }
+
+    // Meters per second
+    fn speed(&self) -> f64 {
+        match self {
+            Mode::Walk => WALK_SPEED,
+            Mode::Bike => BIKE_SPEED,
+            Mode::Drive => DRIVE_SPEED
+        }
+    }
+
+    fn allows(&self, profile: Profile, way: &Way, edge: &Edge) -> bool {
+        match self {
+            Mode::Walk => profile.allows(way),
+            Mode::Bike => way.is_bikeable()
+                && (edge.forward || !way.is_oneway_for_bikes()),
+            Mode::Drive => way.is_drivable()
+                && (edge.forward || !way.is_oneway())
+        }
+    }
+}
+
+// Walks start in the car if we can drive, it stays where we park.
+// Without target, all the steps on foot are included.
+#[derive(Debug)]
+pub struct Multimodal<'a> {
+    pub target: Option<&'a Node>,
+    pub max_cost: Option<u64>,
+    pub profile: Profile, // when walking
+    pub modes: Vec<Mode>, // what we may switch to on the way
+    pub via: Vec<&'a Snap>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct State {
+    pub mode: Mode
+}
+
+impl<'a> Multimodal<'a> {
+    fn cost(&self, mode: Mode, dist: u64) -> u64 {
+        (dist as f64 * WALK_SPEED / mode.speed()) as u64
+    }
+
+    // As the crow flies at our best speed, so we never overestimate
+    fn heuristic(&self, node: &Node) -> u64 {
+        let fastest = self.modes.iter()
+            .map(|mode| mode.speed())
+            .fold(WALK_SPEED, f64::max);
+        self.target.map_or(0, |target|
+            (node.distance(target) as f64 * WALK_SPEED / fastest) as u64)
+    }
+
+    // Modes we can take at node, and what it costs to change
+    fn switches(&self, osm: &Db, node: &Node, mode: Mode) -> Vec<(Mode, u64)> {
+        let docks = osm.docks_at(node);
+        match mode {
+            Mode::Walk if self.modes.contains(&Mode::Bike)
+                && docks.iter().any(|dock| dock.rents()) => {
+                let unlock = osm.options().bike_unlock as f64 * WALK_SPEED;
+                vec![(Mode::Bike, unlock as u64)]
+            }
+            Mode::Bike if docks.iter().any(|dock| dock.returns()) =>
+                vec![(Mode::Walk, 0)],
+            Mode::Drive if osm.parking_at(node).is_some() => {
+                let park = osm.options().park_time as f64 * WALK_SPEED;
+                vec![(Mode::Walk, park as u64)]
+            }
+            _ => vec![]
+        }
+    }
+}
+
+impl<'a> graph::GraphWalker<'a, Db> for Multimodal<'a> {
+    type State = State;
+
+    fn graph_id(&self, state: &State) -> u64 {
+        state.mode as u64
+    }
+
+    fn initial_state(&self, _: &Node) -> State {
+        match self.modes.contains(&Mode::Drive) {
+            true => State { mode: Mode::Drive },
+            false => State { mode: Mode::Walk }
+        }
+    }
+
+    fn stop(&self, res: &graph::WalkResult<'a, Db, Self>) -> bool {
+        match self.target {
+            Some(target) => res.steps().any(|step| step.to == target),
+            None => false
+        }
+    }
+
+    // We get there on foot, bikes stay at docks and cars at parkings
+    fn include(&self, step: &graph::Step<'a, Db, Self>) -> bool {
+        step.state.mode == Mode::Walk && self.target.map_or(true, |target|
+            step.to == target)
+    }
+
+    fn neighbors(&self, osm: &'a Db, prev: &graph::Step<'a, Db, Self>)
+        -> Vec<(&'a Edge, u64, u64, &'a Node, State)> {
+        let mode = prev.state.mode;
+        let mut neighbors = osm.neighbors_via(prev.to, &self.via)
+            .filter(|(edge, _, _)| mode.allows(self.profile, osm.way_of(edge),
+                                               edge))
+            .filter(|(_, _, node)| mode != Mode::Walk
+                    || self.profile.passes(node))
+            .map(|(edge, dist, node)| {
+                let penalty = match mode {
+                    Mode::Walk => self.profile.penalty(node),
+                    _ => 0
+                };
+                (edge, self.cost(mode, dist) + penalty, self.heuristic(node),
+                 node, prev.state)
+            })
+            .collect::<Vec<_>>();
+
+        for (next, cost) in self.switches(osm, prev.to, mode) {
+            neighbors.push((osm.mode_switch(), cost, self.heuristic(prev.to),
+                            prev.to, State { mode: next }));
+        }
+
+        if let Some(max_cost) = self.max_cost {
+            neighbors.retain(|(_, cost, _, _, _)| prev.total + cost < max_cost);
+        }
+        neighbors
+    }
+}
+
+// A part of a path in a single mode
+pub struct Leg<'r, 'a> {
+    pub mode: Mode,
+    pub from: &'a Node, // where we switched to it
+    pub cost: u64,
+    pub steps: Vec<&'r graph::Step<'a, Db, Multimodal<'a>>>
+}
+
+// The path to step, cut where modes change, in walk order. Switches
+// don't move, they're only in the cost.
+pub fn legs<'r, 'a>(res: &'r graph::WalkResult<'a, Db, Multimodal<'a>>,
+                    step: &'r graph::Step<'a, Db, Multimodal<'a>>)
+    -> Vec<Leg<'r, 'a>> {
+    let mut path = res.path(step).collect::<Vec<_>>();
+    path.reverse();
+
+    split(path.into_iter().filter_map(|step| {
+        let prev = res.prev(step)?;
+        Some((step.state.mode, step.cost, prev.to, step.to, step))
+    }))
+        .into_iter()
+        .map(|(mode, from, cost, steps)| Leg { mode, from, cost, steps })
+        .collect()
+}
+
+// Same on (mode, cost, from, to, step) in walk order: each leg starts
+// where its first step does and keeps the steps that moved
+fn split<N, T>(path: impl Iterator<Item=(Mode, u64, N, N, T)>)
+    -> Vec<(Mode, N, u64, Vec<T>)> where N: PartialEq + Copy {
+    let mut legs: Vec<(Mode, N, u64, Vec<T>)> = vec![];
+    for (mode, cost, from, to, step) in path {
+        if legs.last().map(|leg| leg.0) != Some(mode) {
+            legs.push((mode, from, 0, vec![]));
+        }
+        if let Some(leg) = legs.last_mut() {
+            leg.2 += cost;
+            if from != to {
+                leg.3.push(step);
+            }
+        }
+    }
+    legs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mode::{Drive, Walk};
+
+    #[test]
+    fn split() {
+        // Drive 1 -> 2 -> 3, park at 3, walk 3 -> 4
+        let path = vec![(Drive, 10, 1, 2, "a"), (Drive, 5, 2, 3, "b"),
+                        (Walk, 300, 3, 3, "park"), (Walk, 20, 3, 4, "c")];
+        let legs = super::split(path.into_iter());
+        assert_eq!(legs, vec![(Drive, 1, 15, vec!["a", "b"]),
+                              (Walk, 3, 320, vec!["c"])]);
+    }
+
+    #[test]
+    fn split_one_mode() {
+        let path = vec![(Walk, 1, 1, 2, "a"), (Walk, 2, 2, 3, "b")];
+        assert_eq!(super::split(path.into_iter()),
+                   vec![(Walk, 1, 3, vec!["a", "b"])]);
+        assert!(super::split(Vec::<(_, _, u32, u32, ())>::new().into_iter())
+                .is_empty());
+    }
+}
@@ -1,26 +1,29 @@
+use geohash;
 use geojson;
 use graph;
+use gtfs;
 use osm;
 
 use super::converters::ApiFrom;
 
-use std::rc::Rc;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::thread;
 
 use super::reduce::Reduce;
+use super::error::ApiError;
+use super::router::Router;
 use osm::walkers;
 use rocket::State;
+use rocket::http::uri::Origin;
 
 use rocket_contrib::json::Json;
 
+use rayon::prelude::*;
+
 use serde::Deserialize;
 use serde::Serialize;
 
-const INITIAL_DIST:u64=150; // XXX this is a temp non optimal way to find
-                          // nodes with a connection to the graph from a POI
-
 // Api types
 //
 // Inputs:
@@ -42,16 +45,62 @@ pub enum Point {
     },
 }
 
+// A Point resolved on the graph: an existing node, or a virtual one
+// snapped on the closest edge that only lives for this request
+pub enum Located<'a> {
+    Node(&'a osm::Node),
+    Snapped(osm::Snap)
+}
+
+impl<'a> Located<'a> {
+    fn node(&self) -> &osm::Node {
+        match self {
+            Located::Node(node) => node,
+            Located::Snapped(snap) => &snap.node
+        }
+    }
+
+    fn snap(&self) -> Option<&osm::Snap> {
+        match self {
+            Located::Node(_) => None,
+            Located::Snapped(snap) => Some(snap)
+        }
+    }
+}
+
 impl Point {
-    fn get_node<'a>(&self, db: &'a osm::Db) -> Option<&'a osm::Node> {
+    // id is given to the virtual node if we snap, so it must be unique
+    // in the request (and negative not to collide with OSM)
+    fn locate<'a>(&self, db: &'a osm::Db, profile: osm::Profile, id: i64)
+        -> Result<Located<'a>, osm::Error> {
          match &self {
-            Point::Node { node_id } => db.node_by_id(*node_id),
+            Point::Node { node_id } =>
+                db.node(*node_id).map(Located::Node),
             Point::LonLat { lon, lat } =>
-                db.initial_node(*lat, *lon, INITIAL_DIST)
+                db.snap(*lat, *lon, db.options().snap_distance, profile, id)
+                    .map(Located::Snapped)
+                    .ok_or(osm::Error::NoGraphNode { lat: *lat, lon: *lon })
         }
     }
 }
 
+// The first point of the constraints picks the region
+fn route<'r>(router: &'r Router, constraints: &[PoiConstraint], uri: &Origin)
+    -> Result<&'r osm::Db, ApiError> {
+    constraints.iter()
+        .filter_map(|c| match c {
+            PoiConstraint::Near(point, _) |
+            PoiConstraint::OnTheWay(point, _) => Some(point),
+            _ => None
+        })
+        .next()
+        .map(|point| match point {
+            Point::Node { node_id } => router.with_node(*node_id),
+            Point::LonLat { lon, lat } => router.at(*lat, *lon, uri)
+        })
+        .unwrap_or_else(|| router.single())
+}
+
 #[derive(Debug, Deserialize)]
 pub enum PoiConstraint {
     OnTheWay(Point, Point),
@@ -65,12 +114,17 @@ pub struct MultiIc {
     poi: String,
     // This is almost optional since we'd start from the constraints
     // for most cases. Exception being: gimme a bar near a market.
-    constraints: Vec<PoiConstraint>
+    constraints: Vec<PoiConstraint>,
+    #[serde(default)]
+    profile: osm::Profile
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Score {
-    constraints: Vec<PoiConstraint>
+    constraints: Vec<PoiConstraint>,
+    #[serde(default)]
+    profile: osm::Profile,
+    precision: Option<usize> // geohash length of the squares
 }
 
 #[derive(Debug, Serialize)]
@@ -81,7 +135,9 @@ pub struct NodeDescription {
     lon: f64,
     lat: f64,
     kind: Vec<String>,
-    wheelchair: Option<bool>
+    wheelchair: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<u64>
 }
 
 impl ApiFrom<&osm::Node> for NodeDescription {
@@ -93,7 +149,8 @@ impl ApiFrom<&osm::Node> for NodeDescription {
             lon: node.lon,
             lat: node.lat,
             kind: node.poi_types().into_iter().map(|s| s.to_string()).collect(),
-            wheelchair: node.wheelchair()
+            wheelchair: node.wheelchair(),
+            distance: None
         }
     }
 }
@@ -110,6 +167,7 @@ pub struct MultiIcResult {
 #[derive(Debug, Serialize)]
 // This is a square on a map, with a score
 pub struct SquareScore {
+    id: String, // geohash
     top: f64,
     bottom: f64,
     left: f64,
@@ -124,45 +182,472 @@ pub struct ScoreResult {
     squares: Vec<SquareScore>
 }
 
-#[get("/path?<from>&<to>")]
-pub fn path(state: State<osm::Db>, from: i64, to: i64) -> Json<geojson::FeatureCollection<geojson::MultiLine>>
+// Defaults to foot
+fn parse_profile(profile: Option<String>) -> Result<osm::Profile, osm::Error> {
+    profile.map_or(Ok(osm::Profile::default()), |p| p.parse())
+}
+
+// The service date of the feeds, None for today where they are
+fn parse_date(date: Option<String>) -> Result<Option<gtfs::NaiveDate>, ApiError> {
+    date.map(|date| gtfs::parse_date(&date)
+             .ok_or(ApiError::Invalid(format!("bad date {}", date))))
+        .transpose()
+}
+
+// Modes we may switch to along a path, comma separated. bike is shared,
+// from a dock to another, drive starts in the car and leaves it at a
+// parking.
+fn parse_modes(modes: &str) -> Result<Vec<walkers::multimodal::Mode>, osm::Error> {
+    modes.split(',')
+        .filter(|mode| !mode.is_empty())
+        .map(|mode| mode.parse())
+        .collect()
+}
+
+// Where we switched to a mode: a dock, a parking or whatever the node is
+fn switch_name(osm: &osm::Db, node: &osm::Node) -> Option<String> {
+    osm.docks_at(node).iter()
+        .filter_map(|dock| dock.name.clone())
+        .next()
+        .or(osm.parking_at(node).and_then(|parking| parking.name()))
+        .or(node.name())
+}
+
+// A feature by leg, starting where we switched
+fn multimodal_path(osm: &osm::Db, from: &osm::Node, to: &osm::Node,
+                   profile: osm::Profile, modes: Vec<walkers::multimodal::Mode>)
+    -> Result<Json<geojson::FeatureCollection<geojson::MultiLine>>, ApiError>
 {
-    let osm = state.inner();
+    if profile == osm::Profile::Wheelchair
+        && modes.contains(&walkers::multimodal::Mode::Bike) {
+        return Err(ApiError::Invalid("bikes need the foot profile".to_string()));
+    }
 
-    let from = osm.node_by_id(from).unwrap();
-    let to = osm.node_by_id(to).unwrap();
+    let walker = walkers::multimodal::Multimodal {
+        target: Some(to),
+        max_cost: None,
+        profile: profile,
+        modes: modes,
+        via: vec![]
+    };
+
+    let res = graph::walk(osm, walker, from);
+    let step = match res.steps().min_by_key(|step| step.total) {
+        Some(step) => step,
+        None => return Err(osm.unreachable(from, to, profile).into())
+    };
+
+    let features = walkers::multimodal::legs(&res, step).iter().map(|leg| {
+        geojson::Feature {
+            properties: geojson::Properties {
+                name: switch_name(osm, leg.from),
+                node_id: Some(leg.from.id),
+                mode: Some(leg.mode.name().to_string()),
+                ..Default::default()
+            },
+            data: geojson::MultiLine {
+                data: leg.steps.iter()
+                    .map(|step| geojson::MultiLineSegment::api_from((osm, &res, *step)))
+                    .collect()
+            }
+        }
+    }).collect();
+    Ok(Json(geojson::FeatureCollection{ features }))
+}
+
+#[get("/path?<from>&<to>&<profile>&<modes>")]
+pub fn path(state: State<Router>, from: i64, to: i64, profile: Option<String>,
+            modes: Option<String>)
+    -> Result<Json<geojson::FeatureCollection<geojson::MultiLine>>, ApiError>
+{
+    let osm = state.inner().with_node(from)?;
+    let profile = parse_profile(profile)?;
+
+    let from = osm.node(from)?;
+    let to = osm.node(to)?;
+
+    if let Some(modes) = modes {
+        return multimodal_path(osm, from, to, profile, parse_modes(&modes)?);
+    }
 
     let walker = walkers::explore::Explore {
         max_cost: None,
         predicate: |x| x == to,
-        target: Some(to)
+        target: Some(to),
+        profile: profile,
+        via: vec![]
     };
 
-    let features = graph::walk(osm, walker, from).steps.iter().map(|step| 
-        geojson::Feature{
-            properties: geojson::Properties {
-                name: Some(format!("cost {}", step.total)),
-                node_id: Some(step.to.id)
+    let res = graph::walk(osm, walker, from);
+    let step = match res.steps().min_by_key(|step| step.total) {
+        Some(step) => step,
+        None => return Err(osm.unreachable(from, to, profile).into())
+    };
+
+    let issues = osm.path_issues(profile, &res, step);
+    let feature = geojson::Feature{
+        properties: geojson::Properties {
+            name: Some(format!("cost {}", step.total)),
+            node_id: Some(step.to.id),
+            accessible: Some(issues.iter().all(|issue| !issue.blocks())),
+            issues: issues.iter().map(|issue| issue.to_string()).collect(),
+            mode: Some("walk".to_string()),
+            ..Default::default()
+        },
+        data: geojson::MultiLine::api_from((osm, &res, step))
+    };
+    Ok(Json(geojson::FeatureCollection{ features: vec![feature] }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct StopDescription {
+    stop_id: String,
+    name: String,
+    lon: f64,
+    lat: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<u64>
+}
+
+impl ApiFrom<&gtfs::Stop> for StopDescription {
+    fn api_from(stop: &gtfs::Stop) -> Self {
+        StopDescription {
+            stop_id: stop.id().to_string(),
+            name: stop.name().to_string(),
+            lon: stop.get_lon(),
+            lat: stop.get_lat(),
+            distance: None
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LegDescription {
+    mode: &'static str, // walk, drive or transit
+    departure: String,  // HH:MM:SS
+    arrival: String,
+    // Walks only have the stop they start or end at, if any
+    stops: Vec<StopDescription>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trip_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headsign: Option<String>,
+    // Where a drive ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parking: Option<NodeDescription>
+}
+
+impl ApiFrom<&gtfs::Leg> for LegDescription {
+    fn api_from(leg: &gtfs::Leg) -> Self {
+        match leg {
+            gtfs::Leg::Walk { from, to, departure, arrival } => LegDescription {
+                mode: "walk",
+                departure: gtfs::format_time(*departure),
+                arrival: gtfs::format_time(*arrival),
+                stops: from.iter().chain(to.iter())
+                    .map(StopDescription::api_from)
+                    .collect(),
+                route_id: None,
+                trip_id: None,
+                route_name: None,
+                headsign: None,
+                parking: None
             },
-            data: geojson::MultiLine::api_from(step)
+            gtfs::Leg::Ride { route_id, trip_id, route_name, headsign, stops,
+                              departure, arrival, .. } =>
+                LegDescription {
+                    mode: "transit",
+                    departure: gtfs::format_time(*departure),
+                    arrival: gtfs::format_time(*arrival),
+                    stops: stops.iter().map(StopDescription::api_from).collect(),
+                    route_id: Some(route_id.clone()),
+                    trip_id: Some(trip_id.clone()),
+                    route_name: route_name.clone(),
+                    headsign: headsign.clone(),
+                    parking: None
+                }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JourneyDescription {
+    departure: String,
+    arrival: String,
+    rides: usize,
+    legs: Vec<LegDescription>,
+    paths: geojson::FeatureCollection<geojson::MultiLine> // a feature by leg
+}
+
+#[derive(Debug, Serialize)]
+pub struct JourneyResult {
+    journeys: Vec<JourneyDescription>
+}
+
+impl ApiFrom<(&gtfs::Journey, &osm::Node, &osm::Node)> for JourneyDescription {
+    fn api_from((journey, from, to): (&gtfs::Journey, &osm::Node, &osm::Node))
+        -> Self {
+        JourneyDescription {
+            departure: gtfs::format_time(journey.departure),
+            arrival: gtfs::format_time(journey.arrival),
+            rides: journey.rides,
+            legs: journey.legs.iter().map(LegDescription::api_from).collect(),
+            paths: geojson::FeatureCollection {
+                features: journey.legs.iter()
+                    .map(|leg| geojson::Feature::api_from((leg, from, to)))
+                    .collect()
+            }
         }
-    ).collect();
-    Json(geojson::FeatureCollection{ features })
+    }
+}
+
+// The first walk is driving to the parking then walking from it
+fn park_and_ride(journey: &gtfs::Journey, parked: &osm::Parked,
+                 from: &osm::Node, to: &osm::Node) -> JourneyDescription {
+    let mut description =
+        JourneyDescription::api_from((journey, parked.at, to));
+    let parking = journey.departure + parked.drive;
+
+    description.legs.insert(0, LegDescription {
+        mode: "drive",
+        departure: gtfs::format_time(journey.departure),
+        arrival: gtfs::format_time(parking),
+        stops: vec![],
+        route_id: None,
+        trip_id: None,
+        route_name: None,
+        headsign: None,
+        parking: Some(NodeDescription::api_from(parked.at))
+    });
+    description.legs[1].departure = gtfs::format_time(parking);
+
+    let mut drive = vec![geojson::GeoPoint::api_from(from)];
+    drive.extend(parked.shape.iter()
+                 .map(|(lon, lat)| geojson::GeoPoint(*lon, *lat)));
+    drive.push(geojson::GeoPoint::api_from(parked.at));
+    description.paths.features.insert(0, geojson::Feature {
+        properties: geojson::Properties {
+            name: parked.at.name(),
+            node_id: Some(parked.at.id),
+            mode: Some("drive".to_string()),
+            ..Default::default()
+        },
+        data: geojson::MultiLine {
+            data: vec![geojson::MultiLineSegment(drive)]
+        }
+    });
+    description
+}
+
+// Fastest transit journeys for each number of rides. depart_at is
+// HH:MM:SS in the feeds' time, on date (YYYY-MM-DD, today by default).
+// With park, we start driving and take transit from a parking.
+#[get("/journey?<from>&<to>&<depart_at>&<date>&<profile>&<park>")]
+pub fn journey(state: State<Router>, from: i64, to: i64, depart_at: String,
+               date: Option<String>, profile: Option<String>,
+               park: Option<bool>)
+    -> Result<Json<JourneyResult>, ApiError> {
+    let osm = state.inner().with_node(from)?;
+    let profile = parse_profile(profile)?;
+    let depart_at = gtfs::parse_time(&depart_at)
+        .ok_or(ApiError::Invalid(format!("bad time {}", depart_at)))?;
+    let date = parse_date(date)?;
+
+    let from = osm.closest_initial(from, osm.options().snap_distance, profile)?;
+    let to = osm.closest_initial(to, osm.options().snap_distance, profile)?;
+
+    if park.unwrap_or(false) {
+        let journeys = osm.park_and_ride(from, to, vec![], date, depart_at,
+                                         profile)?;
+        return Ok(Json(JourneyResult {
+            journeys: journeys.iter()
+                .map(|(journey, parked)|
+                     park_and_ride(journey, parked, from, to))
+                .collect()
+        }));
+    }
+
+    let journeys = osm.journeys(from, to, vec![], date, depart_at, profile)?;
+    Ok(Json(JourneyResult {
+        journeys: journeys.iter()
+            .map(|journey| JourneyDescription::api_from((journey, from, to)))
+            .collect()
+    }))
 }
 
-#[get("/isochrone?<node>&<dist>")]
-pub fn isochrone(state: State<osm::Db>, node: i64, dist: u64)
-    -> Json<geojson::MultiLine> {
-    let osm = state.inner();
+#[get("/isochrone?<node>&<dist>&<profile>")]
+pub fn isochrone(state: State<Router>, node: i64, dist: u64,
+                 profile: Option<String>)
+    -> Result<Json<geojson::MultiLine>, ApiError> {
+    let osm = state.inner().with_node(node)?;
+    let profile = parse_profile(profile)?;
 
     let walker = walkers::explore::Explore {
         max_cost: Some(dist),
         predicate: |x| true,
-        target: None
+        target: None,
+        profile: profile,
+        via: vec![]
     };
 
-    let node = osm.closest_initial(node, INITIAL_DIST).unwrap();
-    Json(geojson::MultiLine::api_from(graph::walk(osm, walker, node)))
+    let node = osm.closest_initial(node, osm.options().snap_distance, profile)?;
+    Ok(Json(geojson::MultiLine::api_from((osm, graph::walk(osm, walker, node)))))
+}
+
+#[derive(Debug, Serialize)]
+// A square we can get to, and the fastest way there
+pub struct ReachSquare {
+    id: String, // geohash
+    top: f64,
+    bottom: f64,
+    left: f64,
+    right: f64,
+    time: u32, // seconds
+    mode: &'static str, // walk or transit
+    rides: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<StopDescription> // where we get off
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransitIsochrone {
+    squares: Vec<ReachSquare>
+}
+
+// Longer isochrones walk most of a region, and transit runs out anyway
+const MAX_ISOCHRONE_MINUTES: u32 = 180;
+
+// Where we can be minutes (up to MAX_ISOCHRONE_MINUTES) after leaving node
+// at depart_at (HH:MM:SS) on date (as for journeys), walking and riding
+#[get("/isochrone/transit?<node>&<depart_at>&<date>&<minutes>&<profile>&<precision>")]
+pub fn transit_isochrone(state: State<Router>, node: i64, depart_at: String,
+                         date: Option<String>, minutes: u32,
+                         profile: Option<String>, precision: Option<usize>)
+    -> Result<Json<TransitIsochrone>, ApiError> {
+    let osm = state.inner().with_node(node)?;
+    let profile = parse_profile(profile)?;
+    let depart_at = gtfs::parse_time(&depart_at)
+        .ok_or(ApiError::Invalid(format!("bad time {}", depart_at)))?;
+    let date = parse_date(date)?;
+    let precision = precision.unwrap_or(osm.options().score_precision);
+
+    let node = osm.closest_initial(node, osm.options().snap_distance, profile)?;
+    let budget = minutes.min(MAX_ISOCHRONE_MINUTES) * 60;
+    let reach = osm.transit_reach(node, vec![], date, depart_at, budget,
+                                  profile);
+
+    // The fastest node of each square speaks for it
+    let mut squares: HashMap<String, &osm::Reach> = HashMap::new();
+    for (node, reach) in reach.iter() {
+        let hash = osm.square_hash(node.lat, node.lon, precision);
+        match squares.get(&hash) {
+            Some(best) if best.time <= reach.time => (),
+            _ => { squares.insert(hash, reach); }
+        }
+    }
+
+    Ok(Json(TransitIsochrone {
+        squares: squares.into_iter().map(|(hash, reach)| {
+            let gbox = geohash::geobox_for_hash(&hash);
+            ReachSquare {
+                id: hash,
+                top: gbox.y_range.max,
+                bottom: gbox.y_range.min,
+                left: gbox.x_range.min,
+                right: gbox.x_range.max,
+                time: reach.time,
+                mode: if reach.rides > 0 { "transit" } else { "walk" },
+                rides: reach.rides,
+                stop: reach.stop.as_ref().map(StopDescription::api_from)
+            }
+        }).collect()
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct StopList {
+    stops: Vec<StopDescription>
+}
+
+#[get("/stops?<lat>&<lon>&<radius>")]
+pub fn stops(state: State<Router>, uri: &Origin, lat: f64, lon: f64,
+             radius: u64) -> Result<Json<StopList>, ApiError> {
+    let osm = state.inner().at(lat, lon, uri)?;
+
+    Ok(Json(StopList {
+        stops: osm.stops_near(lat, lon, radius).into_iter()
+            .map(|(stop, dist)| {
+                let mut desc = StopDescription::api_from(stop);
+                desc.distance = Some(dist);
+                desc
+            })
+            .collect()
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DepartureDescription {
+    trip_id: String,
+    route_id: String,
+    route_name: Option<String>,
+    headsign: Option<String>,
+    color: Option<String>,
+    scheduled: String, // HH:MM:SS
+    departure: String, // with the delay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<i64> // seconds, when realtime tells
+}
+
+#[derive(Debug, Serialize)]
+pub struct DepartureBoard {
+    stop: StopDescription,
+    departures: Vec<DepartureDescription>,
+    alerts: Vec<String>
+}
+
+const DEPARTURES_PAGE_SIZE: usize = 10;
+
+// Soonest first from at (HH:MM:SS) on date (as for journeys), stops are
+// looked up in every region
+#[get("/stops/<id>/departures?<at>&<date>&<limit>")]
+pub fn departures(state: State<Router>, id: String, at: String,
+                  date: Option<String>, limit: Option<usize>)
+    -> Result<Json<DepartureBoard>, ApiError> {
+    let at = gtfs::parse_time(&at)
+        .ok_or(ApiError::Invalid(format!("bad time {}", at)))?;
+    let date = parse_date(date)?;
+    let stop = state.inner().locals()
+        .filter_map(|osm| osm.transit_stop(&id).ok())
+        .next()
+        .ok_or(osm::Error::UnknownStop(id.to_string()))?;
+
+    Ok(Json(DepartureBoard {
+        stop: StopDescription::api_from(stop),
+        departures: stop.departures(date, at).iter()
+            .filter_map(|trip| {
+                let route = stop.route(trip.route_id());
+                Some(DepartureDescription {
+                    trip_id: trip.trip_id().to_string(),
+                    route_id: trip.route_id().to_string(),
+                    route_name: route.and_then(|r| r.name())
+                        .map(|name| name.to_string()),
+                    headsign: trip.headsign().map(|h| h.to_string()),
+                    color: route.and_then(|r| r.color()),
+                    scheduled: gtfs::format_time(trip.scheduled_time()?),
+                    departure: gtfs::format_time(trip.departure_time()?),
+                    delay: trip.reported_delay()
+                })
+            })
+            .take(limit.unwrap_or(DEPARTURES_PAGE_SIZE))
+            .collect(),
+        alerts: stop.alerts(None).into_iter()
+            .filter_map(|alert| alert.header)
+            .collect()
+    }))
 }
 
 #[derive(Debug, Serialize)]
@@ -171,8 +656,9 @@ pub struct SearchResult {
 }
 
 #[get("/search?<q>&<lat>&<lon>")]
-pub fn search(state: State<osm::Db>, q: String, lat: f64, lon: f64) -> Json<SearchResult> {
-    let osm = state.inner();
+pub fn search(state: State<Router>, uri: &Origin, q: String, lat: f64, lon: f64)
+    -> Result<Json<SearchResult>, ApiError> {
+    let osm = state.inner().at(lat, lon, uri)?;
 
     let mut nodes = osm.node_index.matching(&q)
             .into_iter()
@@ -184,12 +670,12 @@ pub fn search(state: State<osm::Db>, q: String, lat: f64, lon: f64) -> Json<Sear
     // XXX Inject latency for test
     thread::sleep(Duration::from_millis(300));
 
-    Json(SearchResult {
+    Ok(Json(SearchResult {
         nodes: nodes.iter()
             .take(10)
             .map(|node| NodeDescription::api_from(node))
             .collect()
-    })
+    }))
 }
 
 #[derive(Serialize)]
@@ -197,37 +683,52 @@ pub struct PoiList {
     items: Vec<String>,
 }
 
+// Every type we know of, in any local region
 #[get("/pois")]
-pub fn pois(state: State<osm::Db>) -> Json<PoiList> {
-    let osm = state.inner();
+pub fn pois(state: State<Router>) -> Json<PoiList> {
+    let router = state.inner();
 
     // XXX Inject latency for test
     thread::sleep(Duration::from_millis(300));
 
-    Json(PoiList {items: osm.node_index.poi_types().iter()
+    Json(PoiList {items: router.locals()
+        .flat_map(|osm| osm.node_index.poi_types())
         .map(|s| s.to_string())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
         .collect()})
 }
 
-#[get("/pois/<kind>?<lat>&<lon>&<dist>")]
-pub fn nodes_of_poi(state: State<osm::Db>, kind: String,
-                    lat: f64, lon: f64, dist: u64) -> Json<SearchResult> {
-    let osm = state.inner();
-    //XXX We should use quadmap for spatial index queries instead
-    Json(SearchResult {
-        nodes: osm.node_index.of_poi(&kind)
-            .into_iter()
-            .filter_map(|id| osm.node_by_id(*id))
-            .filter(|node| node.distance_from(lat, lon) < dist)
-            .map(|node| NodeDescription::api_from(node))
+const POI_PAGE_SIZE:usize=50;
+// Bigger pages make huge answers, page through them with offset
+const MAX_POI_PAGE_SIZE: usize = 500;
+
+// Closest first - unknown kinds are a 404
+#[get("/pois/<kind>?<lat>&<lon>&<dist>&<offset>&<limit>")]
+pub fn nodes_of_poi(state: State<Router>, uri: &Origin, kind: String,
+                    lat: f64, lon: f64, dist: u64,
+                    offset: Option<usize>, limit: Option<usize>)
+    -> Result<Json<SearchResult>, ApiError> {
+    let osm = state.inner().at(lat, lon, uri)?;
+
+    Ok(Json(SearchResult {
+        nodes: osm.node_index.poi_within(&kind, lat, lon, dist)?
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(POI_PAGE_SIZE).min(MAX_POI_PAGE_SIZE))
+            .filter_map(|(id, dist)| osm.node_by_id(id).map(|node| {
+                let mut desc = NodeDescription::api_from(node);
+                desc.distance = Some(dist);
+                desc
+            }))
             .collect()
-    })
+    }))
 }
 
 #[post("/isochrone", data = "<params>")]
-pub fn multi_isochrone(state: State<osm::Db>, params: Json<MultiIc>)
-    -> Json<MultiIcResult> {
-    let osm = state.inner();
+pub fn multi_isochrone(state: State<Router>, uri: &Origin,
+                       params: Json<MultiIc>)
+    -> Result<Json<MultiIcResult>, ApiError> {
+    let osm = route(state.inner(), &params.constraints, uri)?;
 
     debug!("Multi Ic looking for {}", params.poi);
 
@@ -240,56 +741,102 @@ pub fn multi_isochrone(state: State<osm::Db>, params: Json<MultiIc>)
     let mut nodes = HashSet::new();
     let mut pois = HashSet::new();
     let predicate = |a: &osm::Node| a.is_poi_type(&params.poi);
+    let profile = params.profile;
 
-    for c in &params.constraints {
-        match c {
-            PoiConstraint::Near(point, max_cost) => {
-                let point = point.get_node(&osm).unwrap();
-                let walker = walkers::explore::Explore {
-                    max_cost: Some(*max_cost),
-                    predicate: predicate,
-                    target: None
-                };
-                let res = graph::walk(osm, walker, &point);
-                debug!("walking from {:?} cost {}", point, res.nr_inv);
-                res_vec.push(res);
-                nodes.insert(point);
+    // Resolve the points first, walks borrow them until we respond
+    let located = params.constraints.iter().enumerate().map(|(i, c)| {
+        let id = -2 * (i as i64) - 1;
+        Ok(match c {
+            PoiConstraint::Near(point, _) =>
+                vec![point.locate(osm, profile, id)?],
+            PoiConstraint::OnTheWay(a, b) => {
+                let mut a = a.locate(osm, profile, id)?;
+                let mut b = b.locate(osm, profile, id - 1)?;
+                if let (Located::Snapped(a), Located::Snapped(b)) =
+                    (&mut a, &mut b) {
+                    osm::Snap::link(a, b);
+                }
+                vec![a, b]
             },
-            /* This scans too many POIs */
-            PoiConstraint::DefinedByPoi(kind, max_cost) => {
-                for poi in osm.node_index.of_poi(&kind).iter()
-                    .filter_map(|id| osm.node_by_id(*id)) {
+            _ => vec![]
+        })
+    }).collect::<Result<Vec<Vec<Located>>, osm::Error>>()?;
+
+    // Constraints don't depend on each other: walk them in parallel
+    let walked = params.constraints.par_iter().zip(located.par_iter())
+        .map(|(c, located)| {
+            let via = located.iter()
+                .filter_map(|l| l.snap())
+                .collect::<Vec<&osm::Snap>>();
+            Ok(match c {
+                PoiConstraint::Near(_, max_cost) => {
+                    let point = located[0].node();
+                    let walker = walkers::explore::Explore {
+                        max_cost: Some(*max_cost),
+                        predicate: predicate,
+                        target: None,
+                        profile: profile,
+                        via: via
+                    };
+                    let res = graph::walk(osm, walker, point);
+                    debug!("walking from {:?} cost {}", point, res.nr_inv);
+                    Some((res, vec![point]))
+                },
+                PoiConstraint::OnTheWay(_, _) => {
+                    let a = located[0].node();
+                    let b = located[1].node();
+                    let walker = walkers::explore::Explore {
+                        max_cost: None,
+                        predicate: predicate,
+                        target: Some(b),
+                        profile: profile,
+                        via: via
+                    };
+                    let res = graph::walk(osm, walker, a);
+                    debug!("tracing from {:?} to {:?} cost {}", a, b,
+                           res.nr_inv);
+                    if !res.steps().any(|step| step.to == b) {
+                        return Err(osm::Error::Unreachable {
+                            from: a.id, to: b.id });
+                    }
+                    Some((res, vec![a, b]))
+                },
+                _ => None
+            })
+        }).collect::<Result<Vec<_>, osm::Error>>()?;
+
+    for (res, points) in walked.into_iter().filter_map(|walk| walk) {
+        res_vec.push(res);
+        nodes.extend(points);
+    }
+
+    /* This scans too many POIs */
+    for c in params.constraints.iter() {
+        if let PoiConstraint::DefinedByPoi(kind, max_cost) = c {
+            let walked = osm.node_index.of_poi(&kind)?.par_iter()
+                .filter_map(|id| osm.node_by_id(*id))
+                .map(|poi| {
                     let walker = walkers::explore::Explore {
                         max_cost: Some(*max_cost),
                         predicate: predicate, /* Needed for type -
                                                  wait for a refactor
                                                  */
-                        target: None
+                        target: None,
+                        profile: profile,
+                        via: vec![]
                     };
-                    let res = graph::walk(osm, walker, &poi);
+                    let res = graph::walk(osm, walker, poi);
                     debug!("walking from POI {:?} cost {}", poi, res.nr_inv);
-                    if (res.steps.iter().any(|x| predicate(x.to))) {
-                        debug!("POI {:?} matches NearPoi", poi);
-                        pois.insert(poi);
-                    }
-                    poi_vec.push(res);
+                    (poi, res)
+                }).collect::<Vec<_>>();
+
+            for (poi, res) in walked {
+                if (res.steps().any(|x| predicate(x.to))) {
+                    debug!("POI {:?} matches NearPoi", poi);
+                    pois.insert(poi);
                 }
-            },
-            PoiConstraint::OnTheWay(a, b) => {
-                let a = a.get_node(&osm).unwrap();
-                let b = b.get_node(&osm).unwrap();
-                let walker = walkers::explore::Explore {
-                    max_cost: None,
-                    predicate: predicate,
-                    target: Some(&b)
-                };
-                let res = graph::walk(osm, walker, &a);
-                debug!("tracing from {:?} to {:?} cost {}", a, b, res.nr_inv);
-                res_vec.push(res);
-                nodes.insert(a);
-                nodes.insert(b);
-            },
-            _ => {}
+                poi_vec.push(res);
+            }
         }
     }
 
@@ -301,16 +848,14 @@ pub fn multi_isochrone(state: State<osm::Db>, params: Json<MultiIc>)
 
 
     // Here we have points satisfying OnTheWay & Near
-    let steps = match explored {
-        Some(res) => res.steps,
-        None => vec![]
-    };
+    let steps = explored.iter()
+        .flat_map(|res| res.steps().map(move |step| (res, step)));
 
     let mut features = Vec::new();
 
     // And also: verify for each point that it has a wanted POI nearby,
     // if that is on our list
-    steps.iter().filter(|node| {
+    steps.filter(|(_, node)| {
         // Filter out NearPoi first
         params.constraints.iter()
         .map(|c| {
@@ -320,9 +865,11 @@ pub fn multi_isochrone(state: State<osm::Db>, params: Json<MultiIc>)
                                 max_cost: Some(*cost),
                                 predicate: |x| x.is_poi_type(&kind),
                                 target: None,
+                                profile: profile,
+                                via: vec![]
                     };
                     let res = graph::walk(osm, walker, node.to);
-                    res.steps.iter().map(|step| {
+                    res.steps().map(|step| {
                         debug!("NearPoi {:?} OK for {:?} => {:?}", c,
                                &node.to, &step.to);
                         nodes.insert(step.to);
@@ -332,36 +879,41 @@ pub fn multi_isochrone(state: State<osm::Db>, params: Json<MultiIc>)
                 _ => true
             }
         }).all(|x| x == true)
-    }).filter(|a| a.to.is_poi_type(&params.poi))
-        .inspect(|a| debug!("Adding Exp from step to {:?}", a.to))
-        .for_each(|a| {
+    }).filter(|(_, a)| a.to.is_poi_type(&params.poi))
+        .inspect(|(_, a)| debug!("Adding Exp from step to {:?}", a.to))
+        .for_each(|(res, a)| {
             pois.insert(a.to);
             features.push(geojson::Feature{
                 properties: geojson::Properties {
                     name: Some(format!("{:?} (cost {})", a.to.name(),
                     a.total)),
-                    node_id: Some(a.to.id)
+                    node_id: Some(a.to.id),
+                    ..Default::default()
                 },
-                data: geojson::MultiLine::api_from(a)
+                data: geojson::MultiLine::api_from((osm, res, a))
             });
         });
 
-    Json(MultiIcResult {
+    Ok(Json(MultiIcResult {
         points: pois.iter().map(|a| NodeDescription::api_from(a)).collect(),
         nodes: nodes.iter().map(|a| NodeDescription::api_from(a)).collect(),
         paths: geojson::FeatureCollection {
             features
         }
-    })
+    }))
 }
 
-#[post("/square?<lat>&<lon>")]
-pub fn square(state: State<osm::Db>, lat: f64, lon: f64) -> Option<Json<SquareScore>>
+#[post("/square?<lat>&<lon>&<precision>")]
+pub fn square(state: State<Router>, uri: &Origin, lat: f64, lon: f64,
+              precision: Option<usize>)
+    -> Result<Option<Json<SquareScore>>, ApiError>
 {
-    let osm = state.inner();
+    let osm = state.inner().at(lat, lon, uri)?;
 
-    let n = osm.scores.get(&osm.node_index.square_for(lat, lon).id);
-    n.map(|score| Json(SquareScore {
+    let hash = osm.square_hash(lat, lon, precision.unwrap_or(
+            osm.options().score_precision));
+    Ok(osm.square(&hash).map(|score| Json(SquareScore {
+        id: score.hash.clone(),
         left: score.left,
         right: score.right,
         bottom: score.bottom,
@@ -369,13 +921,54 @@ pub fn square(state: State<osm::Db>, lat: f64, lon: f64) -> Option<Json<SquareSc
         score: 0,
         features: score.scores.keys().cloned().collect(),
         costs: score.scores.clone()
+    })))
+}
+
+// Squares around a point - for frontends caching tiles by geohash
+#[get("/squares?<lat>&<lon>&<radius>")]
+pub fn squares(state: State<Router>, uri: &Origin, lat: f64, lon: f64,
+               radius: u64) -> Result<Json<ScoreResult>, ApiError>
+{
+    let osm = state.inner().at(lat, lon, uri)?;
+
+    Ok(Json(ScoreResult {
+        squares: osm.squares_within(lat, lon, radius).into_iter()
+            .map(|n| SquareScore {
+                id: n.hash.clone(),
+                left: n.left,
+                right: n.right,
+                bottom: n.bottom,
+                top: n.top,
+                score: 0,
+                features: n.scores.keys().cloned().collect(),
+                costs: n.scores.clone()
+            }).collect()
     }))
 }
 
+#[get("/squares/<hash>")]
+pub fn square_by_hash(state: State<Router>, uri: &Origin, hash: String)
+    -> Result<Option<Json<SquareScore>>, ApiError>
+{
+    let osm = state.inner().for_hash(&hash, uri)?;
+
+    Ok(osm.square(&hash).map(|score| Json(SquareScore {
+        id: score.hash.clone(),
+        left: score.left,
+        right: score.right,
+        bottom: score.bottom,
+        top: score.top,
+        score: 0,
+        features: score.scores.keys().cloned().collect(),
+        costs: score.scores.clone()
+    })))
+}
+
 #[post("/score", data = "<params>")]
-pub fn score(state: State<osm::Db>, params: Json<Score>) -> Json<ScoreResult>
+pub fn score(state: State<Router>, uri: &Origin, params: Json<Score>)
+    -> Result<Json<ScoreResult>, ApiError>
 {
-    let osm = state.inner();
+    let osm = route(state.inner(), &params.constraints, uri)?;
 
     let mut squares = HashSet::new();
     let mut wanted_pois = HashMap::new();
@@ -395,20 +988,25 @@ pub fn score(state: State<osm::Db>, params: Json<Score>) -> Json<ScoreResult>
 
     // TODO Poi costs, scores, square_for, etc. has no interface in OSM,
     // create one instead of raw accessing everything
-    node_cons.iter()
-        .map(|(point, dist)| {
-            let node = point.get_node(&osm).unwrap();
+    let located = node_cons.iter().enumerate()
+        .map(|(i, (point, _))|
+             point.locate(osm, params.profile, -(i as i64) - 1))
+        .collect::<Result<Vec<Located>, osm::Error>>()?;
+
+    node_cons.par_iter().zip(located.par_iter())
+        .map(|((_, dist), located)| {
             let walker = walkers::explore::Explore {
                 max_cost: Some(**dist),
                 predicate: |_| true,
-                target: None
+                target: None,
+                profile: params.profile,
+                via: located.snap().into_iter().collect()
             };
-            graph::walk(osm, walker, node)
-        }).reduce(|a, b| a&b)
-        .map(|res| res.steps.iter().for_each(|step| {
-            squares.insert(
-                osm.node_index.square_for(step.to.lat, step.to.lon).id
-            );
+            graph::walk(osm, walker, located.node())
+        }).reduce_with(|a, b| a&b)
+        .map(|res| res.steps().for_each(|step| {
+            squares.insert(osm.square_hash(step.to.lat, step.to.lon,
+                params.precision.unwrap_or(osm.options().score_precision)));
         }));
 
     // TODO Again here - hardcoded 1500,...
@@ -422,18 +1020,19 @@ pub fn score(state: State<osm::Db>, params: Json<Score>) -> Json<ScoreResult>
             .sum()
     };
 
-    Json(ScoreResult {
-        squares: squares.iter().filter_map(|n| osm.scores.get(&n))
+    Ok(Json(ScoreResult {
+        squares: squares.iter().filter_map(|hash| osm.square(hash))
             .map(|n| SquareScore {
+                id: n.hash.clone(),
                 left: n.left,
                 right: n.right,
                 bottom: n.bottom,
                 top: n.top,
-                score: score_fn(n),
+                score: score_fn(&n),
                 features: n.scores.keys().cloned().filter(|key|
                               wanted_pois.contains_key(&key)
                 ).collect(),
                 costs: n.scores.clone()
             }).collect()
-    })
+    })))
 }
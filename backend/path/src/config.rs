@@ -0,0 +1,393 @@
+use osm;
+
+use std::fs;
+
+use serde::Deserialize;
+
+// The server configuration, from a TOML file (see config.example.toml)
+// and/or the command line. Anything on the command line wins.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: String,
+    pub cors_origins: Vec<String>, // empty allows any origin
+    pub profile: ProfileConfig,
+    pub precompute: PrecomputeConfig,
+    pub transit: TransitConfig,
+    #[serde(rename = "region")]
+    pub regions: Vec<RegionConfig>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegionConfig {
+    pub name: String,
+    #[serde(default)]
+    pub pbf: Vec<String>, // overlapping extracts are merged
+    #[serde(default)]
+    pub gtfs: Vec<String>,
+    #[serde(default)]
+    pub realtime: Vec<String>, // GTFS-Realtime files or http:// urls
+    #[serde(default)]
+    pub gbfs: Vec<String>, // bike share snapshots, OSM docks without
+    pub remote: Option<String>, // url of the backend serving that region
+    #[serde(default)]
+    pub prefixes: Vec<String> // geohash prefixes a remote covers
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub snap_distance: u64,
+    pub connect_distance: u64,
+    pub min_component: usize
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PrecomputeConfig {
+    pub scores: bool,
+    pub score_precision: usize,
+    pub score_max_cost: u64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TransitConfig {
+    pub realtime_interval: u64,
+    pub transit_walk: u64,
+    pub max_rides: usize,
+    pub bike_unlock: u64,
+    pub park_time: u64,
+    pub max_drive: u64
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "localhost:8000".to_string(),
+            cors_origins: vec![],
+            profile: ProfileConfig::default(),
+            precompute: PrecomputeConfig::default(),
+            transit: TransitConfig::default(),
+            regions: vec![]
+        }
+    }
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        let options = osm::Options::default();
+        ProfileConfig {
+            snap_distance: options.snap_distance,
+            connect_distance: options.connect_distance,
+            min_component: options.min_component
+        }
+    }
+}
+
+impl Default for PrecomputeConfig {
+    fn default() -> Self {
+        let options = osm::Options::default();
+        PrecomputeConfig {
+            scores: options.scores,
+            score_precision: options.score_precision,
+            score_max_cost: options.score_max_cost
+        }
+    }
+}
+
+impl Default for TransitConfig {
+    fn default() -> Self {
+        let options = osm::Options::default();
+        TransitConfig {
+            realtime_interval: options.realtime_interval,
+            transit_walk: options.transit_walk,
+            max_rides: options.max_rides,
+            bike_unlock: options.bike_unlock,
+            park_time: options.park_time,
+            max_drive: options.max_drive
+        }
+    }
+}
+
+pub const USAGE: &str = "usage: path [validate] [options]
+    validate                check the GTFS feeds of the regions and exit,
+                            printing a JSON line by feed
+    --help                  print this and exit
+    --config FILE           read FILE (TOML) first
+    --listen HOST:PORT
+    --cors ORIGIN           allowed origin, repeat for more
+    --region SPEC           name=map.osm.pbf[,more.pbf,feed.zip,gbfs.json...]
+                            or name@9q8,9q9=http://host:port
+    --pbf FILE              shortcut for a single local region
+    --gtfs FILE             GTFS feed of that region
+    --realtime SOURCE       GTFS-Realtime file or http:// url of that region
+    --gbfs FILE             bike share station information or status
+    --realtime-interval S   seconds between realtime reloads
+    --transit-walk M        how far we walk to and from stops
+    --max-rides N           rides in a journey
+    --bike-unlock S         seconds to get a shared bike out
+    --park-time S           seconds to park and get out of the car
+    --max-drive S           how long we drive to park and ride
+    --snap-distance M
+    --connect-distance M
+    --min-component N       smaller graph islands are not routable
+    --scores | --no-scores  precompute POI score squares or not
+    --score-precision N     geohash length of the score squares
+    --score-max-cost M";
+
+// Anywhere on the line, before anything else is checked
+pub fn wants_help(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--help")
+}
+
+fn parse<T: std::str::FromStr>(opt: &str, value: Option<String>)
+    -> Result<T, String> {
+    value.ok_or(format!("{} needs a value", opt))?
+        .parse()
+        .map_err(|_| format!("bad value for {}", opt))
+}
+
+impl RegionConfig {
+    // name=files or name@prefixes=url
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.splitn(2, '=');
+        let (name, what) = match (parts.next(), parts.next()) {
+            (Some(name), Some(what)) => (name, what),
+            _ => return Err(format!("bad region {}", spec))
+        };
+
+        let mut name_parts = name.splitn(2, '@');
+        let name = name_parts.next().unwrap_or("").to_string();
+        Ok(match name_parts.next() {
+            Some(prefixes) => RegionConfig {
+                name: name,
+                pbf: vec![],
+                gtfs: vec![],
+                realtime: vec![],
+                gbfs: vec![],
+                remote: Some(what.to_string()),
+                prefixes: prefixes.split(',').map(String::from).collect()
+            },
+            None => {
+                let (pbf, rest): (Vec<String>, Vec<String>) = what.split(',')
+                    .map(String::from)
+                    .partition(|file| file.ends_with(".pbf"));
+                let (gbfs, gtfs) = rest.into_iter()
+                    .partition(|file| file.ends_with(".json"));
+                RegionConfig {
+                    name: name,
+                    pbf: pbf,
+                    gtfs: gtfs,
+                    realtime: vec![],
+                    gbfs: gbfs,
+                    remote: None,
+                    prefixes: vec![]
+                }
+            }
+        })
+    }
+}
+
+impl Config {
+    pub fn load(file: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(file)
+            .map_err(|e| format!("can't read {}: {}", file, e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("can't parse {}: {}", file, e))
+    }
+
+    pub fn from_args<I: Iterator<Item=String>>(args: I) -> Result<Self, String> {
+        let args = args.collect::<Vec<String>>();
+
+        let mut config = match args.iter().position(|a| a == "--config") {
+            Some(i) => Config::load(args.get(i + 1)
+                                    .ok_or("--config needs a value")?)?,
+            None => Config::default()
+        };
+
+        let mut cors = vec![];
+        let mut regions = vec![];
+        let mut pbf = vec![];
+        let mut gtfs = vec![];
+        let mut realtime = vec![];
+        let mut gbfs = vec![];
+
+        let mut args = args.into_iter();
+        while let Some(opt) = args.next() {
+            match opt.as_str() {
+                "--config" => { args.next(); },
+                "--listen" => config.listen = parse(&opt, args.next())?,
+                "--cors" => cors.push(parse(&opt, args.next())?),
+                "--region" => regions.push(RegionConfig::parse(
+                        &parse::<String>(&opt, args.next())?)?),
+                "--pbf" => pbf.push(parse(&opt, args.next())?),
+                "--gtfs" => gtfs.push(parse(&opt, args.next())?),
+                "--realtime" => realtime.push(parse(&opt, args.next())?),
+                "--gbfs" => gbfs.push(parse(&opt, args.next())?),
+                "--realtime-interval" =>
+                    config.transit.realtime_interval = parse(&opt, args.next())?,
+                "--transit-walk" =>
+                    config.transit.transit_walk = parse(&opt, args.next())?,
+                "--max-rides" =>
+                    config.transit.max_rides = parse(&opt, args.next())?,
+                "--bike-unlock" =>
+                    config.transit.bike_unlock = parse(&opt, args.next())?,
+                "--park-time" =>
+                    config.transit.park_time = parse(&opt, args.next())?,
+                "--max-drive" =>
+                    config.transit.max_drive = parse(&opt, args.next())?,
+                "--snap-distance" =>
+                    config.profile.snap_distance = parse(&opt, args.next())?,
+                "--connect-distance" =>
+                    config.profile.connect_distance = parse(&opt, args.next())?,
+                "--min-component" =>
+                    config.profile.min_component = parse(&opt, args.next())?,
+                "--scores" => config.precompute.scores = true,
+                "--no-scores" => config.precompute.scores = false,
+                "--score-precision" =>
+                    config.precompute.score_precision = parse(&opt, args.next())?,
+                "--score-max-cost" =>
+                    config.precompute.score_max_cost = parse(&opt, args.next())?,
+                _ => return Err(format!("unknown option {}", opt))
+            }
+        }
+
+        if !cors.is_empty() {
+            config.cors_origins = cors;
+        }
+        if !pbf.is_empty() {
+            regions.push(RegionConfig {
+                name: "local".to_string(),
+                pbf: pbf,
+                gtfs: gtfs,
+                realtime: realtime,
+                gbfs: gbfs,
+                remote: None,
+                prefixes: vec![]
+            });
+        }
+        if !regions.is_empty() {
+            config.regions = regions;
+        }
+        if config.regions.is_empty() {
+            config.regions.push(RegionConfig::parse(
+                    "local=map.osm.pbf,gtfs/gtfs.zip")?);
+        }
+
+        Ok(config)
+    }
+
+    pub fn options(&self) -> osm::Options {
+        osm::Options {
+            scores: self.precompute.scores,
+            score_precision: self.precompute.score_precision,
+            score_max_cost: self.precompute.score_max_cost,
+            snap_distance: self.profile.snap_distance,
+            connect_distance: self.profile.connect_distance,
+            min_component: self.profile.min_component,
+            realtime_interval: self.transit.realtime_interval,
+            transit_walk: self.transit.transit_walk,
+            max_rides: self.transit.max_rides,
+            bike_unlock: self.transit.bike_unlock,
+            park_time: self.transit.park_time,
+            max_drive: self.transit.max_drive
+        }
+    }
+
+    // Rocket wants them apart
+    pub fn address(&self) -> Result<(String, u16), String> {
+        let mut parts = self.listen.rsplitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(port), Some(host)) => Ok((host.to_string(),
+                port.parse().map_err(|_| format!("bad port in {}",
+                                                 self.listen))?)),
+            _ => Err(format!("bad listen address {}", self.listen))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wants_help, Config, RegionConfig};
+
+    fn args(line: &str) -> impl Iterator<Item=String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn region_files() {
+        let region = RegionConfig::parse(
+            "sf=bay.osm.pbf,muni.zip,more.osm.pbf,bart.zip,gbfs.json").unwrap();
+        assert_eq!(region.name, "sf");
+        assert_eq!(region.pbf, vec!["bay.osm.pbf", "more.osm.pbf"]);
+        assert_eq!(region.gtfs, vec!["muni.zip", "bart.zip"]);
+        assert_eq!(region.gbfs, vec!["gbfs.json"]);
+        assert!(region.remote.is_none());
+
+        let remote = RegionConfig::parse("la@9q5,9qh=http://la:8000").unwrap();
+        assert_eq!(remote.name, "la");
+        assert_eq!(remote.remote.as_deref(), Some("http://la:8000"));
+        assert_eq!(remote.prefixes, vec!["9q5", "9qh"]);
+        assert!(remote.pbf.is_empty() && remote.gtfs.is_empty());
+
+        assert!(RegionConfig::parse("sf").is_err());
+    }
+
+    #[test]
+    fn from_args() {
+        let config = Config::from_args(args(
+                "--pbf bay.osm.pbf --gtfs muni.zip --cors http://a --max-rides 2"))
+            .unwrap();
+        assert_eq!(config.regions.len(), 1);
+        assert_eq!(config.regions[0].name, "local");
+        assert_eq!(config.regions[0].pbf, vec!["bay.osm.pbf"]);
+        assert_eq!(config.regions[0].gtfs, vec!["muni.zip"]);
+        assert_eq!(config.cors_origins, vec!["http://a"]);
+        assert_eq!(config.transit.max_rides, 2);
+
+        // The default region
+        let config = Config::from_args(args("")).unwrap();
+        assert_eq!(config.regions[0].pbf, vec!["map.osm.pbf"]);
+        assert_eq!(config.regions[0].gtfs, vec!["gtfs/gtfs.zip"]);
+
+        assert!(Config::from_args(args("--max-rides two")).is_err());
+        assert!(Config::from_args(args("--max-rides")).is_err());
+        assert!(Config::from_args(args("--nope")).is_err());
+    }
+
+    #[test]
+    fn missing_file() {
+        let err = Config::from_args(args("--config /nonexistent/path.toml"))
+            .unwrap_err();
+        assert!(err.starts_with("can't read /nonexistent/path.toml"), "{}", err);
+        assert!(Config::from_args(args("--config")).is_err());
+    }
+
+    #[test]
+    fn address() {
+        let config = Config::from_args(args("--listen 0.0.0.0:8080")).unwrap();
+        assert_eq!(config.address(), Ok(("0.0.0.0".to_string(), 8080)));
+        let config = Config::from_args(args("--listen [::1]:80")).unwrap();
+        assert_eq!(config.address(), Ok(("[::1]".to_string(), 80)));
+
+        let config = Config::from_args(args("--listen localhost:http")).unwrap();
+        assert_eq!(config.address(),
+                   Err("bad port in localhost:http".to_string()));
+        let config = Config::from_args(args("--listen localhost:99999")).unwrap();
+        assert!(config.address().is_err());
+        let config = Config::from_args(args("--listen localhost")).unwrap();
+        assert_eq!(config.address(),
+                   Err("bad listen address localhost".to_string()));
+    }
+
+    #[test]
+    fn help() {
+        let line = |line: &str| args(line).collect::<Vec<_>>();
+        assert!(wants_help(&line("--help")));
+        assert!(wants_help(&line("validate --pbf map.osm.pbf --help")));
+        assert!(!wants_help(&line("--pbf map.osm.pbf")));
+        // Not an option from_args knows, main handles it first
+        assert!(Config::from_args(args("--help")).is_err());
+    }
+}
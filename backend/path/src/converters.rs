@@ -1,7 +1,6 @@
 use geojson;
 use graph;
-
-use std::rc::Rc;
+use gtfs;
 
 use super::api::NodeDescription;
 
@@ -41,42 +40,88 @@ impl ApiFrom<&osm::Node> for geojson::Feature<geojson::Point> {
             properties: geojson::Properties {
                 node_id: Some(node.id),
                 name: node.name().map(String::from),
+                ..Default::default()
             },
             data: geojson::Point::api_from(node)
         }
     }
 }
 
-impl<'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<&graph::Step<'a, osm::Db, W>> for geojson::MultiLineSegment {
-    fn api_from(step: &graph::Step<'a, osm::Db, W>) -> Self {
-        let from_node = match &step.from {
+// A step needs its walk to know where it comes from, and the Db for the
+// shape of the edge it took
+impl<'r, 'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<(&'a osm::Db, &'r graph::WalkResult<'a, osm::Db, W>, &'r graph::Step<'a, osm::Db, W>)> for geojson::MultiLineSegment {
+    fn api_from((db, res, step): (&'a osm::Db, &'r graph::WalkResult<'a, osm::Db, W>, &'r graph::Step<'a, osm::Db, W>)) -> Self {
+        let from_node = match res.prev(step) {
             Some(prev_step) => prev_step.to,
             None => step.to
         };
-        geojson::MultiLineSegment(
-            geojson::GeoPoint::api_from(from_node),
-            geojson::GeoPoint::api_from(step.to)
-        )
+        let mut line = vec![geojson::GeoPoint::api_from(from_node)];
+        if let Some(edge) = step.edge {
+            line.extend(db.edge_shape(edge).into_iter()
+                        .map(|(lon, lat)| geojson::GeoPoint(lon, lat)));
+        }
+        line.push(geojson::GeoPoint::api_from(step.to));
+        geojson::MultiLineSegment(line)
     }
 }
 
-impl<'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<graph::WalkResult<'a, osm::Db, W>> for geojson::MultiLine {
-    fn api_from(res: graph::WalkResult<'a, osm::Db, W>) -> Self {
+impl<'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<(&'a osm::Db, graph::WalkResult<'a, osm::Db, W>)> for geojson::MultiLine {
+    fn api_from((db, res): (&'a osm::Db, graph::WalkResult<'a, osm::Db, W>)) -> Self {
         geojson::MultiLine {
-            data: res.steps.iter()
+            data: res.steps()
                 .filter(|step| step.from.is_some())
-                .map(|step| geojson::MultiLineSegment::api_from(step))
+                .map(|step| geojson::MultiLineSegment::api_from((db, &res, step)))
                 .collect(),
         }
     }
 }
 
-impl<'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<&'a Rc<graph::Step<'a, osm::Db, W>>> for geojson::MultiLine {
-    fn api_from(step: &'a Rc<graph::Step<'a, osm::Db, W>>) -> Self {
+// The path to a step
+impl<'r, 'a, W: 'a + graph::GraphWalker<'a, osm::Db>> ApiFrom<(&'a osm::Db, &'r graph::WalkResult<'a, osm::Db, W>, &'r graph::Step<'a, osm::Db, W>)> for geojson::MultiLine {
+    fn api_from((db, res, step): (&'a osm::Db, &'r graph::WalkResult<'a, osm::Db, W>, &'r graph::Step<'a, osm::Db, W>)) -> Self {
         geojson::MultiLine {
-            data: step.into_iter()
-                .map(|prev| geojson::MultiLineSegment::api_from(prev))
+            data: res.path(step)
+                .map(|prev| geojson::MultiLineSegment::api_from((db, res, prev)))
                 .collect()
         }
     }
 }
+
+// A leg of a journey, walks that start or end it go from or to the nodes.
+// We don't keep the way we walk to transit, walks are straight.
+impl ApiFrom<(&gtfs::Leg, &osm::Node, &osm::Node)> for geojson::Feature<geojson::MultiLine> {
+    fn api_from((leg, from, to): (&gtfs::Leg, &osm::Node, &osm::Node)) -> Self {
+        let at = |stop: &Option<gtfs::Stop>, node: &osm::Node| stop.as_ref()
+            .map_or(geojson::GeoPoint::api_from(node),
+                    |stop| geojson::GeoPoint(stop.get_lon(), stop.get_lat()));
+        match leg {
+            gtfs::Leg::Walk { from: start, to: end, .. } => geojson::Feature {
+                properties: geojson::Properties {
+                    mode: Some("walk".to_string()),
+                    ..Default::default()
+                },
+                data: geojson::MultiLine {
+                    data: vec![geojson::MultiLineSegment(
+                        vec![at(start, from), at(end, to)])]
+                }
+            },
+            gtfs::Leg::Ride { route_name, headsign, color, text_color, shape,
+                              .. } => geojson::Feature {
+                properties: geojson::Properties {
+                    name: route_name.clone(),
+                    mode: Some("transit".to_string()),
+                    route: route_name.clone(),
+                    headsign: headsign.clone(),
+                    color: color.clone(),
+                    text_color: text_color.clone(),
+                    ..Default::default()
+                },
+                data: geojson::MultiLine {
+                    data: vec![geojson::MultiLineSegment(shape.iter()
+                        .map(|(lon, lat)| geojson::GeoPoint(*lon, *lat))
+                        .collect())]
+                }
+            }
+        }
+    }
+}
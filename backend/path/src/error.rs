@@ -0,0 +1,101 @@
+use osm;
+
+use std::fmt;
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status, Redirect, Responder};
+
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+// Everything a handler can fail with. Db errors keep their variant so
+// they map to a status, the rest comes from routing between regions.
+#[derive(Debug)]
+pub enum ApiError {
+    Osm(osm::Error),
+    Invalid(String), // a parameter we can't read
+    Outside(String), // no region holds that
+    Remote(String) // another backend does, redirect there
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: &'static str, // stable, for clients to match on
+    message: String
+}
+
+impl ApiError {
+    fn status(&self) -> Status {
+        match self {
+            ApiError::Osm(osm::Error::UnknownNode(_)) |
+            ApiError::Osm(osm::Error::UnknownPoiKind(_)) |
+            ApiError::Osm(osm::Error::UnknownStop(_)) |
+            ApiError::Outside(_) => Status::NotFound,
+            ApiError::Osm(osm::Error::UnknownProfile(_)) |
+            ApiError::Osm(osm::Error::UnknownMode(_)) |
+            ApiError::Invalid(_) => Status::BadRequest,
+            ApiError::Osm(osm::Error::NoGraphNode { .. }) |
+            ApiError::Osm(osm::Error::Unreachable { .. }) |
+            ApiError::Osm(osm::Error::Inaccessible { .. }) =>
+                Status::UnprocessableEntity,
+            ApiError::Osm(osm::Error::Gtfs(_, _)) |
+            ApiError::Osm(osm::Error::Gbfs(_, _)) |
+            ApiError::Osm(osm::Error::EmptyMap) =>
+                Status::InternalServerError,
+            ApiError::Remote(_) => Status::TemporaryRedirect
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Osm(osm::Error::UnknownNode(_)) => "unknown_node",
+            ApiError::Osm(osm::Error::NoGraphNode { .. }) => "no_graph_node",
+            ApiError::Osm(osm::Error::UnknownPoiKind(_)) => "unknown_poi_kind",
+            ApiError::Osm(osm::Error::Unreachable { .. }) => "unreachable",
+            ApiError::Osm(osm::Error::Inaccessible { .. }) => "inaccessible",
+            ApiError::Osm(osm::Error::UnknownProfile(_)) => "unknown_profile",
+            ApiError::Osm(osm::Error::UnknownStop(_)) => "unknown_stop",
+            ApiError::Osm(osm::Error::UnknownMode(_)) => "unknown_mode",
+            ApiError::Osm(osm::Error::Gtfs(_, _)) => "gtfs",
+            ApiError::Osm(osm::Error::Gbfs(_, _)) => "gbfs",
+            ApiError::Osm(osm::Error::EmptyMap) => "empty_map",
+            ApiError::Invalid(_) => "invalid",
+            ApiError::Outside(_) => "outside",
+            ApiError::Remote(_) => "remote"
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Osm(err) => write!(f, "{}", err),
+            ApiError::Invalid(reason) |
+            ApiError::Outside(reason) => write!(f, "{}", reason),
+            ApiError::Remote(url) => write!(f, "served by {}", url)
+        }
+    }
+}
+
+impl From<osm::Error> for ApiError {
+    fn from(err: osm::Error) -> Self {
+        ApiError::Osm(err)
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        match self {
+            ApiError::Remote(url) => Redirect::temporary(url).respond_to(req),
+            err => {
+                debug!("{} failed: {}", req.uri(), err);
+                status::Custom(err.status(), Json(ErrorBody {
+                    error: err.kind(),
+                    message: err.to_string()
+                })).respond_to(req)
+            }
+        }
+    }
+}
@@ -4,10 +4,15 @@ extern crate rocket;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate rayon;
 
 mod api;
 mod reduce;
 mod converters;
+mod router;
+mod config;
+mod error;
+mod validate;
 
 use std::time::{Duration, Instant};
 /*
@@ -15,12 +20,68 @@ use graph;
 use osm;
 */
 
-use rocket_cors::CorsOptions;
+use rocket_cors::{AllowedOrigins, CorsOptions};
+
+// Bad configuration, nothing is loaded yet
+fn usage_error(err: &str) -> ! {
+    eprintln!("{}\n{}", err, config::USAGE);
+    std::process::exit(1);
+}
 
 pub fn main() {
     env_logger::init();
 
-    let mut osm_db = osm::Db::new("map.osm.pbf", vec!["gtfs/gtfs.zip"]);
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if config::wants_help(&args) {
+        println!("whe.re path server\n{}", config::USAGE);
+        return;
+    }
+
+    let validate = args.first().map_or(false, |arg| arg == "validate");
+    let args = args.into_iter().skip(if validate { 1 } else { 0 });
+
+    let config = match config::Config::from_args(args) {
+        Ok(config) => config,
+        Err(err) => usage_error(&err)
+    };
+
+    if validate {
+        std::process::exit(if validate::run(&config) { 0 } else { 1 });
+    }
+
+    let cors = match (CorsOptions {
+        allowed_origins: if config.cors_origins.is_empty() {
+            AllowedOrigins::all()
+        } else {
+            AllowedOrigins::some_exact(&config.cors_origins)
+        },
+        ..Default::default()
+    }).to_cors() {
+        Ok(cors) => cors,
+        Err(err) => usage_error(&format!("bad cors origins: {}", err))
+    };
+
+    let (address, port) = match config.address() {
+        Ok(address) => address,
+        Err(err) => usage_error(&err)
+    };
+
+    let mut router = router::Router::new();
+    for region in &config.regions {
+        match &region.remote {
+            Some(url) => router.add_remote(&region.name,
+                                           region.prefixes.clone(), url),
+            None => match osm::Db::new(&region.pbf, &region.gtfs,
+                                       &region.realtime, &region.gbfs,
+                                       config.options()) {
+                Ok(db) => router.add_local(&region.name, db),
+                Err(err) => {
+                    error!("Can't load region {}: {}", region.name, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
 
     /* Alamo Square Cafe to Family
     let node0 = osm_db.node_by_id(65325380).unwrap();
@@ -42,18 +103,29 @@ pub fn main() {
                       node0, node1, start.elapsed(), path.steps.len());
     */
 
-    let cors = CorsOptions::default().to_cors().unwrap();
+    let rocket_config = rocket::Config::build(
+        rocket::config::Environment::active().unwrap())
+        .address(address)
+        .port(port)
+        .finalize()
+        .unwrap();
 
-    rocket::ignite()
-        .manage(osm_db)
+    rocket::custom(rocket_config)
+        .manage(router)
         .attach(cors)
         .mount("/graph", routes![api::isochrone,
+                                 api::transit_isochrone,
                                  api::search,
                                  api::path,
+                                 api::journey,
                                  api::pois,
                                  api::nodes_of_poi,
+                                 api::stops,
+                                 api::departures,
                                  api::multi_isochrone,
                                  api::square,
+                                 api::squares,
+                                 api::square_by_hash,
                                  api::score])
         .launch();
 }
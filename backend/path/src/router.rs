@@ -0,0 +1,232 @@
+use geohash;
+use osm;
+
+use std::collections::HashSet;
+
+use rocket::http::uri::Origin;
+
+use super::error::ApiError;
+
+// Regions are matched on geohash prefixes of this length - ~40x20km
+const ROUTE_PRECISION:usize=4;
+
+pub enum Backend {
+    Local(Box<osm::Db>),
+    Remote(String) // base url of another path server
+}
+
+pub struct Region {
+    pub name: String,
+    prefixes: HashSet<String>,
+    pub backend: Backend
+}
+
+impl Region {
+    // Hashes shorter than our prefixes are bigger boxes, holding some
+    fn covers(&self, hash: &str) -> bool {
+        self.prefixes.iter().any(|prefix| hash.starts_with(prefix.as_str())
+                                 || prefix.starts_with(hash))
+    }
+}
+
+// Dispatch requests to the Db loaded for a city, by the geohash of
+// their coordinates. Regions served by other backends get a redirect,
+// keeping the method and body for POSTs.
+pub struct Router {
+    regions: Vec<Region>
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router { regions: Vec::new() }
+    }
+
+    pub fn add_local(&mut self, name: &str, db: osm::Db) {
+        let bbox = db.bbox();
+        let prefixes = geohash::cover_bbox(
+            &geohash::GeoBox::new(bbox.min_x, bbox.max_x,
+                                  bbox.min_y, bbox.max_y),
+            ROUTE_PRECISION);
+        info!("Region {} is local, covering {:?}", name, prefixes);
+        self.regions.push(Region {
+            name: name.to_string(),
+            prefixes: prefixes,
+            backend: Backend::Local(Box::new(db))
+        });
+    }
+
+    pub fn add_remote(&mut self, name: &str, prefixes: Vec<String>,
+                      url: &str) {
+        info!("Region {} is at {}, covering {:?}", name, url, prefixes);
+        self.regions.push(Region {
+            name: name.to_string(),
+            prefixes: prefixes.into_iter().collect(),
+            backend: Backend::Remote(url.trim_end_matches('/').to_string())
+        });
+    }
+
+    pub fn locals(&self) -> impl Iterator<Item=&osm::Db> {
+        self.regions.iter().filter_map(|region| match &region.backend {
+            Backend::Local(db) => Some(db.as_ref()),
+            Backend::Remote(_) => None
+        })
+    }
+
+    fn serve<'a>(region: &'a Region, uri: &Origin)
+        -> Result<&'a osm::Db, ApiError> {
+        match &region.backend {
+            Backend::Local(db) => Ok(db),
+            Backend::Remote(url) => {
+                debug!("{} is served by {} at {}", uri, region.name, url);
+                Err(ApiError::Remote(format!("{}{}", url, uri)))
+            }
+        }
+    }
+
+    // Region for a geohash or a prefix of one. Local Dbs win when their
+    // box really holds the point, prefixes overlap on borders.
+    pub fn for_hash(&self, hash: &str, uri: &Origin)
+        -> Result<&osm::Db, ApiError> {
+        let gbox = geohash::geobox_for_hash(hash);
+        let (lat, lon) = ((gbox.y_range.min + gbox.y_range.max) / 2.0,
+                          (gbox.x_range.min + gbox.x_range.max) / 2.0);
+        let inside = |region: &&Region| match &region.backend {
+            Backend::Local(db) => db.contains(lat, lon),
+            Backend::Remote(_) => false
+        };
+
+        self.regions.iter().filter(|region| region.covers(hash))
+            .find(inside)
+            .or_else(|| self.regions.iter().find(|region| region.covers(hash)))
+            .ok_or_else(|| ApiError::Outside(
+                    format!("{} is outside of all regions", hash)))
+            .and_then(|region| Self::serve(region, uri))
+    }
+
+    pub fn at(&self, lat: f64, lon: f64, uri: &Origin)
+        -> Result<&osm::Db, ApiError> {
+        self.for_hash(&geohash::hash(lat, lon, 12), uri)
+            .map_err(|err| match err {
+                ApiError::Outside(_) => ApiError::Outside(
+                    format!("{},{} is outside of all regions", lat, lon)),
+                err => err
+            })
+    }
+
+    // Node ids don't say where they are: only local Dbs can answer
+    pub fn with_node(&self, id: i64) -> Result<&osm::Db, ApiError> {
+        self.locals().find(|db| db.node_by_id(id).is_some())
+            .ok_or(ApiError::Osm(osm::Error::UnknownNode(id)))
+    }
+
+    // For requests without a position, when there's no doubt
+    pub fn single(&self) -> Result<&osm::Db, ApiError> {
+        match self.locals().collect::<Vec<&osm::Db>>().as_slice() {
+            [db] => Ok(db),
+            _ => Err(ApiError::Outside(
+                    "this needs a position to pick a region".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osm::{Db, Node, Options, Way};
+
+    use rocket::http::uri::Origin;
+    use rocket::http::Status;
+    use rocket::local::Client;
+    use rocket::response::Responder;
+
+    use super::{ApiError, Router};
+
+    // A street between two nodes
+    fn db(ids: (i64, i64), a: (f64, f64), b: (f64, f64)) -> Db {
+        Db::from_elements(
+            vec![Node::new(ids.0, a.0, a.1, &[]), Node::new(ids.1, b.0, b.1, &[])],
+            vec![Way::new(ids.0 * 100, vec![ids.0, ids.1],
+                          &[("highway", "residential")])],
+            Options { scores: false, min_component: 1, ..Default::default() })
+            .unwrap()
+    }
+
+    // San Francisco is all in 9q8y, the east bay region starts there
+    // too, on the bridge, and goes on in 9q9p. LA is elsewhere.
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.add_local("sf", db((1, 2), (37.77, -122.42), (37.78, -122.41)));
+        router.add_local("east", db((11, 12), (37.785, -122.35), (37.80, -122.27)));
+        router.add_remote("la", vec!["9q5".to_string()], "http://la:8000/");
+        router
+    }
+
+    fn name(db: &Db) -> &'static str {
+        if db.node_by_id(1).is_some() { "sf" } else { "east" }
+    }
+
+    #[test]
+    fn at() {
+        let router = router();
+        let uri = Origin::parse("/graph/stops").unwrap();
+        assert_eq!(name(router.at(37.775, -122.415, &uri).unwrap()), "sf");
+        // Both cover 9q8y, east holds the point
+        assert_eq!(name(router.at(37.786, -122.348, &uri).unwrap()), "east");
+        assert_eq!(name(router.at(37.80, -122.27, &uri).unwrap()), "east");
+
+        match router.at(48.85, 2.35, &uri) {
+            Err(ApiError::Outside(reason)) =>
+                assert_eq!(reason, "48.85,2.35 is outside of all regions"),
+            _ => panic!("Paris is not in a region")
+        }
+    }
+
+    #[test]
+    fn for_hash() {
+        let router = router();
+        let uri = Origin::parse("/graph/squares").unwrap();
+        assert_eq!(name(router.for_hash("9q8yy7", &uri).unwrap()), "sf");
+        assert_eq!(name(router.for_hash("9q9p19", &uri).unwrap()), "east");
+
+        // Shorter than ROUTE_PRECISION: any region in the box, the
+        // first one when none holds its center
+        assert_eq!(name(router.for_hash("9q8y", &uri).unwrap()), "sf");
+        assert_eq!(name(router.for_hash("9q8", &uri).unwrap()), "sf");
+        assert_eq!(name(router.for_hash("9q9", &uri).unwrap()), "east");
+        assert_eq!(name(router.for_hash("9", &uri).unwrap()), "sf");
+        match router.for_hash("u09", &uri) {
+            Err(ApiError::Outside(reason)) =>
+                assert_eq!(reason, "u09 is outside of all regions"),
+            _ => panic!("u09 is not in a region")
+        }
+    }
+
+    #[test]
+    fn remote() {
+        let router = router();
+        let uri = Origin::parse("/graph/stops?lat=34.05&lon=-118.25").unwrap();
+        let err = match router.at(34.05, -118.25, &uri) {
+            Err(err) => err,
+            Ok(_) => panic!("LA is not local")
+        };
+
+        let client = Client::new(rocket::ignite()).unwrap();
+        let req = client.get("/graph/stops?lat=34.05&lon=-118.25");
+        let res = err.respond_to(req.inner()).unwrap();
+        assert_eq!(res.status(), Status::TemporaryRedirect);
+        assert_eq!(res.headers().get_one("Location"),
+                   Some("http://la:8000/graph/stops?lat=34.05&lon=-118.25"));
+
+        let uri = Origin::parse("/graph/squares/9q5c").unwrap();
+        match router.for_hash("9q5c", &uri) {
+            Err(ApiError::Remote(url)) =>
+                assert_eq!(url, "http://la:8000/graph/squares/9q5c"),
+            _ => panic!("9q5c is in LA")
+        }
+    }
+}
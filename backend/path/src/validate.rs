@@ -0,0 +1,49 @@
+use gtfs;
+use osm;
+
+use serde::Serialize;
+use serde_json::json;
+
+use super::config::Config;
+
+#[derive(Serialize)]
+struct RegionReport<'a> {
+    region: &'a str,
+    #[serde(flatten)]
+    report: &'a gtfs::Report
+}
+
+// Check the GTFS feeds of the local regions against their maps, printing
+// a JSON line by feed. False if any has problems or can't be read.
+pub fn run(config: &Config) -> bool {
+    let mut ok = true;
+    for region in config.regions.iter().filter(|r| r.remote.is_none()) {
+        let bbox = osm::Db::extent(&region.pbf);
+        if bbox.is_none() {
+            warn!("No map for region {}, stops can't be out of it",
+                  region.name);
+        }
+
+        for feed in &region.gtfs {
+            let line = match gtfs::validate(feed, bbox.as_ref()) {
+                Ok(report) => {
+                    ok &= report.is_ok();
+                    serde_json::to_string(&RegionReport {
+                        region: &region.name,
+                        report: &report
+                    })
+                },
+                Err(err) => {
+                    ok = false;
+                    serde_json::to_string(&json!({
+                        "region": region.name,
+                        "feed": feed,
+                        "error": err.to_string()
+                    }))
+                }
+            };
+            println!("{}", line.expect("Reports are plain data"));
+        }
+    }
+    ok
+}
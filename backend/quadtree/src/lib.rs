@@ -7,12 +7,71 @@ use std::iter;
 use std::borrow::Borrow;
 use std::cmp::Ordering::Equal;
 
+mod nearest;
+pub mod rtree;
+
+use nearest::{BestFirst, Branch, Candidate};
+
 // A point in our hash
 pub trait Point where Self: Sized {
     fn get_x(&self) -> f64;
     fn get_y(&self) -> f64;
 }
 
+// Distance in meters between two x/y (lon/lat) positions
+pub fn distance(x1: f64, y1: f64, x2: f64, y2: f64) -> f64 {
+    let phi1 = y1.to_radians();
+    let phi2 = y2.to_radians();
+    let dphi = (y2 - y1).to_radians();
+    let dlam = (x2 - x1).to_radians();
+    let a = (dphi / 2.0).sin().powi(2) +
+        phi1.cos() * phi2.cos() * (dlam / 2.0).sin().powi(2);
+    let c = 2.0 * (a.sqrt().atan2((1.0 - a).sqrt()));
+    6371000.0 * c //  mean radius of earth
+}
+
+// A lon/lat aligned box - x is lon, y is lat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BBox {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64
+}
+
+impl BBox {
+    pub fn new(min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Self {
+        BBox { min_x, min_y, max_x, max_y }
+    }
+
+    // The box enclosing a circle of radius meters around x/y
+    pub fn around(x: f64, y: f64, meters: f64) -> Self {
+        let dy = (meters / 6371000.0).to_degrees();
+        let dx = dy / y.to_radians().cos().max(0.000001);
+        BBox::new(x - dx, y - dy, x + dx, y + dy)
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        self.min_x <= x && x <= self.max_x &&
+            self.min_y <= y && y <= self.max_y
+    }
+
+    pub fn intersects(&self, other: &BBox) -> bool {
+        self.min_x <= other.max_x && other.min_x <= self.max_x &&
+            self.min_y <= other.max_y && other.min_y <= self.max_y
+    }
+
+    // Distance in meters from x/y to the closest point of the box,
+    // 0 if inside
+    pub fn distance_to(&self, x: f64, y: f64) -> f64 {
+        if self.contains(x, y) {
+            return 0.0;
+        }
+        distance(x, y, x.max(self.min_x).min(self.max_x),
+                 y.max(self.min_y).min(self.max_y))
+    }
+}
+
 #[derive(Debug)]
 pub struct QuadCell<T: Point> {
     pub id: u64,
@@ -68,6 +127,66 @@ impl<T: Point> QuadTree<T> {
     pub fn walk(&self) -> impl Iterator<Item=&QuadCell<T>> {
         self.root.walk()
     }
+
+    // All values sorted by distance from x/y, lazily: neighbouring cells
+    // are only visited when they can hold something closer than what's
+    // left in the queue.
+    pub fn around(&self, x: f64, y: f64) -> Nearest<T> {
+        Nearest::new(&self.root, x, y, None)
+    }
+
+    // The k closest values with their distance in meters
+    pub fn nearest(&self, x: f64, y: f64, k: usize)
+        -> impl Iterator<Item=(&T, f64)> {
+        self.around(x, y).take(k)
+    }
+
+    // Values within meters of x/y, closest first
+    pub fn within_radius(&self, x: f64, y: f64, meters: f64) -> Nearest<T> {
+        Nearest::new(&self.root, x, y, Some(meters))
+    }
+
+    // Values inside bbox, in no particular order
+    pub fn within_bbox(&self, bbox: BBox) -> impl Iterator<Item=&T> {
+        self.root.walk_bbox(bbox)
+            .flat_map(|cell| cell.values.iter().flatten())
+            .filter(move |v| bbox.contains(v.get_x(), v.get_y()))
+    }
+}
+
+// All values of the tree, closest first
+pub struct Nearest<'a, T: Point>(BestFirst<'a, QuadCell<T>>);
+
+impl<'a, T: Point> Nearest<'a, T> {
+    fn new(root: &'a QuadCell<T>, x: f64, y: f64, max_dist: Option<f64>)
+        -> Self {
+        let mut it = BestFirst::new(x, y, max_dist);
+        it.push(root.bbox().distance_to(x, y), Candidate::Node(root));
+        Nearest(it)
+    }
+}
+
+impl<'a, T: Point> Iterator for Nearest<'a, T> {
+    type Item = (&'a T, f64);
+
+    fn next(&mut self) -> Option<(&'a T, f64)> {
+        self.0.next()
+    }
+}
+
+impl<T: Point> Branch for QuadCell<T> {
+    type Value = T;
+
+    fn expand<'a>(&'a self, x: f64, y: f64, queue: &mut BestFirst<'a, Self>) {
+        for v in self.values.iter().flatten() {
+            queue.push(distance(x, y, v.get_x(), v.get_y()),
+                       Candidate::Value(v));
+        }
+        for child in self.left.iter().chain(self.right.iter()) {
+            queue.push(child.bbox().distance_to(x, y),
+                       Candidate::Node(child));
+        }
+    }
 }
 
 impl<T: Point> QuadCell<T> {
@@ -103,6 +222,24 @@ impl<T: Point> QuadCell<T> {
         Box::new(left_iter.chain(right_iter))
     }
 
+    // Same as walk, skipping cells that don't intersect bbox
+    fn walk_bbox<'a>(&'a self, bbox: BBox)
+        -> Box<dyn Iterator<Item = &'a QuadCell<T>> + 'a> {
+        if !self.bbox().intersects(&bbox) {
+            return Box::new(iter::empty());
+        }
+
+        match (&self.left, &self.right) {
+            (Some(left), Some(right)) => Box::new(
+                left.walk_bbox(bbox).chain(right.walk_bbox(bbox))),
+            _ => Box::new(iter::once(self))
+        }
+    }
+
+    pub fn bbox(&self) -> BBox {
+        BBox::new(self.x, self.y, self.x + self.xsize, self.y + self.ysize)
+    }
+
     // Add a value into the current v
     fn insert(&mut self, v: T) {
         trace!("inserting T({},{}) in {},{}/{},{}", &v.get_x(), &v.get_y(),
@@ -380,4 +517,63 @@ mod tests {
         assert_eq!(x.walk()
            .collect::<Vec<&super::QuadCell<SomePoint>>>().len(), 16)
     }
+
+    // A 0.01 degree grid around SF, 1 value per cell
+    fn grid() -> QuadTree<SomePoint> {
+        let mut x = QuadTree::<SomePoint>::of_capacity(-122.5, 37.7,
+                                                       0.04, 0.04, 1);
+        for a in 0..4 {
+            for b in 0..4 {
+                x.insert(SomePoint {
+                    x: -122.495 + 0.01 * a as f64,
+                    y: 37.705 + 0.01 * b as f64
+                });
+            }
+        }
+        x
+    }
+
+    #[test]
+    fn nearest_across_cells() {
+        let x = grid();
+        // Right on the border of two cells, closer to the right one
+        let found = x.nearest(-122.4899, 37.705, 2)
+            .map(|(pt, _)| (pt.x, pt.y))
+            .collect::<Vec<(f64, f64)>>();
+        assert_eq!(found, vec![(-122.485, 37.705), (-122.495, 37.705)]);
+    }
+
+    #[test]
+    fn around_sorted() {
+        let x = grid();
+        let dists = x.around(-122.47, 37.72)
+            .map(|(_, dist)| dist)
+            .collect::<Vec<f64>>();
+        assert_eq!(dists.len(), 16);
+        assert!(dists.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn within_radius() {
+        let x = grid();
+        // 0.01 lon is ~880m here, 0.01 lat ~1110m
+        let found = x.within_radius(-122.495, 37.705, 1000.0)
+            .map(|(_, dist)| dist as u64)
+            .collect::<Vec<u64>>();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0], 0);
+        assert!(found[1] > 800 && found[1] < 1000);
+    }
+
+    #[test]
+    fn within_bbox() {
+        let x = grid();
+        let bbox = super::BBox::new(-122.49, 37.70, -122.47, 37.72);
+        let mut found = x.within_bbox(bbox)
+            .map(|pt| (pt.x, pt.y))
+            .collect::<Vec<(f64, f64)>>();
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[0], (-122.485, 37.705));
+    }
 }
@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+use std::cmp::Ordering::Equal;
+use std::collections::BinaryHeap;
+
+// A tree node we can walk best-first: it pushes its children and values
+// with their distance to x/y
+pub trait Branch where Self: Sized {
+    type Value;
+
+    fn expand<'a>(&'a self, x: f64, y: f64, queue: &mut BestFirst<'a, Self>);
+}
+
+pub enum Candidate<'a, N: Branch> {
+    Node(&'a N),
+    Value(&'a N::Value)
+}
+
+struct Queued<'a, N: Branch> {
+    dist: f64,
+    item: Candidate<'a, N>
+}
+
+impl<'a, N: Branch> PartialEq for Queued<'a, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist == other.dist
+    }
+}
+
+impl<'a, N: Branch> Eq for Queued<'a, N> {}
+
+impl<'a, N: Branch> PartialOrd for Queued<'a, N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, N: Branch> Ord for Queued<'a, N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for a minqueue
+        other.dist.partial_cmp(&self.dist).unwrap_or(Equal)
+    }
+}
+
+// Best-first iterator over a tree: nodes are queued with the distance to
+// their box, values with their own distance. Whatever pops out first is
+// the closest thing left.
+pub struct BestFirst<'a, N: Branch> {
+    x: f64,
+    y: f64,
+    max_dist: Option<f64>,
+    queue: BinaryHeap<Queued<'a, N>>
+}
+
+impl<'a, N: Branch> BestFirst<'a, N> {
+    pub fn new(x: f64, y: f64, max_dist: Option<f64>) -> Self {
+        BestFirst { x, y, max_dist, queue: BinaryHeap::new() }
+    }
+
+    pub fn push(&mut self, dist: f64, item: Candidate<'a, N>) {
+        match self.max_dist {
+            Some(max) if dist > max => {},
+            _ => self.queue.push(Queued { dist, item })
+        }
+    }
+}
+
+impl<'a, N: Branch> Iterator for BestFirst<'a, N> {
+    type Item = (&'a N::Value, f64);
+
+    fn next(&mut self) -> Option<(&'a N::Value, f64)> {
+        while let Some(queued) = self.queue.pop() {
+            match queued.item {
+                Candidate::Value(v) => return Some((v, queued.dist)),
+                Candidate::Node(node) => node.expand(self.x, self.y, self)
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,412 @@
+use std::cmp::Ordering::Equal;
+
+use super::{distance, BBox};
+use super::nearest::{BestFirst, Branch, Candidate};
+
+// Anything with a bounding box - segments, shapes
+pub trait Bounded where Self: Sized {
+    fn bbox(&self) -> BBox;
+
+    // Distance in meters from x/y to the item. Defaults to its box,
+    // lines should override so nearest() sorts on the real geometry.
+    fn distance_to(&self, x: f64, y: f64) -> f64 {
+        self.bbox().distance_to(x, y)
+    }
+}
+
+#[derive(Debug)]
+enum RNode<T: Bounded> {
+    Leaf(BBox, Vec<T>),
+    Inner(BBox, Vec<RNode<T>>)
+}
+
+impl<T: Bounded> RNode<T> {
+    fn bbox(&self) -> BBox {
+        match self {
+            RNode::Leaf(bbox, _) => *bbox,
+            RNode::Inner(bbox, _) => *bbox
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            RNode::Leaf(_, values) => values.len(),
+            RNode::Inner(_, children) => children.len()
+        }
+    }
+
+    // Insert down the child needing the least enlargement, returns the
+    // new sibling if we had to split
+    fn insert(&mut self, v: T, capacity: usize) -> Option<RNode<T>> {
+        let vbox = v.bbox();
+        match self {
+            RNode::Leaf(bbox, values) => {
+                *bbox = union(bbox, &vbox);
+                values.push(v);
+            },
+            RNode::Inner(bbox, children) => {
+                *bbox = union(bbox, &vbox);
+                let best = children.iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)|
+                            enlargement(&a.bbox(), &vbox)
+                            .partial_cmp(&enlargement(&b.bbox(), &vbox))
+                            .unwrap_or(Equal))
+                    .map(|(i, _)| i)
+                    .unwrap();
+                if let Some(sibling) = children[best].insert(v, capacity) {
+                    children.push(sibling);
+                }
+            }
+        }
+
+        if self.len() > capacity {
+            Some(self.split())
+        } else {
+            None
+        }
+    }
+
+    // Sort along the widest axis and move the upper half to a sibling
+    fn split(&mut self) -> RNode<T> {
+        let bbox = self.bbox();
+        let on_x = bbox.max_x - bbox.min_x >= bbox.max_y - bbox.min_y;
+        match self {
+            RNode::Leaf(bbox, values) => {
+                sort_by_center(values, |v| v.bbox(), on_x);
+                let upper = values.split_off(values.len() / 2);
+                *bbox = bbox_of(values.iter().map(|v| v.bbox()));
+                RNode::Leaf(bbox_of(upper.iter().map(|v| v.bbox())), upper)
+            },
+            RNode::Inner(bbox, children) => {
+                sort_by_center(children, |c| c.bbox(), on_x);
+                let upper = children.split_off(children.len() / 2);
+                *bbox = bbox_of(children.iter().map(|c| c.bbox()));
+                RNode::Inner(bbox_of(upper.iter().map(|c| c.bbox())), upper)
+            }
+        }
+    }
+}
+
+fn union(a: &BBox, b: &BBox) -> BBox {
+    BBox::new(a.min_x.min(b.min_x), a.min_y.min(b.min_y),
+              a.max_x.max(b.max_x), a.max_y.max(b.max_y))
+}
+
+fn area(a: &BBox) -> f64 {
+    (a.max_x - a.min_x) * (a.max_y - a.min_y)
+}
+
+fn enlargement(a: &BBox, b: &BBox) -> f64 {
+    area(&union(a, b)) - area(a)
+}
+
+fn bbox_of<I: Iterator<Item=BBox>>(mut boxes: I) -> BBox {
+    let first = boxes.next().unwrap_or(BBox::new(0.0, 0.0, 0.0, 0.0));
+    boxes.fold(first, |acc, b| union(&acc, &b))
+}
+
+fn sort_by_center<V, F: Fn(&V) -> BBox>(values: &mut [V], bbox: F,
+                                         on_x: bool) {
+    let center = |v: &V| {
+        let b = bbox(v);
+        if on_x { b.min_x + b.max_x } else { b.min_y + b.max_y }
+    };
+    values.sort_by(|a, b| center(a).partial_cmp(&center(b)).unwrap_or(Equal));
+}
+
+// Sort-Tile-Recursive packing: slice by x, then each slice by y, and
+// pack capacity-sized nodes. Repeat on the nodes until one is left.
+fn pack<V, F: Fn(&V) -> BBox>(mut values: Vec<V>, capacity: usize, bbox: F)
+    -> Vec<Vec<V>> {
+    let nr_nodes = values.len().div_ceil(capacity);
+    let nr_slices = (nr_nodes as f64).sqrt().ceil() as usize;
+    let slice_len = nr_slices * capacity;
+
+    sort_by_center(&mut values, &bbox, true);
+
+    let mut nodes = Vec::new();
+    while !values.is_empty() {
+        let rest = values.split_off(slice_len.min(values.len()));
+        let mut slice = values;
+        values = rest;
+
+        sort_by_center(&mut slice, &bbox, false);
+        while !slice.is_empty() {
+            let rest = slice.split_off(capacity.min(slice.len()));
+            nodes.push(slice);
+            slice = rest;
+        }
+    }
+    nodes
+}
+
+#[derive(Debug)]
+pub struct RTree<T: Bounded> {
+    root: Option<RNode<T>>,
+    node_capacity: usize
+}
+
+impl<T: Bounded> Default for RTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Bounded> RTree<T> {
+    pub fn new() -> Self {
+        Self::of_capacity(16)
+    }
+
+    pub fn of_capacity(capacity: usize) -> Self {
+        RTree {
+            root: None,
+            node_capacity: capacity.max(2)
+        }
+    }
+
+    // Build a packed tree at once - prefer this when loading a map,
+    // the boxes overlap a lot less than with one by one inserts
+    pub fn bulk_load(values: Vec<T>) -> Self {
+        let mut tree = Self::new();
+        if values.is_empty() {
+            return tree;
+        }
+
+        let capacity = tree.node_capacity;
+        let mut level: Vec<RNode<T>> = pack(values, capacity, |v| v.bbox())
+            .into_iter()
+            .map(|vals| RNode::Leaf(bbox_of(vals.iter().map(|v| v.bbox())),
+                                    vals))
+            .collect();
+
+        while level.len() > 1 {
+            level = pack(level, capacity, |n| n.bbox())
+                .into_iter()
+                .map(|nodes| RNode::Inner(
+                        bbox_of(nodes.iter().map(|n| n.bbox())), nodes))
+                .collect();
+        }
+
+        tree.root = level.pop();
+        tree
+    }
+
+    pub fn insert(&mut self, v: T) {
+        let capacity = self.node_capacity;
+        match self.root.as_mut() {
+            None => {
+                self.root = Some(RNode::Leaf(v.bbox(), vec![v]));
+            },
+            Some(root) => {
+                if let Some(sibling) = root.insert(v, capacity) {
+                    let old = self.root.take().unwrap();
+                    let bbox = union(&old.bbox(), &sibling.bbox());
+                    self.root = Some(RNode::Inner(bbox, vec![old, sibling]));
+                }
+            }
+        }
+    }
+
+    // Items whose box intersects bbox, in no particular order
+    pub fn intersecting(&self, bbox: BBox) -> Intersecting<'_, T> {
+        Intersecting {
+            bbox,
+            nodes: self.root.iter().collect(),
+            values: [].iter()
+        }
+    }
+
+    // All items sorted by distance_to from x/y, lazily
+    pub fn nearest(&self, x: f64, y: f64) -> NearestItems<'_, T> {
+        NearestItems::new(self.root.as_ref(), x, y, None)
+    }
+
+    pub fn within_radius(&self, x: f64, y: f64, meters: f64)
+        -> NearestItems<'_, T> {
+        NearestItems::new(self.root.as_ref(), x, y, Some(meters))
+    }
+}
+
+pub struct Intersecting<'a, T: Bounded> {
+    bbox: BBox,
+    nodes: Vec<&'a RNode<T>>,
+    values: std::slice::Iter<'a, T>
+}
+
+impl<'a, T: Bounded> Iterator for Intersecting<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            let bbox = self.bbox;
+            if let Some(v) = self.values.find(|v| v.bbox().intersects(&bbox)) {
+                return Some(v);
+            }
+
+            match self.nodes.pop()? {
+                RNode::Leaf(nbox, values) => {
+                    if nbox.intersects(&bbox) {
+                        self.values = values.iter();
+                    }
+                },
+                RNode::Inner(nbox, children) => {
+                    if nbox.intersects(&bbox) {
+                        self.nodes.extend(children.iter());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Same best-first walk as the quadtree's Nearest, over boxes
+pub struct NearestItems<'a, T: Bounded>(BestFirst<'a, RNode<T>>);
+
+impl<'a, T: Bounded> NearestItems<'a, T> {
+    fn new(root: Option<&'a RNode<T>>, x: f64, y: f64,
+           max_dist: Option<f64>) -> Self {
+        let mut it = BestFirst::new(x, y, max_dist);
+        if let Some(root) = root {
+            it.push(root.bbox().distance_to(x, y), Candidate::Node(root));
+        }
+        NearestItems(it)
+    }
+}
+
+impl<'a, T: Bounded> Iterator for NearestItems<'a, T> {
+    type Item = (&'a T, f64);
+
+    fn next(&mut self) -> Option<(&'a T, f64)> {
+        self.0.next()
+    }
+}
+
+impl<T: Bounded> Branch for RNode<T> {
+    type Value = T;
+
+    fn expand<'a>(&'a self, x: f64, y: f64, queue: &mut BestFirst<'a, Self>) {
+        match self {
+            RNode::Leaf(_, values) => for v in values {
+                queue.push(v.distance_to(x, y), Candidate::Value(v));
+            },
+            RNode::Inner(_, children) => for child in children {
+                queue.push(child.bbox().distance_to(x, y),
+                           Candidate::Node(child));
+            }
+        }
+    }
+}
+
+// A straight line from a to b carrying some data (way id, edge, ...)
+#[derive(Debug, Clone)]
+pub struct Segment<D> {
+    pub data: D,
+    pub ax: f64,
+    pub ay: f64,
+    pub bx: f64,
+    pub by: f64
+}
+
+impl<D> Segment<D> {
+    pub fn new(data: D, ax: f64, ay: f64, bx: f64, by: f64) -> Self {
+        Segment { data, ax, ay, bx, by }
+    }
+
+    // Closest point of the segment to x/y as (t, px, py), t being the
+    // fraction from a to b. Lon is scaled by cos(lat) so this holds for
+    // the short segments we have on a map.
+    pub fn project(&self, x: f64, y: f64) -> (f64, f64, f64) {
+        let scale = self.ay.to_radians().cos();
+        let (dx, dy) = ((self.bx - self.ax) * scale, self.by - self.ay);
+        let (px, py) = ((x - self.ax) * scale, y - self.ay);
+        let len2 = dx * dx + dy * dy;
+        let t = if len2 > 0.0 {
+            ((px * dx + py * dy) / len2).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (t, self.ax + (self.bx - self.ax) * t, self.ay + (self.by - self.ay) * t)
+    }
+}
+
+impl<D> Bounded for Segment<D> {
+    fn bbox(&self) -> BBox {
+        BBox::new(self.ax.min(self.bx), self.ay.min(self.by),
+                  self.ax.max(self.bx), self.ay.max(self.by))
+    }
+
+    fn distance_to(&self, x: f64, y: f64) -> f64 {
+        let (_, px, py) = self.project(x, y);
+        distance(x, y, px, py)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bounded, RTree, Segment};
+    use super::super::BBox;
+
+    // A little street grid: horizontal and vertical lines every 0.01
+    fn streets() -> Vec<Segment<u32>> {
+        let mut segs = Vec::new();
+        let mut id = 0;
+        for a in 0..10 {
+            for b in 0..10 {
+                let x = -122.5 + 0.01 * a as f64;
+                let y = 37.7 + 0.01 * b as f64;
+                segs.push(Segment::new(id, x, y, x + 0.01, y));
+                segs.push(Segment::new(id + 1, x, y, x, y + 0.01));
+                id += 2;
+            }
+        }
+        segs
+    }
+
+    #[test]
+    fn bulk_and_insert_agree() {
+        let packed = RTree::bulk_load(streets());
+        let mut inserted = RTree::of_capacity(4);
+        for seg in streets() {
+            inserted.insert(seg);
+        }
+
+        let bbox = BBox::new(-122.455, 37.745, -122.445, 37.755);
+        let mut a = packed.intersecting(bbox).map(|s| s.data)
+            .collect::<Vec<u32>>();
+        let mut b = inserted.intersecting(bbox).map(|s| s.data)
+            .collect::<Vec<u32>>();
+        a.sort();
+        b.sort();
+        assert_eq!(a.len(), 4);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn closest_edge() {
+        let tree = RTree::bulk_load(streets());
+        // A bit north of the middle of the first horizontal segment
+        let (seg, dist) = tree.nearest(-122.495, 37.7001).next().unwrap();
+        assert_eq!(seg.data, 0);
+        assert!(dist > 10.0 && dist < 12.0);
+        let (t, x, y) = seg.project(-122.495, 37.7001);
+        assert!((t - 0.5).abs() < 0.001);
+        assert!((x - -122.495).abs() < 0.000001);
+        assert_eq!(y, 37.7);
+    }
+
+    #[test]
+    fn nearest_sorted() {
+        let tree = RTree::bulk_load(streets());
+        let dists = tree.within_radius(-122.453, 37.752, 2000.0)
+            .map(|(seg, dist)| {
+                assert!((seg.distance_to(-122.453, 37.752) - dist).abs()
+                        < 0.001);
+                dist
+            })
+            .collect::<Vec<f64>>();
+        assert!(dists.len() > 20);
+        assert!(dists.windows(2).all(|w| w[0] <= w[1]));
+        assert!(dists.iter().all(|d| *d <= 2000.0));
+    }
+}